@@ -6,6 +6,7 @@ extern crate test;
 
 use mpir::pbc::choices::ChoicesCode;
 use mpir::pbc::cuckoo::CuckooCode;
+use mpir::pbc::hasher::KeyedHasher;
 use mpir::pbc::pung::PungCode;
 use mpir::pbc::replication::ReplicationCode;
 use mpir::pbc::sharding::ShardingCode;
@@ -13,6 +14,7 @@ use mpir::pbc::{BatchCode, Tuple};
 use rand::Rng;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::sync::Arc;
 use test::Bencher;
 
 const K: usize = 64;
@@ -50,7 +52,7 @@ where
 {
     let mut rng = rand::thread_rng();
     let collection: Vec<Tuple<usize, usize>> = (0..SIZE).map(|e| Tuple { t: (e, e * e) }).collect();
-    let oracle = code.encode(&collection);
+    let oracle = code.encode(&collection).unwrap();
     let num_elems: Vec<u64> = oracle.iter().map(|vec| vec.len() as u64).collect();
 
     let mut key_set: HashSet<usize> = HashSet::new();
@@ -85,7 +87,7 @@ where
     b.iter(|| {
         for bucket in 0..oracle.len() {
             let codewords = vec![oracle[bucket][ind_vec[bucket] as usize].clone()];
-            code.decode(&codewords);
+            code.decode(&codewords).unwrap();
         }
     });
 }
@@ -120,6 +122,13 @@ fn bench_encode_cuckoo(b: &mut Bencher) {
     encode(b, &code);
 }
 
+#[bench]
+fn bench_encode_cuckoo_keyed(b: &mut Bencher) {
+    let mut code = CuckooCode::new(K, 3, 1.3);
+    code.set_hasher(Arc::new(KeyedHasher::new([7; 16])));
+    encode(b, &code);
+}
+
 #[bench]
 fn bench_encode_sharding(b: &mut Bencher) {
     let code = ShardingCode::new(K);
@@ -158,29 +167,13 @@ fn bench_decode_choices(b: &mut Bencher) {
 
 #[bench]
 fn bench_schedule_pung(b: &mut Bencher) {
-    let mut code = PungCode::new(K);
+    let code = PungCode::new(K);
     let mut rng = rand::thread_rng();
 
     // hack to ensure no duplicates
     let keys: HashSet<usize> = (0..K).map(|_| rng.next_u32() as usize % SIZE).collect();
     let keys: Vec<usize> = keys.iter().cloned().collect();
 
-    // Ughh pung's code is data-dependent so we actually need to create a collection
-    let tuples: Vec<Tuple<usize, usize>> = (0..SIZE).map(|e| Tuple { t: (e, e * e) }).collect();
-    let db: Vec<Vec<Tuple<usize, usize>>> = code.encode(&tuples);
-
-    // Get label mapping
-    let mut labels: HashMap<usize, Vec<usize>> = HashMap::new();
-
-    for (i, vec_tuple) in db.iter().enumerate() {
-        for tuple in vec_tuple {
-            let entry = labels.entry(tuple.t.0).or_insert_with(Vec::new);
-            entry.push(i);
-        }
-    }
-
-    code.set_labels(labels);
-
     b.iter(|| {
         (&code as &dyn BatchCode<usize, usize>)
             .get_schedule(&keys)
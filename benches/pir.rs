@@ -2,13 +2,10 @@
 extern crate criterion;
 extern crate mpir;
 extern crate rand;
-extern crate serde;
-
-#[macro_use]
-extern crate serde_derive;
 
 use criterion::Criterion;
 use mpir::client::MultiPirClient;
+use mpir::pbc::block::Block;
 use mpir::pbc::cuckoo::CuckooCode;
 use mpir::pbc::pung::PungCode;
 use mpir::pbc::{BatchCode, Tuple};
@@ -28,33 +25,7 @@ const POLY_DEGREE: u32 = 2048;
 const NUM: u32 = 1 << 20;
 const BATCH_SIZES: [usize; 3] = [16, 64, 256];
 
-#[derive(Serialize, Clone)]
-struct Element {
-    #[serde(serialize_with = "<[_]>::serialize")]
-    e: [u8; SIZE],
-}
-
-impl std::ops::BitXor for Element {
-    type Output = Self;
-
-    fn bitxor(self, rhs: Self) -> Self {
-        let mut el = Element { e: [0u8; SIZE] };
-
-        for i in 0..self.e.len() {
-            el.e[i] = self.e[i] ^ rhs.e[i];
-        }
-
-        el
-    }
-}
-
-impl std::ops::BitXorAssign for Element {
-    fn bitxor_assign(&mut self, rhs: Self) {
-        for i in 0..self.e.len() {
-            self.e[i] ^= rhs.e[i];
-        }
-    }
-}
+type Element = Block<SIZE>;
 
 type OracleTy = (Vec<Vec<Tuple<usize, Element>>>, Vec<(u32, u32)>);
 
@@ -69,11 +40,11 @@ fn get_oracle(
         let mut x = [0u8; SIZE];
         rng.fill_bytes(&mut x);
         collection.push(Tuple {
-            t: (i, Element { e: x }),
+            t: (i, Element::from(x)),
         });
     }
 
-    let oracle = code.encode(&collection);
+    let oracle = code.encode(&collection).unwrap();
     let sizes: Vec<(u32, u32)> = oracle
         .iter()
         .map(|vec| (vec.len() as u32, mem::size_of::<(usize, Element)>() as u32))
@@ -94,7 +65,7 @@ fn setup_cuckoo(c: &mut Criterion) {
                 let mut x = [0u8; SIZE];
                 rng.fill_bytes(&mut x);
                 collection.push(Tuple {
-                    t: (i, Element { e: x }),
+                    t: (i, Element::from(x)),
                 });
             }
 
@@ -102,7 +73,7 @@ fn setup_cuckoo(c: &mut Criterion) {
 
             // measurement
             b.iter(|| {
-                let buckets = code.encode(&collection);
+                let buckets = code.encode(&collection).unwrap();
                 MultiPirServer::new_setup(
                     &buckets[..],
                     (SIZE + mem::size_of::<usize>()) as u32,
@@ -128,7 +99,7 @@ fn setup_pung(c: &mut Criterion) {
                 let mut x = [0u8; SIZE];
                 rng.fill_bytes(&mut x);
                 collection.push(Tuple {
-                    t: (i, Element { e: x }),
+                    t: (i, Element::from(x)),
                 });
             }
 
@@ -136,7 +107,7 @@ fn setup_pung(c: &mut Criterion) {
 
             // measurement
             b.iter(|| {
-                let buckets = code.encode(&collection);
+                let buckets = code.encode(&collection).unwrap();
                 MultiPirServer::new_setup(
                     &buckets[..],
                     (SIZE + mem::size_of::<usize>()) as u32,
@@ -213,26 +184,13 @@ fn query_pung(c: &mut Criterion) {
         |b, &&k| {
             // setup
             let mut rng = ChaChaRng::new_unseeded();
-            let mut code = PungCode::new(k);
+            let code = PungCode::new(k);
 
             let (oracle, sizes) = get_oracle(&code, &mut rng);
 
             // Create the client
             let client = MultiPirClient::new(&sizes, POLY_DEGREE, LOGT, DIM);
 
-            // Get label mapping (since Pung is data-dependent...)
-            let mut labels: HashMap<usize, Vec<usize>> = HashMap::new();
-
-            for (i, vec_tuple) in oracle.iter().enumerate() {
-                for tuple in vec_tuple {
-                    let entry = labels.entry(tuple.t.0).or_insert_with(Vec::new);
-                    entry.push(i);
-                }
-            }
-
-            // Pung's hybrid is a data-dependent code
-            code.set_labels(labels);
-
             // Generate keys (desired indexes)
             let mut key_set: HashSet<usize> = HashSet::new();
             while key_set.len() < k {
@@ -364,7 +322,7 @@ fn reply_pung(c: &mut Criterion) {
         |b, &&k| {
             // setup
             let mut rng = ChaChaRng::new_unseeded();
-            let mut code = PungCode::new(k);
+            let code = PungCode::new(k);
 
             let (oracle, sizes) = get_oracle(&code, &mut rng);
 
@@ -376,19 +334,6 @@ fn reply_pung(c: &mut Criterion) {
             let galois = client.get_galois_keys();
             server.set_galois_keys(&galois, 0);
 
-            // Get label mapping (since Pung is data-dependent...)
-            let mut labels: HashMap<usize, Vec<usize>> = HashMap::new();
-
-            for (i, vec_tuple) in oracle.iter().enumerate() {
-                for tuple in vec_tuple {
-                    let entry = labels.entry(tuple.t.0).or_insert_with(Vec::new);
-                    entry.push(i);
-                }
-            }
-
-            // Pung's hybrid is a data-dependent code
-            code.set_labels(labels);
-
             // Generate keys (desired indexes)
             let mut key_set: HashSet<usize> = HashSet::new();
             while key_set.len() < k {
@@ -521,7 +466,7 @@ fn decode_pung(c: &mut Criterion) {
         |b, &&k| {
             // setup
             let mut rng = ChaChaRng::new_unseeded();
-            let mut code = PungCode::new(k);
+            let code = PungCode::new(k);
 
             let (oracle, sizes) = get_oracle(&code, &mut rng);
 
@@ -533,19 +478,6 @@ fn decode_pung(c: &mut Criterion) {
             let galois = client.get_galois_keys();
             server.set_galois_keys(&galois, 0);
 
-            // Get label mapping (since Pung is data-dependent...)
-            let mut labels: HashMap<usize, Vec<usize>> = HashMap::new();
-
-            for (i, vec_tuple) in oracle.iter().enumerate() {
-                for tuple in vec_tuple {
-                    let entry = labels.entry(tuple.t.0).or_insert_with(Vec::new);
-                    entry.push(i);
-                }
-            }
-
-            // Pung's hybrid is a data-dependent code
-            code.set_labels(labels);
-
             // Generate keys (desired indexes)
             let mut key_set: HashSet<usize> = HashSet::new();
             while key_set.len() < k {
@@ -0,0 +1,203 @@
+// Estimates the failure probability of a batch code's scheduling algorithm by running it on
+// many random batches. Run with no arguments for usage.
+
+use mpir::pbc::choices::ChoicesCode;
+use mpir::pbc::cuckoo::CuckooCode;
+use mpir::pbc::descriptor::CodeParams;
+use mpir::pbc::pung::PungCode;
+use mpir::pbc::sharding::ShardingCode;
+use mpir::pbc::simulation::{estimate_failure, trials_needed};
+use mpir::pbc::{BatchCode, Strategy};
+use rand::{ChaChaRng, Rng, SeedableRng};
+use std::env;
+use std::process;
+
+const USAGE: &str = "usage: pbc_failure <sharding|choices|cuckoo|pung> <k> [options]
+
+options:
+  --batch <n>        keys per batch (default: k)
+  --d <d>            number of choices for choices and cuckoo (default: 2 and 3)
+  --r <r>            bucket factor for cuckoo (default: 1.5)
+  --replicas <b>     replicas of each bucket for sharding and choices (default: retry bound)
+  --stash <s>        stash buckets for cuckoo (default: 0)
+  --strategy <name>  heuristic, matching or bfs (default: heuristic)
+  --trials <n>       number of batches (default: 10000)
+  --target <p>       print the trials needed to show a failure probability below p
+  --z <z>            normal quantile of the confidence interval (default: 1.96)
+  --seed <n>         seed for the keys and the scheduling algorithm (default: random)";
+
+struct Options {
+    code: String,
+    k: usize,
+    batch: Option<usize>,
+    d: Option<usize>,
+    r: f64,
+    replicas: Option<usize>,
+    stash: usize,
+    strategy: Strategy,
+    trials: usize,
+    target: Option<f64>,
+    z: f64,
+    seed: Option<u32>,
+}
+
+fn fail(msg: &str) -> ! {
+    eprintln!("{msg}\n\n{USAGE}");
+    process::exit(1);
+}
+
+fn parse<T: std::str::FromStr>(name: &str, value: Option<String>) -> T {
+    match value.map(|v| v.parse()) {
+        Some(Ok(v)) => v,
+        _ => fail(&format!("invalid or missing value for {name}")),
+    }
+}
+
+fn parse_args() -> Options {
+    let mut args = env::args().skip(1);
+
+    let code = args.next().unwrap_or_else(|| fail("missing code"));
+    let k = parse("k", args.next());
+
+    let mut opts = Options {
+        code,
+        k,
+        batch: None,
+        d: None,
+        r: 1.5,
+        replicas: None,
+        stash: 0,
+        strategy: Strategy::Heuristic,
+        trials: 10000,
+        target: None,
+        z: 1.96,
+        seed: None,
+    };
+
+    while let Some(flag) = args.next() {
+        let value = args.next();
+
+        match flag.as_str() {
+            "--batch" => opts.batch = Some(parse(&flag, value)),
+            "--d" => opts.d = Some(parse(&flag, value)),
+            "--r" => opts.r = parse(&flag, value),
+            "--replicas" => opts.replicas = Some(parse(&flag, value)),
+            "--stash" => opts.stash = parse(&flag, value),
+            "--trials" => opts.trials = parse(&flag, value),
+            "--target" => opts.target = Some(parse(&flag, value)),
+            "--z" => opts.z = parse(&flag, value),
+            "--seed" => opts.seed = Some(parse(&flag, value)),
+            "--strategy" => {
+                opts.strategy = match value.as_deref() {
+                    Some("heuristic") => Strategy::Heuristic,
+                    Some("matching") => Strategy::Matching,
+                    Some("bfs") => Strategy::Bfs,
+                    _ => fail("invalid or missing value for --strategy"),
+                }
+            }
+            _ => fail(&format!("unknown option {flag}")),
+        }
+    }
+
+    if let Some(target) = opts.target {
+        if !(target > 0.0 && target < 1.0) {
+            fail("--target must be in (0, 1)");
+        }
+    }
+
+    opts
+}
+
+fn build_code(opts: &Options) -> Box<dyn BatchCode<usize, usize>> {
+    match opts.code.as_str() {
+        "sharding" => {
+            let replicas = opts.replicas.unwrap_or_else(|| {
+                if opts.k <= 2 {
+                    fail("sharding needs k > 2 for the default --replicas");
+                }
+
+                mpir::retry_bound!(opts.k)
+            });
+            let mut code = ShardingCode::with_replicas(opts.k, replicas)
+                .unwrap_or_else(|e| fail(&e.to_string()));
+            code.set_strategy(opts.strategy);
+            Box::new(code)
+        }
+        "choices" => {
+            let d = opts.d.unwrap_or(2);
+            let replicas = opts.replicas.unwrap_or(mpir::retry_bound!(opts.k, d));
+            let mut code = ChoicesCode::with_replicas(opts.k, d, replicas)
+                .unwrap_or_else(|e| fail(&e.to_string()));
+            code.set_strategy(opts.strategy);
+            Box::new(code)
+        }
+        "cuckoo" => {
+            let d = opts.d.unwrap_or(3);
+            let mut code = CuckooCode::with_stash(opts.k, d, opts.r, opts.stash)
+                .unwrap_or_else(|e| fail(&e.to_string()));
+            code.set_strategy(opts.strategy);
+            Box::new(code)
+        }
+        "pung" => {
+            CodeParams::Pung { k: opts.k }
+                .validate()
+                .unwrap_or_else(|e| fail(&e.to_string()));
+            Box::new(PungCode::new(opts.k))
+        }
+        _ => fail(&format!("unknown code {}", opts.code)),
+    }
+}
+
+fn main() {
+    let opts = parse_args();
+    let code = build_code(&opts);
+
+    let seed = opts.seed.unwrap_or_else(|| rand::thread_rng().gen());
+    let mut rng = ChaChaRng::from_seed(&[seed]);
+
+    let estimate = match estimate_failure(
+        code.as_ref(),
+        opts.batch.unwrap_or(opts.k),
+        opts.trials,
+        |rng| rng.next_u64() as usize,
+        &mut rng,
+    ) {
+        Ok(estimate) => estimate,
+        Err(e) => fail(&e.to_string()),
+    };
+
+    let (low, high) = estimate.interval(opts.z);
+
+    println!("seed: {seed}");
+    println!(
+        "failures: {} of {} ({:.3e})",
+        estimate.failures,
+        estimate.trials,
+        estimate.failure_rate()
+    );
+    println!(
+        "interval (z = {}): [{:.3e}, {:.3e}], upper bound 2^{:.2}",
+        opts.z,
+        low,
+        high,
+        high.log2()
+    );
+
+    if let Some(bound) = code.failure_bound() {
+        println!("analytic bound: {bound:.3e}");
+    }
+
+    if let Some(target) = opts.target {
+        println!(
+            "trials without failure needed for {:.3e}: {}",
+            target,
+            trials_needed(target, opts.z)
+        );
+    }
+
+    println!("retries per batch: {}", estimate.retries);
+
+    for (retries, count) in &estimate.retries.histogram {
+        println!("  {retries}: {count}");
+    }
+}
@@ -0,0 +1,270 @@
+use crate::client::MultiPirClient;
+use crate::pbc::block::{Block, BlockError};
+use crate::pbc::Tuple;
+use crate::rounds::{Retrieval, RoundError, RoundPlanner};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use rand::Rng;
+use sealpir::{PirQuery, PirReply};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::{cmp, error, fmt, hash};
+
+// Bytes of the header at the start of every value: its length (u64) and its digest
+const HEADER: usize = 8 + 32;
+
+/// Key of one chunk of a value: the value's key and the position of the chunk.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq, Hash)]
+pub struct ChunkKey<K>
+where
+    K: Clone + Serialize,
+{
+    pub key: K,
+    pub index: usize,
+}
+
+/// Stores values that are larger than a PIR element as several chunks, each a separate entry
+/// (with a ChunkKey) of the encoded collection. Every value is split into the same number of
+/// chunks, so the number of chunks a client retrieves does not reveal the size of the values
+/// it wants. The first chunk starts with the value's length and a SHA-256 digest of its key
+/// and value, which the client checks after reassembling the chunks.
+///
+/// The server encodes the output of `split_collection` with any batch code; the client uses
+/// `chunk_keys` and `join`, or `retrieve`, which schedules the chunks of all the requested keys
+/// (over several rounds if they do not fit in a single batch).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chunker<const N: usize> {
+    chunks: usize,
+}
+
+impl<const N: usize> Chunker<N> {
+    /// Splits every value into the given number of chunks (at least 1).
+    pub fn new(chunks: usize) -> Self {
+        assert!(chunks > 0, "values need at least one chunk");
+        assert!(
+            chunks * Block::<N>::CAPACITY >= HEADER,
+            "chunks are too small to hold the header"
+        );
+
+        Chunker { chunks }
+    }
+
+    /// Uses as few chunks as can hold values of up to max_len bytes.
+    pub fn for_len(max_len: usize) -> Self {
+        let bytes = max_len + HEADER;
+        Chunker::new(bytes.div_ceil(Block::<N>::CAPACITY))
+    }
+
+    pub fn chunks(&self) -> usize {
+        self.chunks
+    }
+
+    /// Length of the largest value that fits in the chunks.
+    pub fn max_len(&self) -> usize {
+        self.chunks * Block::<N>::CAPACITY - HEADER
+    }
+
+    /// Splits a value into its chunks.
+    #[allow(clippy::type_complexity)]
+    pub fn split<K>(
+        &self,
+        key: &K,
+        value: &[u8],
+    ) -> Result<Vec<Tuple<ChunkKey<K>, Block<N>>>, ChunkError>
+    where
+        K: Clone + Serialize,
+    {
+        if value.len() > self.max_len() {
+            return Err(ChunkError::TooLong {
+                len: value.len(),
+                max_len: self.max_len(),
+            });
+        }
+
+        let mut bytes = Vec::with_capacity(HEADER + value.len());
+        bytes.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&digest(key, value)?);
+        bytes.extend_from_slice(value);
+
+        let mut parts = bytes.chunks(Block::<N>::CAPACITY);
+
+        (0..self.chunks)
+            .map(|index| {
+                let part = parts.next().unwrap_or(&[]);
+
+                Ok(Tuple {
+                    t: (
+                        ChunkKey {
+                            key: key.clone(),
+                            index,
+                        },
+                        Block::encode(part).unwrap(),
+                    ),
+                })
+            })
+            .collect()
+    }
+
+    /// Splits every value of the collection into its chunks.
+    #[allow(clippy::type_complexity)]
+    pub fn split_collection<K>(
+        &self,
+        collection: &[Tuple<K, Vec<u8>>],
+    ) -> Result<Vec<Tuple<ChunkKey<K>, Block<N>>>, ChunkError>
+    where
+        K: Clone + Serialize,
+    {
+        let mut chunks = Vec::with_capacity(collection.len() * self.chunks);
+
+        for entry in collection {
+            chunks.extend(self.split(&entry.t.0, &entry.t.1)?);
+        }
+
+        Ok(chunks)
+    }
+
+    /// Keys of all the chunks of the given keys.
+    pub fn chunk_keys<K>(&self, keys: &[K]) -> Vec<ChunkKey<K>>
+    where
+        K: Clone + Serialize,
+    {
+        keys.iter()
+            .flat_map(|key| {
+                (0..self.chunks).map(move |index| ChunkKey {
+                    key: key.clone(),
+                    index,
+                })
+            })
+            .collect()
+    }
+
+    /// Reassembles the value of key from its chunks, and checks it against the digest.
+    pub fn join<K>(
+        &self,
+        key: &K,
+        chunks: &HashMap<ChunkKey<K>, Block<N>>,
+    ) -> Result<Vec<u8>, ChunkError>
+    where
+        K: Clone + Serialize + cmp::Eq + hash::Hash,
+    {
+        let mut bytes = Vec::with_capacity(self.chunks * Block::<N>::CAPACITY);
+
+        for index in 0..self.chunks {
+            let chunk_key = ChunkKey {
+                key: key.clone(),
+                index,
+            };
+            let chunk = chunks
+                .get(&chunk_key)
+                .ok_or(ChunkError::MissingChunk { index })?;
+
+            bytes.extend_from_slice(chunk.decode()?);
+        }
+
+        if bytes.len() < HEADER {
+            return Err(ChunkError::Corrupted);
+        }
+
+        let mut len = [0u8; 8];
+        len.copy_from_slice(&bytes[..8]);
+        let len = u64::from_le_bytes(len) as usize;
+        let value = &bytes[HEADER..];
+
+        if len != value.len() || digest(key, value)?[..] != bytes[8..HEADER] {
+            return Err(ChunkError::Corrupted);
+        }
+
+        Ok(value.to_vec())
+    }
+
+    /// Retrieves the values of keys with the given client (see `RoundPlanner::retrieve`). The
+    /// planner's code and layout must be those of the chunks (from `split_collection`) that the
+    /// server encoded.
+    pub fn retrieve<K, F>(
+        &self,
+        planner: &RoundPlanner<ChunkKey<K>, Block<N>>,
+        client: &MultiPirClient,
+        keys: &[K],
+        rng: &mut dyn Rng,
+        send: F,
+    ) -> Result<Retrieval<K, Vec<u8>>, ChunkError>
+    where
+        K: Clone + Serialize + cmp::Eq + hash::Hash,
+        F: FnMut(&[PirQuery]) -> Vec<PirReply>,
+    {
+        let chunks = planner.retrieve(client, &self.chunk_keys(keys), rng, send)?;
+        let mut values = HashMap::with_capacity(keys.len());
+
+        for key in keys {
+            values.insert(key.clone(), self.join(key, &chunks.values)?);
+        }
+
+        Ok(Retrieval {
+            values,
+            rounds: chunks.rounds,
+        })
+    }
+}
+
+// SHA-256 digest of a key and its value
+fn digest<K: Serialize>(key: &K, value: &[u8]) -> Result<[u8; 32], ChunkError> {
+    let key = bincode::serialize(key).map_err(|e| ChunkError::Serialization(e.to_string()))?;
+    let mut hasher = Sha256::new();
+    let mut out = [0u8; 32];
+
+    hasher.input(&(key.len() as u64).to_le_bytes());
+    hasher.input(&key);
+    hasher.input(value);
+    hasher.result(&mut out);
+
+    Ok(out)
+}
+
+/// Reasons why a value cannot be split into chunks or reassembled from them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChunkError {
+    /// The value is longer than `Chunker::max_len`.
+    TooLong { len: usize, max_len: usize },
+    /// The chunk with this index was not retrieved.
+    MissingChunk { index: usize },
+    /// A chunk is not a well-formed block.
+    Block(BlockError),
+    /// The reassembled value does not match its length or digest.
+    Corrupted,
+    /// The key could not be serialized.
+    Serialization(String),
+    /// The chunks could not be retrieved (see `RoundPlanner::retrieve`).
+    Round(RoundError),
+}
+
+impl From<BlockError> for ChunkError {
+    fn from(e: BlockError) -> Self {
+        ChunkError::Block(e)
+    }
+}
+
+impl From<RoundError> for ChunkError {
+    fn from(e: RoundError) -> Self {
+        ChunkError::Round(e)
+    }
+}
+
+impl fmt::Display for ChunkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChunkError::TooLong { len, max_len } => {
+                write!(
+                    f,
+                    "value of {len} bytes is longer than the {max_len} allowed"
+                )
+            }
+            ChunkError::MissingChunk { index } => write!(f, "chunk {index} is missing"),
+            ChunkError::Block(e) => write!(f, "invalid chunk: {e}"),
+            ChunkError::Corrupted => write!(f, "value does not match its length or digest"),
+            ChunkError::Serialization(e) => write!(f, "could not serialize key: {e}"),
+            ChunkError::Round(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl error::Error for ChunkError {}
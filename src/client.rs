@@ -1,10 +1,53 @@
+use rand::{OsRng, Rng};
 use sealpir::client::PirClient;
 use sealpir::{PirQuery, PirReply};
+use std::collections::HashMap;
+use std::{cmp, error, fmt, hash};
 
 pub struct MultiPirClient<'a> {
-    handles: Vec<PirClient<'a>>,
+    handles: Vec<PirClient<'a>>, // a single handle serves every bucket if shared
+    num_buckets: usize,
+    shared: bool,
+    ele_nums: Vec<u32>, // number of entries in each bucket
 }
 
+/// A query for every bucket built from a schedule by `MultiPirClient::gen_scheduled_query`.
+pub struct ScheduledQuery<K> {
+    pub query: Vec<PirQuery>,
+    /// Row queried in each bucket, which is needed to decode the replies.
+    pub indexes: Vec<u32>,
+    /// Whether each bucket's reply holds (part of) a requested entry. The others are dummies.
+    pub real: Vec<bool>,
+    buckets: HashMap<K, Vec<usize>>, // key -> buckets holding its parts
+}
+
+/// Reasons why a schedule cannot be turned into a query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueryError {
+    /// The schedule refers to a bucket that the client does not have.
+    UnknownBucket { bucket: usize },
+    /// The row is past the end of the bucket.
+    RowOutOfRange { bucket: usize, row: usize },
+    /// The schedule reads this bucket more than once.
+    BucketReused { bucket: usize },
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueryError::UnknownBucket { bucket } => write!(f, "bucket {bucket} does not exist"),
+            QueryError::RowOutOfRange { bucket, row } => {
+                write!(f, "row {row} is past the end of bucket {bucket}")
+            }
+            QueryError::BucketReused { bucket } => {
+                write!(f, "bucket {bucket} is scheduled more than once")
+            }
+        }
+    }
+}
+
+impl error::Error for QueryError {}
+
 impl<'a> MultiPirClient<'a> {
     pub fn new(
         buckets: &[(u32, u32)],
@@ -24,48 +67,181 @@ impl<'a> MultiPirClient<'a> {
             ));
         }
 
-        MultiPirClient { handles }
+        MultiPirClient {
+            handles,
+            num_buckets: buckets.len(),
+            shared: false,
+            ele_nums: buckets.iter().map(|b| b.0).collect(),
+        }
+    }
+
+    /// Creates a client for num_buckets buckets that all have the same size (e.g., after
+    /// padding::pad). A single set of PIR parameters and a single Galois key serve every bucket.
+    pub fn new_uniform(
+        num_buckets: usize,
+        ele_num: u32,
+        ele_size: u32,
+        poly_degree: u32,
+        log_plain_mod: u32,
+        d: u32,
+    ) -> MultiPirClient<'a> {
+        let handle = PirClient::new(ele_num, ele_size, poly_degree, log_plain_mod, d);
+
+        MultiPirClient {
+            handles: vec![handle],
+            num_buckets,
+            shared: true,
+            ele_nums: vec![ele_num; num_buckets],
+        }
     }
 
     pub fn update_params(&mut self, buckets: &[(u32, u32)], d: u32) {
-        assert_eq!(buckets.len(), self.handles.len());
+        assert_eq!(buckets.len(), self.num_buckets);
+
+        if self.shared {
+            assert!(
+                buckets.iter().all(|b| *b == buckets[0]),
+                "Buckets of a uniform client must have the same size"
+            );
+        }
 
         for (i, handle) in self.handles.iter_mut().enumerate() {
             handle.update_params(buckets[i].0, buckets[i].1, d);
         }
+
+        self.ele_nums = buckets.iter().map(|b| b.0).collect();
+    }
+
+    fn handle(&self, bucket: usize) -> &PirClient<'a> {
+        if self.shared {
+            &self.handles[0]
+        } else {
+            &self.handles[bucket]
+        }
     }
 
     pub fn gen_query(&self, indexes: &[u32]) -> Vec<PirQuery> {
         let len = indexes.len();
-        assert_eq!(len, self.handles.len());
+        assert_eq!(len, self.num_buckets);
 
         let mut queries = Vec::with_capacity(len);
 
         for (i, index) in indexes.iter().enumerate() {
-            queries.push(self.handles[i].gen_query(*index));
+            queries.push(self.handle(i).gen_query(*index));
         }
 
         queries
     }
 
+    /// Generates a query for every bucket from a schedule that maps each key to the (bucket,
+    /// row) pairs to read (see `BatchCode::get_indexed_schedule`). Buckets that the schedule
+    /// does not read are queried at a uniformly random row drawn from the operating system's
+    /// CSPRNG, so that the server cannot tell them from the real ones. Fails if the schedule
+    /// reads a bucket twice or a row that does not exist.
+    pub fn gen_scheduled_query<K>(
+        &self,
+        schedule: &HashMap<K, Vec<(usize, usize)>>,
+    ) -> Result<ScheduledQuery<K>, QueryError>
+    where
+        K: Clone + cmp::Eq + hash::Hash,
+    {
+        let mut indexes = vec![0; self.num_buckets];
+        let mut real = vec![false; self.num_buckets];
+        let mut buckets = HashMap::with_capacity(schedule.len());
+
+        for (key, positions) in schedule {
+            for &(bucket, row) in positions {
+                if bucket >= self.num_buckets {
+                    return Err(QueryError::UnknownBucket { bucket });
+                }
+
+                if row >= self.ele_nums[bucket] as usize {
+                    return Err(QueryError::RowOutOfRange { bucket, row });
+                }
+
+                if real[bucket] {
+                    return Err(QueryError::BucketReused { bucket });
+                }
+
+                indexes[bucket] = row as u32;
+                real[bucket] = true;
+            }
+
+            buckets.insert(key.clone(), positions.iter().map(|p| p.0).collect());
+        }
+
+        let mut rng = OsRng::new().expect("Could not access the operating system's RNG");
+
+        for bucket in 0..self.num_buckets {
+            if !real[bucket] && self.ele_nums[bucket] > 0 {
+                indexes[bucket] = rng.gen_range(0, self.ele_nums[bucket]);
+            }
+        }
+
+        Ok(ScheduledQuery {
+            query: self.gen_query(&indexes),
+            indexes,
+            real,
+            buckets,
+        })
+    }
+
+    /// Decodes the replies to a query from `gen_scheduled_query`, skipping the dummy buckets.
+    /// Returns, for each key of the schedule, the results of its buckets (in the order of the
+    /// schedule), which `BatchCode::decode` turns into the key's entry.
+    pub fn decode_scheduled_replies<K, T>(
+        &self,
+        query: &ScheduledQuery<K>,
+        replies: &[PirReply],
+    ) -> HashMap<K, Vec<T>>
+    where
+        K: Clone + cmp::Eq + hash::Hash,
+        T: Clone,
+    {
+        assert_eq!(replies.len(), self.num_buckets);
+
+        query
+            .buckets
+            .iter()
+            .map(|(key, buckets)| {
+                let results = buckets
+                    .iter()
+                    .map(|&b| self.handle(b).decode_reply(query.indexes[b], &replies[b]))
+                    .collect();
+
+                (key.clone(), results)
+            })
+            .collect()
+    }
+
     pub fn get_galois_keys(&self) -> Vec<Vec<u8>> {
-        let mut keys = Vec::with_capacity(self.handles.len());
+        let mut keys = Vec::with_capacity(self.num_buckets);
 
-        for handle in &self.handles {
-            keys.push(handle.get_key().clone());
+        for i in 0..self.num_buckets {
+            keys.push(self.handle(i).get_key().clone());
         }
 
         keys
     }
 
+    /// The Galois key shared by every bucket (see new_uniform and
+    /// MultiPirServer::set_shared_galois_key). Returns None unless the client is uniform.
+    pub fn get_shared_galois_key(&self) -> Option<Vec<u8>> {
+        if !self.shared {
+            return None;
+        }
+
+        Some(self.handles[0].get_key().clone())
+    }
+
     pub fn decode_replies<T: Clone>(&self, indexes: &[u32], replies: &[PirReply]) -> Vec<T> {
         let len = replies.len();
-        assert_eq!(len, self.handles.len());
+        assert_eq!(len, self.num_buckets);
 
         let mut results = Vec::with_capacity(len);
 
-        for (i, handle) in self.handles.iter().enumerate() {
-            results.push(handle.decode_reply(indexes[i], &replies[i]));
+        for (i, reply) in replies.iter().enumerate() {
+            results.push(self.handle(i).decode_reply(indexes[i], reply));
         }
 
         results
@@ -0,0 +1,304 @@
+use crate::client::{MultiPirClient, QueryError};
+use crate::pbc::hasher::{BucketHasher, HasherSpec};
+use crate::pbc::matching::bfs_insertion;
+use crate::pbc::{BatchCode, ScheduleError, Tuple};
+use rand::Rng;
+use sealpir::{PirQuery, PirReply};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::{cmp, error, fmt, hash};
+
+// Prefix of the data hashed to find a key's slots, so that they are independent of the
+// buckets that codes place the key in (even if both use the same hasher)
+const DOMAIN: &[u8] = b"mpir-keyword-slot";
+
+/// Public parameters of keyword PIR, which the server publishes along with the code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeywordParams {
+    /// Number of slots each key can occupy in its bucket (the hash functions of the table).
+    pub probes: usize,
+    /// Number of rows of each of a bucket's tables (one per probe).
+    pub width: usize,
+    pub hasher: HasherSpec,
+}
+
+/// Result of looking up a key with keyword PIR.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Lookup<V> {
+    Found(V),
+    /// No entry of the collection has the key.
+    NotPresent,
+}
+
+/// Keyword PIR: retrieves entries by key without knowing which keys the collection has (unlike
+/// `BatchCode::layout`, which needs all of them). The server places each bucket of the
+/// encoding (see `BatchCode::encode`) into a cuckoo hash table that consists of `probes`
+/// tables of `width` rows, where each key can only be in one row of each table, derived from
+/// the key alone. Each table is served as its own PIR database, so a client retrieves the
+/// key's row in every table of the key's bucket and checks the keys of the entries it gets.
+///
+/// This requires a systematic code (ReplicationCode, ShardingCode, ChoicesCode or CuckooCode),
+/// since the entries of the buckets must be the entries of the collection.
+pub struct KeywordPir {
+    params: KeywordParams,
+    hasher: Arc<dyn BucketHasher>,
+}
+
+impl KeywordPir {
+    /// Fails if probes or width is 0, or the hasher is not supported (see
+    /// `HasherSpec::is_supported`), which clients must check since the params come from the
+    /// server.
+    pub fn new(params: KeywordParams) -> Result<Self, KeywordError> {
+        if params.probes == 0 {
+            return Err(KeywordError::NoProbes);
+        }
+
+        if params.width == 0 {
+            return Err(KeywordError::NoRows);
+        }
+
+        if !params.hasher.is_supported() {
+            return Err(KeywordError::UnsupportedHasher {
+                version: params.hasher.version(),
+            });
+        }
+
+        Ok(KeywordPir {
+            params,
+            hasher: params.hasher.build(),
+        })
+    }
+
+    /// Picks a width for the given buckets, with r * len rows in total for the fullest bucket
+    /// (r > 1; e.g., r = 1.5 with 2 or more probes). `build_tables` fails if some bucket does
+    /// not fit, in which case r should be increased. Fails if probes is 0.
+    pub fn for_buckets<T>(buckets: &[Vec<T>], probes: usize, r: f64) -> Result<Self, KeywordError> {
+        let len = buckets.iter().map(|b| b.len()).max().unwrap_or(0);
+        let width = (len as f64 * r / probes as f64).ceil() as usize;
+
+        KeywordPir::new(KeywordParams {
+            probes,
+            width: cmp::max(width, 1),
+            hasher: HasherSpec::default(),
+        })
+    }
+
+    pub fn params(&self) -> KeywordParams {
+        self.params
+    }
+
+    /// Number of PIR databases (tables) for a code with the given number of buckets.
+    pub fn num_tables(&self, num_buckets: usize) -> usize {
+        num_buckets * self.params.probes
+    }
+
+    /// The (table, row) pairs where key may be if it is in the given bucket.
+    pub fn positions<K: Serialize>(
+        &self,
+        bucket: usize,
+        key: &K,
+    ) -> Result<Vec<(usize, usize)>, ScheduleError> {
+        let mut data = DOMAIN.to_vec();
+        data.extend(
+            bincode::serialize(key).map_err(|e| ScheduleError::Serialization(e.to_string()))?,
+        );
+
+        Ok((0..self.params.probes)
+            .map(|p| {
+                let row = self.hasher.hash_and_mod(p, 0, &data, self.params.width);
+                (bucket * self.params.probes + p, row)
+            })
+            .collect())
+    }
+
+    /// Places the entries of every bucket into its tables, filling the empty rows with
+    /// `dummy`. Returns `num_tables` tables of `width` rows, which the server sets up (e.g.,
+    /// with `MultiPirServer::setup`). The dummy's key is reserved: it must not be in the
+    /// collection (otherwise this fails with `DummyKey`), and clients pass it to `lookup` so
+    /// that the filler rows are never returned as entries.
+    pub fn build_tables<K, V>(
+        &self,
+        buckets: &[Vec<Tuple<K, V>>],
+        dummy: &Tuple<K, V>,
+    ) -> Result<Vec<Vec<Tuple<K, V>>>, KeywordError>
+    where
+        K: Clone + Serialize + cmp::Eq,
+        V: Clone + Serialize,
+    {
+        if let Some(bucket) = buckets
+            .iter()
+            .position(|entries| entries.iter().any(|entry| entry.t.0 == dummy.t.0))
+        {
+            return Err(KeywordError::DummyKey { bucket });
+        }
+
+        let (probes, width) = (self.params.probes, self.params.width);
+        let mut tables = vec![vec![dummy.clone(); width]; self.num_tables(buckets.len())];
+
+        for (bucket, entries) in buckets.iter().enumerate() {
+            let mut candidates = Vec::with_capacity(entries.len());
+
+            // Slots are numbered p * width + row within the bucket
+            for entry in entries {
+                candidates.push(
+                    self.positions(bucket, &entry.t.0)?
+                        .iter()
+                        .map(|&(table, row)| (table % probes) * width + row)
+                        .collect(),
+                );
+            }
+
+            for (entry, slot) in entries
+                .iter()
+                .zip(bfs_insertion(&candidates, probes * width))
+            {
+                let slot = slot.ok_or(KeywordError::TableFull { bucket })?;
+                tables[bucket * probes + slot / width][slot % width] = entry.clone();
+            }
+        }
+
+        Ok(tables)
+    }
+
+    /// Schedules a batch of keys (which may or may not be in the collection) with the code,
+    /// and returns the positions to retrieve for each key, which
+    /// `MultiPirClient::gen_scheduled_query` turns into a query for every table. Codes that
+    /// schedule randomly draw from rng (see `BatchCode::get_schedule_with_rng`).
+    pub fn schedule<K, V>(
+        &self,
+        code: &dyn BatchCode<K, V>,
+        keys: &[K],
+        rng: &mut dyn Rng,
+    ) -> Result<HashMap<K, Vec<(usize, usize)>>, KeywordError>
+    where
+        K: Clone + Serialize + cmp::Eq + hash::Hash,
+        V: Clone + Serialize,
+    {
+        let mut positions = HashMap::with_capacity(keys.len());
+
+        for (key, buckets) in code.get_schedule_with_rng(keys, rng)? {
+            if buckets.len() != 1 {
+                return Err(KeywordError::NotSystematic);
+            }
+
+            let key_positions = self.positions(buckets[0], &key)?;
+            positions.insert(key, key_positions);
+        }
+
+        Ok(positions)
+    }
+
+    /// Finds the entry with the given key among those retrieved from its positions. The key of
+    /// the server's dummy (see `build_tables`) is never found, since its rows are filler.
+    pub fn lookup<K, V>(key: &K, dummy_key: &K, entries: &[Tuple<K, V>]) -> Lookup<V>
+    where
+        K: Clone + Serialize + cmp::Eq,
+        V: Clone + Serialize,
+    {
+        if key == dummy_key {
+            return Lookup::NotPresent;
+        }
+
+        match entries.iter().find(|entry| entry.t.0 == *key) {
+            Some(entry) => Lookup::Found(entry.t.1.clone()),
+            None => Lookup::NotPresent,
+        }
+    }
+
+    /// Looks up a batch of keys with the given client, which must have one bucket per table.
+    /// Sends a query for every table to the server through `send`, which returns the server's
+    /// replies (e.g., from `MultiPirServer::gen_replies`). Keys are scheduled with rng, and
+    /// dummy_key is the key of the server's dummy (see `lookup`).
+    pub fn retrieve<K, V, F>(
+        &self,
+        code: &dyn BatchCode<K, V>,
+        client: &MultiPirClient,
+        keys: &[K],
+        dummy_key: &K,
+        rng: &mut dyn Rng,
+        send: F,
+    ) -> Result<HashMap<K, Lookup<V>>, KeywordError>
+    where
+        K: Clone + Serialize + cmp::Eq + hash::Hash,
+        V: Clone + Serialize,
+        F: FnOnce(&[PirQuery]) -> Vec<PirReply>,
+    {
+        let query = client.gen_scheduled_query(&self.schedule(code, keys, rng)?)?;
+        let replies = send(&query.query);
+
+        Ok(client
+            .decode_scheduled_replies::<K, Tuple<K, V>>(&query, &replies)
+            .into_iter()
+            .map(|(key, entries)| {
+                let lookup = KeywordPir::lookup(&key, dummy_key, &entries);
+                (key, lookup)
+            })
+            .collect())
+    }
+}
+
+/// Reasons why keyword PIR fails.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeywordError {
+    Schedule(ScheduleError),
+    Query(QueryError),
+    /// The code scheduled a key in several buckets, so it is not systematic.
+    NotSystematic,
+    /// The entries of the bucket do not fit in its tables.
+    TableFull {
+        bucket: usize,
+    },
+    /// The bucket has an entry with the dummy's key, which is reserved for filler rows.
+    DummyKey {
+        bucket: usize,
+    },
+    /// The params have no probes (tables per bucket).
+    NoProbes,
+    /// The params have tables without rows.
+    NoRows,
+    /// The hasher has a version that this crate does not implement.
+    UnsupportedHasher {
+        version: u32,
+    },
+}
+
+impl From<ScheduleError> for KeywordError {
+    fn from(e: ScheduleError) -> Self {
+        KeywordError::Schedule(e)
+    }
+}
+
+impl From<QueryError> for KeywordError {
+    fn from(e: QueryError) -> Self {
+        KeywordError::Query(e)
+    }
+}
+
+impl fmt::Display for KeywordError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeywordError::Schedule(e) => write!(f, "{e}"),
+            KeywordError::Query(e) => write!(f, "{e}"),
+            KeywordError::NotSystematic => {
+                write!(
+                    f,
+                    "keyword PIR needs a code that retrieves each key from one bucket"
+                )
+            }
+            KeywordError::TableFull { bucket } => {
+                write!(f, "entries of bucket {bucket} do not fit in its tables")
+            }
+            KeywordError::DummyKey { bucket } => {
+                write!(f, "bucket {bucket} has an entry with the dummy's key")
+            }
+            KeywordError::NoProbes => write!(f, "keyword PIR needs at least one probe"),
+            KeywordError::NoRows => write!(f, "tables must have at least one row"),
+            KeywordError::UnsupportedHasher { version } => {
+                write!(f, "bucket hash version {version} is not supported")
+            }
+        }
+    }
+}
+
+impl error::Error for KeywordError {}
@@ -7,6 +7,10 @@ extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 
+pub mod chunks;
 pub mod client;
+pub mod keyword;
 pub mod pbc;
+pub mod planner;
+pub mod rounds;
 pub mod server;
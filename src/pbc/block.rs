@@ -0,0 +1,173 @@
+use serde::de::{self, Deserialize, Deserializer, SeqAccess, Visitor};
+use serde::ser::{Serialize, SerializeTuple, Serializer};
+use std::ops::{BitXor, BitXorAssign};
+use std::{error, fmt, marker};
+
+// Number of bytes of the length prefix
+const PREFIX: usize = 4;
+
+/// A value of up to `N - 4` bytes stored in exactly N bytes: a little-endian u32 length,
+/// followed by the value and zero padding. Blocks can be XORed, so they can be the values
+/// (or keys) of any code, including XOR-based ones (see `XorCode`). They serialize to exactly
+/// N bytes, so every entry of a bucket has the same size (e.g., 8 + N bytes for a
+/// `Tuple<usize, Block<N>>` serialized with bincode).
+///
+/// A block is usually created with `Block::encode` and read back with `Block::decode`. Blocks
+/// that result from XORing (e.g., parity entries) do not in general hold a valid value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Block<const N: usize> {
+    bytes: [u8; N],
+}
+
+impl<const N: usize> Block<N> {
+    /// Largest value that fits in the block. `encode` and `decode` fail to compile if N < 4.
+    pub const CAPACITY: usize = N - PREFIX;
+
+    /// Pads the value into a block. Fails if it is longer than CAPACITY.
+    pub fn encode(value: &[u8]) -> Result<Self, BlockError> {
+        if value.len() > Self::CAPACITY {
+            return Err(BlockError::TooLong {
+                len: value.len(),
+                capacity: Self::CAPACITY,
+            });
+        }
+
+        let mut bytes = [0u8; N];
+        bytes[..PREFIX].copy_from_slice(&(value.len() as u32).to_le_bytes());
+        bytes[PREFIX..PREFIX + value.len()].copy_from_slice(value);
+
+        Ok(Block { bytes })
+    }
+
+    /// Returns the value that was passed to `encode`. Fails if the length prefix is larger
+    /// than CAPACITY or the padding is not all zeros, which is the case for (almost all)
+    /// random or corrupted blocks.
+    pub fn decode(&self) -> Result<&[u8], BlockError> {
+        let mut prefix = [0u8; PREFIX];
+        prefix.copy_from_slice(&self.bytes[..PREFIX]);
+        let len = u32::from_le_bytes(prefix) as usize;
+
+        if len > Self::CAPACITY {
+            return Err(BlockError::InvalidLength {
+                len,
+                capacity: Self::CAPACITY,
+            });
+        }
+
+        let (value, padding) = self.bytes[PREFIX..].split_at(len);
+
+        if padding.iter().any(|b| *b != 0) {
+            return Err(BlockError::NonZeroPadding);
+        }
+
+        Ok(value)
+    }
+
+    /// The block's raw contents (including the length prefix and padding).
+    pub fn as_bytes(&self) -> &[u8; N] {
+        &self.bytes
+    }
+}
+
+/// The block of raw bytes (e.g., random contents). Use `Block::encode` for values.
+impl<const N: usize> From<[u8; N]> for Block<N> {
+    fn from(bytes: [u8; N]) -> Self {
+        Block { bytes }
+    }
+}
+
+/// The all-zero block, which decodes to an empty value.
+impl<const N: usize> Default for Block<N> {
+    fn default() -> Self {
+        Block { bytes: [0u8; N] }
+    }
+}
+
+impl<const N: usize> BitXor for Block<N> {
+    type Output = Self;
+
+    fn bitxor(mut self, rhs: Self) -> Self {
+        self ^= rhs;
+        self
+    }
+}
+
+impl<const N: usize> BitXorAssign for Block<N> {
+    fn bitxor_assign(&mut self, rhs: Self) {
+        for (a, b) in self.bytes.iter_mut().zip(rhs.bytes.iter()) {
+            *a ^= b;
+        }
+    }
+}
+
+// Serialized as a tuple rather than a sequence, so that there is no length
+impl<const N: usize> Serialize for Block<N> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(N)?;
+
+        for b in self.bytes.iter() {
+            tuple.serialize_element(b)?;
+        }
+
+        tuple.end()
+    }
+}
+
+impl<'de, const N: usize> Deserialize<'de> for Block<N> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_tuple(N, BlockVisitor(marker::PhantomData))
+    }
+}
+
+struct BlockVisitor<const N: usize>(marker::PhantomData<[u8; N]>);
+
+impl<'de, const N: usize> Visitor<'de> for BlockVisitor<N> {
+    type Value = Block<N>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{N} bytes")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Block<N>, A::Error> {
+        let mut bytes = [0u8; N];
+
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = seq
+                .next_element()?
+                .ok_or_else(|| de::Error::invalid_length(i, &self))?;
+        }
+
+        Ok(Block { bytes })
+    }
+}
+
+/// Reasons why a value cannot be put into or read from a Block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockError {
+    /// The value is longer than the block's capacity.
+    TooLong { len: usize, capacity: usize },
+    /// The block's length prefix is larger than its capacity.
+    InvalidLength { len: usize, capacity: usize },
+    /// The bytes after the value are not all zeros.
+    NonZeroPadding,
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockError::TooLong { len, capacity } => {
+                write!(
+                    f,
+                    "value of {len} bytes does not fit in a block of {capacity}"
+                )
+            }
+            BlockError::InvalidLength { len, capacity } => write!(
+                f,
+                "block claims a value of {len} bytes but can hold at most {capacity}"
+            ),
+            BlockError::NonZeroPadding => write!(f, "block padding is not all zeros"),
+        }
+    }
+}
+
+impl error::Error for BlockError {}
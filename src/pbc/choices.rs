@@ -1,74 +1,84 @@
-use super::BatchCode;
-use super::Tuple;
-use bincode::serialize;
+use super::hasher::{BucketHasher, HasherSpec};
+use super::layout::Layout;
+use super::view::{BucketView, EncodedView};
+use super::{BatchCode, ParamError, PartialSchedule, ScheduleError, Strategy, Tuple};
+use rand::Rng;
 use serde::Serialize;
 use std::collections::HashMap;
-use std::ops::{BitXor, BitXorAssign};
+use std::sync::Arc;
 use std::{cmp, hash};
 
 pub struct ChoicesCode {
     k: usize,
-    d: usize, // d choices
+    d: usize,        // d choices
+    replicas: usize, // replicas of each logical bucket
+    strategy: Strategy,
+    hasher: Arc<dyn BucketHasher>,
 }
 
 impl ChoicesCode {
     pub fn new(k: usize, d: usize) -> ChoicesCode {
         let bound = retry_bound!(k, d);
         assert!(bound < k, "You are better off using replication");
-        ChoicesCode { k, d }
+        ChoicesCode {
+            k,
+            d,
+            replicas: bound,
+            strategy: Strategy::default(),
+            hasher: HasherSpec::default().build(),
+        }
     }
-}
-
-impl<K, V> BatchCode<K, V> for ChoicesCode
-where
-    K: Clone + Serialize + BitXor<Output = K> + BitXorAssign + cmp::Eq + hash::Hash,
-    V: Clone + Serialize + BitXor<Output = V> + BitXorAssign,
-{
-    // Encoding is placing each entry to d logical buckets.
-    // We also replicate each logical bucket b times (b = retry bound).
-    fn encode(&self, collection: &[Tuple<K, V>]) -> Vec<Vec<Tuple<K, V>>> {
-        let bound = retry_bound!(self.k, self.d);
 
-        let total_buckets = self.k * bound;
-        let mut collections: Vec<Vec<Tuple<K, V>>> = Vec::with_capacity(total_buckets);
+    /// Creates a code with d choices that keeps the given number of replicas of each of its k
+    /// logical buckets (instead of the retry bound used by `new`).
+    pub fn with_replicas(k: usize, d: usize, replicas: usize) -> Result<ChoicesCode, ParamError> {
+        ChoicesCode::check_choices(k, d)?;
+        super::check_replicas(k, replicas)?;
 
-        for _ in 0..self.k {
-            collections.push(Vec::new());
-        }
-
-        for entry in collection {
-            // First get the binary representation of the key
-            let bytes = serialize(&entry.t.0).unwrap();
+        Ok(ChoicesCode {
+            k,
+            d,
+            replicas,
+            strategy: Strategy::default(),
+            hasher: HasherSpec::default().build(),
+        })
+    }
 
-            let mut bucket_choices = Vec::with_capacity(self.d);
+    /// Creates a code with d choices and the fewest replicas for which a batch of k keys fails
+    /// with probability at most target (see `failure_bound`).
+    pub fn with_failure(k: usize, d: usize, target: f64) -> Result<ChoicesCode, ParamError> {
+        ChoicesCode::check_choices(k, d)?;
+        let replicas = super::replicas_for(k, d as f64 / k as f64, target)?;
+        ChoicesCode::with_replicas(k, d, replicas)
+    }
 
-            // Map entry's key to d buckets (no repeats)
-            for id in 0..self.d {
-                let mut nonce = 0;
+    fn check_choices(k: usize, d: usize) -> Result<(), ParamError> {
+        if d == 0 || d > k {
+            return Err(ParamError::InvalidParam {
+                name: "d",
+                reason: format!("{d} is not between 1 and k = {k}"),
+            });
+        }
 
-                // The following computes bucket = sha_d(key) % k;
-                let mut bucket = super::hash_and_mod(id, nonce, &bytes, self.k);
+        Ok(())
+    }
 
-                // Ensure each key maps to *different* buckets
-                while bucket_choices.contains(&bucket) {
-                    nonce += 1;
-                    bucket = super::hash_and_mod(id, nonce, &bytes, self.k);
-                }
+    pub fn replicas(&self) -> usize {
+        self.replicas
+    }
 
-                bucket_choices.push(bucket);
-                collections[bucket].push(entry.clone());
-            }
-        }
+    pub fn set_strategy(&mut self, strategy: Strategy) {
+        self.strategy = strategy;
+    }
 
-        // Replicate each of the k logical buckets into b buckets
-        // Every i mod k has the same collection, where 0 <= i < b.
-        for i in self.k..total_buckets {
-            let clone = collections[i % self.k].clone();
-            collections.push(clone);
-        }
+    /// Sets the hash function that places keys in buckets (SHA-256 by default, see
+    /// hasher::Sha256Hasher). The client and the server must use the same one.
+    pub fn set_hasher(&mut self, hasher: Arc<dyn BucketHasher>) {
+        self.hasher = hasher;
+    }
 
-        assert_eq!(collections.len(), total_buckets);
-        collections
+    pub fn hasher(&self) -> &dyn BucketHasher {
+        self.hasher.as_ref()
     }
 
     // This is an adaptation of the "Greedy" algorithm of Azar et al.'s
@@ -81,31 +91,48 @@ where
     // storage. What this means is that Po2C is being applied with respect to the
     // client's keys (not the keys that the storage server received!). This is a crucial
     // but subtle difference.
-    fn get_schedule(&self, keys: &[K]) -> Option<HashMap<K, Vec<usize>>> {
-        assert!(keys.len() <= self.k);
-        let bound = retry_bound!(self.k, self.d);
-        let mut schedule = HashMap::new();
+    // With the Matching or Bfs strategies, keys are instead assigned to replicas with
+    // the corresponding algorithm in the matching module.
+    // Occupied replicas that are skipped are counted in retries.
+    fn schedule<K>(
+        &self,
+        keys: &[K],
+        retries: &mut usize,
+        mut leftover: Option<&mut Vec<K>>,
+    ) -> Result<HashMap<K, Vec<usize>>, ScheduleError>
+    where
+        K: Clone + Serialize + cmp::Eq + hash::Hash,
+    {
+        super::check_batch(keys, self.k)?;
+        let bound = self.replicas;
 
-        for key in keys {
-            let bytes = serialize(&key).unwrap();
-            let mut bucket_choices = Vec::with_capacity(self.d);
-            let mut found = false;
+        if self.strategy != Strategy::Heuristic {
+            let mut candidates: Vec<Vec<usize>> = Vec::with_capacity(keys.len());
 
-            // Map entry's key to d buckets (no repeats)
-            for id in 0..self.d {
-                let mut nonce = 0;
+            // A key can be found in any replica of any of its d logical buckets
+            for key in keys {
+                let bytes = super::key_bytes(key)?;
+                let choices = super::bucket_choices(self.hasher.as_ref(), &bytes, self.d, self.k);
+
+                candidates.push(
+                    choices
+                        .iter()
+                        .flat_map(|bucket| (0..bound).map(move |i| bucket + i * self.k))
+                        .collect(),
+                );
+            }
 
-                // The following computes bucket = sha_d(key) % k;
-                let mut bucket = super::hash_and_mod(id, nonce, &bytes, self.k);
+            let assignment = super::matching::assign(self.strategy, &candidates, self.k * bound);
+            return super::matching::schedule(keys, assignment, leftover);
+        }
 
-                // Ensure each key maps to *different* buckets
-                while bucket_choices.contains(&bucket) {
-                    nonce += 1;
-                    bucket = super::hash_and_mod(id, nonce, &bytes, self.k);
-                }
+        let mut schedule = HashMap::new();
 
-                bucket_choices.push(bucket);
-            }
+        for (index, key) in keys.iter().enumerate() {
+            let bytes = super::key_bytes(key)?;
+            let bucket_choices =
+                super::bucket_choices(self.hasher.as_ref(), &bytes, self.d, self.k);
+            let mut found = false;
 
             // Find a bucket that has not been used. This is sort of analogous
             // to Greedy, but not quite.
@@ -118,11 +145,16 @@ where
                         found = true;
                         break 'bucket_loop;
                     }
+
+                    *retries += 1;
                 }
             }
 
             if !found {
-                return None;
+                match leftover.as_mut() {
+                    Some(leftover) => leftover.push(key.clone()),
+                    None => return Err(ScheduleError::RetryBoundExhausted { index, bound }),
+                }
             }
         }
 
@@ -130,11 +162,95 @@ where
         // We do this only to meet the return type: each entry represents the
         // set of indices that must be queried (in our case, our encoding is
         // systematic so we don't need to have multiple indices per item).
-        Some(schedule)
+        Ok(schedule)
     }
+}
+
+impl<K, V> BatchCode<K, V> for ChoicesCode
+where
+    K: Clone + Serialize + cmp::Eq + hash::Hash,
+    V: Clone + Serialize,
+{
+    // Encoding is placing each entry to d logical buckets.
+    // We also replicate each logical bucket b times (b = replicas).
+    fn encode_view(&self, collection: &[Tuple<K, V>]) -> Result<EncodedView, ScheduleError> {
+        let total_buckets = self.k * self.replicas;
+        let mut collections: Vec<Vec<usize>> = vec![Vec::new(); self.k];
+
+        for (i, bytes) in super::slot_order(collection.iter().map(|e| &e.t.0))? {
+            // Map entry's key to d buckets (no repeats)
+            for bucket in super::bucket_choices(self.hasher.as_ref(), &bytes, self.d, self.k) {
+                collections[bucket].push(i);
+            }
+        }
+
+        let mut buckets: Vec<BucketView> = Vec::with_capacity(total_buckets);
+        buckets.extend(collections.into_iter().map(BucketView::Entries));
+
+        // Replicate each of the k logical buckets into b buckets
+        // Every i mod k has the same collection, where 0 <= i < b.
+        for i in self.k..total_buckets {
+            buckets.push(BucketView::Copy(i % self.k));
+        }
+
+        assert_eq!(buckets.len(), total_buckets);
+        Ok(EncodedView::new(buckets))
+    }
+
+    fn get_schedule(&self, keys: &[K]) -> Result<HashMap<K, Vec<usize>>, ScheduleError> {
+        self.schedule(keys, &mut 0, None)
+    }
+
+    fn get_schedule_with_retries(
+        &self,
+        keys: &[K],
+        _rng: &mut dyn Rng,
+        retries: &mut usize,
+    ) -> Result<HashMap<K, Vec<usize>>, ScheduleError> {
+        self.schedule(keys, retries, None)
+    }
+
+    fn get_partial_schedule(&self, keys: &[K]) -> Result<PartialSchedule<K>, ScheduleError> {
+        let (batch, mut leftover) = super::split_batch(keys, self.k)?;
+        let schedule = self.schedule(batch, &mut 0, Some(&mut leftover))?;
+        Ok(PartialSchedule { schedule, leftover })
+    }
+
+    fn decode(&self, results: &[Tuple<K, V>]) -> Result<Tuple<K, V>, ScheduleError> {
+        super::check_results(1, results.len())?;
+        Ok(results[0].clone())
+    }
+
+    // If no logical bucket is among the choices of more than b keys (b = replicas), the
+    // other keys can take at most b - 1 of the replicas of each of a key's buckets, so every
+    // key finds a free replica. Union bound over the k logical buckets.
+    fn failure_bound(&self) -> Option<f64> {
+        let bound = self.replicas;
+        let p = cmp::min(self.d, self.k) as f64 / self.k as f64;
+        let tail = super::padding::binomial_tail(self.k, p, bound + 1);
+        Some((self.k as f64 * tail).min(1.0))
+    }
+
+    // Each entry is in d of k logical buckets
+    fn bucket_bound(&self, n: usize, security: u32) -> usize {
+        cmp::min(n, super::padding::load_bound(n * self.d, self.k, security))
+    }
+
+    // The replicas of each logical bucket form a group
+    fn layout(&self, collection_keys: &[K]) -> Result<Layout<K>, ScheduleError> {
+        let total_buckets = self.k * self.replicas;
+        let mut layout = Layout::new((0..total_buckets).map(|i| i % self.k).collect());
+
+        for (i, bytes) in super::slot_order(collection_keys.iter())? {
+            for bucket in super::bucket_choices(self.hasher.as_ref(), &bytes, self.d, self.k) {
+                layout.place(collection_keys[i].clone(), bucket);
+            }
+        }
+
+        for i in self.k..total_buckets {
+            layout.set_len(i, layout.bucket_len(i % self.k));
+        }
 
-    fn decode(&self, results: &[Tuple<K, V>]) -> Tuple<K, V> {
-        assert_eq!(results.len(), 1);
-        results[0].clone()
+        Ok(layout)
     }
 }
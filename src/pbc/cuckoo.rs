@@ -1,11 +1,12 @@
-use super::BatchCode;
-use super::Tuple;
-use bincode::serialize;
+use super::hasher::{BucketHasher, HasherSpec};
+use super::layout::Layout;
+use super::view::{BucketView, EncodedView};
+use super::{BatchCode, ParamError, PartialSchedule, ScheduleError, Strategy, Tuple};
 use rand;
 use rand::Rng;
 use serde::Serialize;
 use std::collections::HashMap;
-use std::ops::{BitXor, BitXorAssign};
+use std::sync::Arc;
 use std::{cmp, hash};
 
 const MAX_ATTEMPTS: usize = 1000;
@@ -14,11 +15,170 @@ pub struct CuckooCode {
     k: usize,
     d: usize, // d choices
     r: f64,   // total buckets = ceil(k * r)
+    s: usize, // stash buckets (each holds the entire collection)
+    strategy: Strategy,
+    hasher: Arc<dyn BucketHasher>,
 }
 
 impl CuckooCode {
+    /// Panics if the parameters are invalid (see `with_stash`).
     pub fn new(k: usize, d: usize, r: f64) -> CuckooCode {
-        CuckooCode { k, d, r }
+        CuckooCode::with_stash(k, d, r, 0).unwrap()
+    }
+
+    /// Creates a cuckoo code with s additional stash buckets. Each stash bucket holds a copy of
+    /// the entire collection, so any key that cuckoo insertion fails to place can be retrieved
+    /// from a stash bucket instead. A batch then only fails if more than s keys are left without
+    /// a bucket. Larger values of s lower the failure probability at the cost of s extra copies
+    /// of the collection (and s extra PIR queries).
+    ///
+    /// Fails unless k >= 1, r is finite and positive, 1 <= d <= ceil(k * r) (each key needs d
+    /// distinct buckets), and there are at most MAX_BUCKETS buckets in total.
+    pub fn with_stash(k: usize, d: usize, r: f64, s: usize) -> Result<CuckooCode, ParamError> {
+        let invalid = |name, reason: String| Err(ParamError::InvalidParam { name, reason });
+
+        if k == 0 {
+            return invalid("k", "batches must have at least one key".to_string());
+        }
+
+        if !r.is_finite() || r <= 0.0 {
+            return invalid("r", format!("{r} is not a positive number"));
+        }
+
+        let total_buckets = (k as f64 * r).ceil();
+
+        if total_buckets + s as f64 > super::MAX_BUCKETS as f64 {
+            return invalid(
+                "r",
+                format!(
+                    "ceil(k * r) + s = {} buckets is more than {}",
+                    total_buckets + s as f64,
+                    super::MAX_BUCKETS
+                ),
+            );
+        }
+
+        if d == 0 || d as f64 > total_buckets {
+            return invalid(
+                "d",
+                format!("{d} is not between 1 and ceil(k * r) = {total_buckets}"),
+            );
+        }
+
+        Ok(CuckooCode {
+            k,
+            d,
+            r,
+            s,
+            strategy: Strategy::default(),
+            hasher: HasherSpec::default().build(),
+        })
+    }
+
+    pub fn set_strategy(&mut self, strategy: Strategy) {
+        self.strategy = strategy;
+    }
+
+    /// Sets the hash function that places keys in buckets (SHA-256 by default, see
+    /// hasher::Sha256Hasher). The client and the server must use the same one.
+    pub fn set_hasher(&mut self, hasher: Arc<dyn BucketHasher>) {
+        self.hasher = hasher;
+    }
+
+    pub fn hasher(&self) -> &dyn BucketHasher {
+        self.hasher.as_ref()
+    }
+
+    // Random evictions are drawn from rng, so a seeded rng yields a reproducible schedule.
+    // The number of evictions is added to retries.
+    fn schedule<K>(
+        &self,
+        keys: &[K],
+        rng: &mut dyn Rng,
+        retries: &mut usize,
+        mut leftover: Option<&mut Vec<K>>,
+    ) -> Result<HashMap<K, Vec<usize>>, ScheduleError>
+    where
+        K: Clone + Serialize + cmp::Eq + hash::Hash,
+    {
+        super::check_batch(keys, self.k)?;
+
+        let total_buckets = (self.k as f64 * self.r).ceil() as usize;
+
+        let mut buckets = HashMap::new(); // map containing K -> [bucket 1, ..., bucket d]
+
+        for key in keys {
+            // Map entry's key to d buckets (no repeats)
+            let bytes = super::key_bytes(key)?;
+            buckets.insert(
+                key,
+                super::bucket_choices(self.hasher.as_ref(), &bytes, self.d, total_buckets),
+            );
+        }
+
+        // This is a variant of the Insert algorithm in cuckoo hashing (Pagh and Rodler).
+        // One difference is we only have 1 table and d hash functions.
+        // The difference is that we are doing this for retrieval rather than insertion! (the keys
+        // have already been inserted). What this means is that cuckoo hashing is being applied
+        // with respect to the client's keys (not the keys that the storage server received).
+        // This is a crucial but subtle difference.
+
+        let mut schedule = HashMap::new();
+        let mut stash = Vec::with_capacity(self.s); // keys that could not be placed
+
+        match self.strategy {
+            Strategy::Heuristic => {
+                let mut elements = HashMap::new(); // map containing bucket -> [current key]
+                let start = *retries; // retries may include earlier batches
+
+                for key in keys {
+                    if let Some(homeless) = insert(&mut elements, &buckets, &key, 0, rng, retries) {
+                        if stash.len() < self.s {
+                            stash.push(homeless);
+                        } else if let Some(leftover) = leftover.as_mut() {
+                            leftover.push(homeless.clone());
+                        } else if self.s == 0 {
+                            return Err(ScheduleError::EvictionsExhausted {
+                                evictions: *retries - start,
+                            });
+                        } else {
+                            return Err(ScheduleError::StashOverflow { size: self.s });
+                        }
+                    }
+                }
+
+                for (k, v) in elements {
+                    schedule.insert(v.clone(), vec![k]);
+                }
+            }
+
+            Strategy::Matching | Strategy::Bfs => {
+                let candidates: Vec<Vec<usize>> =
+                    keys.iter().map(|key| buckets[key].clone()).collect();
+                let assignment = super::matching::assign(self.strategy, &candidates, total_buckets);
+
+                for (index, (key, bucket)) in keys.iter().zip(assignment).enumerate() {
+                    match (bucket, leftover.as_mut()) {
+                        (Some(bucket), _) => {
+                            schedule.insert(key.clone(), vec![bucket]);
+                        }
+                        (None, _) if stash.len() < self.s => stash.push(key),
+                        (None, Some(leftover)) => leftover.push(key.clone()),
+                        (None, None) if self.s == 0 => {
+                            return Err(ScheduleError::Unschedulable { index });
+                        }
+                        (None, None) => return Err(ScheduleError::StashOverflow { size: self.s }),
+                    }
+                }
+            }
+        }
+
+        // Stash buckets come right after the d-choice buckets
+        for (i, v) in stash.drain(..).enumerate() {
+            schedule.insert(v.clone(), vec![total_buckets + i]);
+        }
+
+        Ok(schedule)
     }
 }
 
@@ -26,25 +186,29 @@ impl CuckooCode {
 // Algborithm: if either of d buckets is empty, insert there.
 // Otherwise choose one of them at random, insert item there,
 // and relocate existing element by running insert algorithm.
+// Returns the key that was left without a bucket when we run out of attempts (if any).
+// Each relocation is counted in evictions.
+// Strategy::Bfs replaces this with the iterative matching::bfs_insertion.
 fn insert<K>(
     elements: &mut HashMap<usize, K>,
     buckets: &HashMap<K, Vec<usize>>,
     key: &K,
     attempt: usize,
     rng: &mut dyn Rng,
-) -> bool
+    evictions: &mut usize,
+) -> Option<K>
 where
     K: Clone + Serialize + cmp::Eq + hash::Hash,
 {
     if attempt >= MAX_ATTEMPTS {
-        return false;
+        return Some(key.clone());
     }
 
     // Case 1: check to see if any of the d buckets is empty. If so, insert there.
     for hash_id in &buckets[key] {
         if !elements.contains_key(hash_id) {
             elements.insert(*hash_id, key.clone());
-            return true;
+            return None;
         }
     }
 
@@ -56,112 +220,156 @@ where
 
     // Insert new key, and get the key that was previously inserted
     let old_key = elements.insert(chosen_bucket, key.clone()).unwrap();
+    *evictions += 1;
 
     // Re-insert the key that we're relocating
-    insert(elements, buckets, &old_key, attempt + 1, rng)
+    insert(elements, buckets, &old_key, attempt + 1, rng, evictions)
 }
 
 impl<K, V> BatchCode<K, V> for CuckooCode
 where
-    K: Clone + Serialize + BitXor<Output = K> + BitXorAssign + cmp::Eq + hash::Hash,
-    V: Clone + Serialize + BitXor<Output = V> + BitXorAssign,
+    K: Clone + Serialize + cmp::Eq + hash::Hash,
+    V: Clone + Serialize,
 {
-    // Encoding is placing each entry to d buckets
+    // Encoding is placing each entry to d buckets, followed by s stash buckets
+    // that contain the entire collection.
     // This is very different from standard cuckoo hashing.
-    fn encode(&self, collection: &[Tuple<K, V>]) -> Vec<Vec<Tuple<K, V>>> {
+    fn encode_view(&self, collection: &[Tuple<K, V>]) -> Result<EncodedView, ScheduleError> {
         let total_buckets = (self.k as f64 * self.r).ceil() as usize;
-        let mut collections: Vec<Vec<Tuple<K, V>>> = Vec::with_capacity(total_buckets);
-
-        for _ in 0..total_buckets {
-            collections.push(Vec::new());
-        }
-
-        for entry in collection {
-            // First get the binary representation of the key
-            let bytes = serialize(&entry.t.0).unwrap();
+        let mut collections: Vec<Vec<usize>> = vec![Vec::new(); total_buckets];
 
-            let mut bucket_choices = Vec::with_capacity(self.d);
+        let order = super::slot_order(collection.iter().map(|e| &e.t.0))?;
 
+        for (i, bytes) in &order {
             // Map entry's key to d buckets (no repeats)
-            for id in 0..self.d {
-                let mut nonce = 0;
+            for bucket in super::bucket_choices(self.hasher.as_ref(), bytes, self.d, total_buckets)
+            {
+                collections[bucket].push(*i);
+            }
+        }
 
-                // The following computes bucket = sha_d(key) % total_buckets
-                let mut bucket = super::hash_and_mod(id, nonce, &bytes, total_buckets);
+        let mut buckets: Vec<BucketView> = Vec::with_capacity(total_buckets + self.s);
+        buckets.extend(collections.into_iter().map(BucketView::Entries));
 
-                // Ensure each key maps to *different* buckets
-                while bucket_choices.contains(&bucket) {
-                    nonce += 1;
-                    bucket = super::hash_and_mod(id, nonce, &bytes, total_buckets);
-                }
+        // The first stash bucket lists the whole collection and the rest are copies of it
+        if self.s > 0 {
+            buckets.push(BucketView::Entries(order.iter().map(|(i, _)| *i).collect()));
+        }
 
-                bucket_choices.push(bucket);
-                collections[bucket].push(entry.clone());
-            }
+        for _ in 1..self.s {
+            buckets.push(BucketView::Copy(total_buckets));
         }
 
-        collections
+        Ok(EncodedView::new(buckets))
     }
 
-    fn get_schedule(&self, keys: &[K]) -> Option<HashMap<K, Vec<usize>>> {
-        assert!(keys.len() <= self.k);
-
-        let total_buckets = (self.k as f64 * self.r).ceil() as usize;
+    fn get_schedule(&self, keys: &[K]) -> Result<HashMap<K, Vec<usize>>, ScheduleError> {
+        self.schedule(keys, &mut rand::thread_rng(), &mut 0, None)
+    }
 
-        let mut buckets = HashMap::new(); // map containing K -> [bucket 1, ..., bucket d]
+    fn get_schedule_with_rng(
+        &self,
+        keys: &[K],
+        rng: &mut dyn Rng,
+    ) -> Result<HashMap<K, Vec<usize>>, ScheduleError> {
+        self.schedule(keys, rng, &mut 0, None)
+    }
 
-        for key in keys {
-            let bytes = serialize(&key).unwrap();
-            let mut bucket_choices = Vec::with_capacity(self.d);
+    fn get_schedule_with_retries(
+        &self,
+        keys: &[K],
+        rng: &mut dyn Rng,
+        retries: &mut usize,
+    ) -> Result<HashMap<K, Vec<usize>>, ScheduleError> {
+        self.schedule(keys, rng, retries, None)
+    }
 
-            // Map entry's key to d buckets (no repeats)
-            for id in 0..self.d {
-                let mut nonce = 0;
+    fn get_partial_schedule(&self, keys: &[K]) -> Result<PartialSchedule<K>, ScheduleError> {
+        BatchCode::<K, V>::get_partial_schedule_with_rng(self, keys, &mut rand::thread_rng())
+    }
 
-                // The following computes bucket = sha_d(key) % k;
-                let mut bucket = super::hash_and_mod(id, nonce, &bytes, total_buckets);
+    fn get_partial_schedule_with_rng(
+        &self,
+        keys: &[K],
+        rng: &mut dyn Rng,
+    ) -> Result<PartialSchedule<K>, ScheduleError> {
+        let (batch, mut leftover) = super::split_batch(keys, self.k)?;
+        let schedule = self.schedule(batch, rng, &mut 0, Some(&mut leftover))?;
+        Ok(PartialSchedule { schedule, leftover })
+    }
 
-                // Ensure each key maps to *different* buckets
-                while bucket_choices.contains(&bucket) {
-                    nonce += 1;
-                    bucket = super::hash_and_mod(id, nonce, &bytes, total_buckets);
-                }
+    fn decode(&self, results: &[Tuple<K, V>]) -> Result<Tuple<K, V>, ScheduleError> {
+        super::check_results(1, results.len())?;
+        Ok(results[0].clone())
+    }
 
-                bucket_choices.push(bucket);
-            }
+    // Only Matching and Bfs find a schedule whenever one exists. By Hall's theorem, more than s
+    // keys are left without a bucket only if some set of t keys has all of its choices in a set
+    // of t - 1 - s buckets (and a smallest such set of keys has exactly that many). Union bound
+    // over both sets; the heuristic has no known bound.
+    fn failure_bound(&self) -> Option<f64> {
+        let total_buckets = (self.k as f64 * self.r).ceil() as usize;
 
-            buckets.insert(key, bucket_choices);
+        if self.strategy == Strategy::Heuristic || self.d > total_buckets {
+            return None;
         }
 
-        // This is a variant of the Insert algorithm in cuckoo hashing (Pagh and Rodler).
-        // One difference is we only have 1 table and d hash functions.
-        // The difference is that we are doing this for retrieval rather than insertion! (the keys
-        // have already been inserted). What this means is that cuckoo hashing is being applied
-        // with respect to the client's keys (not the keys that the storage server received).
-        // This is a crucial but subtle difference.
+        let ln_fact = super::padding::ln_factorials(cmp::max(self.k, total_buckets));
+        let ln_choose = |n: usize, r: usize| ln_fact[n] - ln_fact[r] - ln_fact[n - r];
+        let mut bound = 0.0;
 
-        let mut rng = rand::thread_rng();
-        let mut elements = HashMap::new(); // map containing bucket -> [current key]
+        for t in (self.s + 1 + self.d)..=self.k {
+            let u = t - 1 - self.s;
 
-        for key in keys {
-            if !insert(&mut elements, &buckets, &key, 0, &mut rng) {
-                return None;
+            if u > total_buckets {
+                break;
             }
+
+            let ln_all_inside = ln_choose(u, self.d) - ln_choose(total_buckets, self.d);
+            bound +=
+                (ln_choose(self.k, t) + ln_choose(total_buckets, u) + t as f64 * ln_all_inside)
+                    .exp();
         }
 
-        let mut schedule = HashMap::new();
+        Some(bound.min(1.0))
+    }
 
-        for (k, v) in elements {
-            schedule.insert(v.clone(), vec![k]);
+    // Each entry is in d of the ceil(k * r) buckets, and stash buckets hold every entry
+    fn bucket_bound(&self, n: usize, security: u32) -> usize {
+        if self.s > 0 {
+            return n;
         }
 
-        assert_eq!(schedule.len(), keys.len());
-
-        Some(schedule)
+        let total_buckets = (self.k as f64 * self.r).ceil() as usize;
+        cmp::min(
+            n,
+            super::padding::load_bound(n * self.d, total_buckets, security),
+        )
     }
 
-    fn decode(&self, results: &[Tuple<K, V>]) -> Tuple<K, V> {
-        assert_eq!(results.len(), 1);
-        results[0].clone()
+    // Each of the d-choice buckets is its own group, and the stash buckets form one group
+    fn layout(&self, collection_keys: &[K]) -> Result<Layout<K>, ScheduleError> {
+        let total_buckets = (self.k as f64 * self.r).ceil() as usize;
+        let groups = (0..total_buckets + self.s)
+            .map(|i| cmp::min(i, total_buckets))
+            .collect();
+        let mut layout = Layout::new(groups);
+
+        for (i, bytes) in super::slot_order(collection_keys.iter())? {
+            for bucket in super::bucket_choices(self.hasher.as_ref(), &bytes, self.d, total_buckets)
+            {
+                layout.place(collection_keys[i].clone(), bucket);
+            }
+
+            if self.s > 0 {
+                layout.place(collection_keys[i].clone(), total_buckets);
+            }
+        }
+
+        for i in 1..self.s {
+            layout.set_len(total_buckets + i, layout.bucket_len(total_buckets));
+        }
+
+        Ok(layout)
     }
 }
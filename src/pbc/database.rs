@@ -0,0 +1,234 @@
+use super::view::{BucketView, EncodedView};
+use super::{BatchCode, ScheduleError, Tuple, Xor};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::{cmp, hash};
+
+/// The output of `BatchCode::encode` together with the code that produced it, which can be
+/// modified one entry at a time. A change only touches the buckets where the entry's key is
+/// placed, their replicas and the parity sub-buckets (for PungCode and SubcubeCode) computed
+/// from them. Each change returns the indices of the buckets it modified, so that only those
+/// buckets need to be set up again on the PIR server (see `MultiPirServer::setup_bucket`).
+///
+/// Entries keep the order used by `encode`, so the buckets always match `encode` on the
+/// current collection, and `BatchCode::layout` on the current keys stays valid.
+pub struct EncodedDatabase<C, K, V>
+where
+    C: BatchCode<K, V>,
+    K: Clone + Serialize + cmp::Eq + hash::Hash,
+    V: Clone + Serialize,
+{
+    code: C,
+    buckets: Vec<Vec<Tuple<K, V>>>,
+    structure: EncodedView, // encoding of an empty collection: which buckets are derived
+    xor: Option<Combine<K, V>>, // combines the rows of Parity buckets
+}
+
+// XOR of two rows, for keys and values that can be XORed
+type Combine<K, V> = fn(Tuple<K, V>, Tuple<K, V>) -> Tuple<K, V>;
+
+// How a change affects a bucket of the database
+#[derive(Clone)]
+enum Change<K, V>
+where
+    K: Clone + Serialize,
+    V: Clone + Serialize,
+{
+    Insert(usize, Tuple<K, V>),
+    Remove(usize),
+    Update(usize, Tuple<K, V>),
+}
+
+impl<C, K, V> EncodedDatabase<C, K, V>
+where
+    C: BatchCode<K, V>,
+    K: Clone + Serialize + cmp::Eq + hash::Hash,
+    V: Clone + Serialize,
+{
+    /// Encodes the collection with the given code.
+    pub fn new(code: C, collection: &[Tuple<K, V>]) -> Result<Self, ScheduleError>
+    where
+        K: Xor,
+        V: Xor,
+    {
+        Self::with_xor(code, collection, Some(|a, b| a ^ b))
+    }
+
+    /// Same as `new`, but for keys and values that cannot be XORed. Fails with `RequiresXor` if
+    /// the code's encoding has Parity buckets (see `EncodedView::is_systematic`).
+    pub fn new_systematic(code: C, collection: &[Tuple<K, V>]) -> Result<Self, ScheduleError> {
+        Self::with_xor(code, collection, None)
+    }
+
+    fn with_xor(
+        code: C,
+        collection: &[Tuple<K, V>],
+        xor: Option<Combine<K, V>>,
+    ) -> Result<Self, ScheduleError> {
+        let structure = code.encode_view(&[])?;
+
+        if xor.is_none() && !structure.is_systematic() {
+            return Err(ScheduleError::RequiresXor);
+        }
+
+        let buckets = code.encode(collection)?;
+
+        Ok(EncodedDatabase {
+            code,
+            buckets,
+            structure,
+            xor,
+        })
+    }
+
+    pub fn code(&self) -> &C {
+        &self.code
+    }
+
+    pub fn buckets(&self) -> &[Vec<Tuple<K, V>>] {
+        &self.buckets
+    }
+
+    pub fn into_buckets(self) -> Vec<Vec<Tuple<K, V>>> {
+        self.buckets
+    }
+
+    /// Adds an entry whose key is not yet in the collection.
+    pub fn insert(&mut self, entry: Tuple<K, V>) -> Result<Vec<usize>, ScheduleError> {
+        let key = entry.t.0.clone();
+
+        self.apply(&key, |found, row| {
+            if found {
+                return Err(ScheduleError::KeyExists);
+            }
+
+            Ok(Change::Insert(row, entry.clone()))
+        })
+    }
+
+    /// Removes the entry with the given key.
+    pub fn remove(&mut self, key: &K) -> Result<Vec<usize>, ScheduleError> {
+        self.apply(key, |found, row| {
+            if !found {
+                return Err(ScheduleError::KeyNotFound);
+            }
+
+            Ok(Change::Remove(row))
+        })
+    }
+
+    /// Replaces the value of the entry with the same key.
+    pub fn update(&mut self, entry: Tuple<K, V>) -> Result<Vec<usize>, ScheduleError> {
+        let key = entry.t.0.clone();
+
+        self.apply(&key, |found, row| {
+            if !found {
+                return Err(ScheduleError::KeyNotFound);
+            }
+
+            Ok(Change::Update(row, entry.clone()))
+        })
+    }
+
+    // Applies the change returned by op (given whether the key is present in a bucket, and its
+    // row there) to each bucket where the code places the key, and then updates the buckets
+    // derived from those. Returns the indices of the buckets that changed.
+    fn apply<F>(&mut self, key: &K, op: F) -> Result<Vec<usize>, ScheduleError>
+    where
+        F: Fn(bool, usize) -> Result<Change<K, V>, ScheduleError>,
+    {
+        // The buckets holding the key are the ones it occupies in a collection of its own
+        let layout = self.code.layout(std::slice::from_ref(key))?;
+        let target = super::slot_key(key)?;
+
+        // Decide on every change before modifying anything, so that errors leave no trace
+        let mut changes: BTreeMap<usize, Change<K, V>> = BTreeMap::new();
+
+        for (i, bucket) in self.structure.buckets().iter().enumerate() {
+            if let BucketView::Entries(_) = bucket {
+                if layout.bucket_len(i) > 0 {
+                    let (found, row) = self.search(i, &target)?;
+                    changes.insert(i, op(found, row)?);
+                }
+            }
+        }
+
+        // Replicas change in the same way as their source. Derived buckets only refer to
+        // earlier buckets, so one pass in order suffices.
+        for (i, bucket) in self.structure.buckets().iter().enumerate() {
+            if let BucketView::Copy(source) = bucket {
+                if let Some(change) = changes.get(source).cloned() {
+                    changes.insert(i, change);
+                }
+            }
+        }
+
+        for (i, change) in &changes {
+            match change.clone() {
+                Change::Insert(row, entry) => self.buckets[*i].insert(row, entry),
+                Change::Remove(row) => {
+                    self.buckets[*i].remove(row);
+                }
+                Change::Update(row, entry) => self.buckets[*i][row] = entry,
+            }
+        }
+
+        let mut dirty: BTreeSet<usize> = changes.keys().cloned().collect();
+
+        for (i, bucket) in self.structure.buckets().iter().enumerate() {
+            if let BucketView::Parity(sources) = bucket {
+                if sources.iter().any(|s| dirty.contains(s)) {
+                    self.buckets[i] = self.parity(sources);
+                    dirty.insert(i);
+                }
+            }
+        }
+
+        Ok(dirty.into_iter().collect())
+    }
+
+    // Binary search for the key (given its slot_key) in a bucket whose entries are in slot
+    // order. Returns whether the key was found, and its row (or the row where it would go).
+    fn search(
+        &self,
+        bucket: usize,
+        target: &(Vec<u8>, Vec<u8>),
+    ) -> Result<(bool, usize), ScheduleError> {
+        let entries = &self.buckets[bucket];
+        let (mut low, mut high) = (0, entries.len());
+
+        while low < high {
+            let mid = (low + high) / 2;
+
+            match super::slot_key(&entries[mid].t.0)?.cmp(target) {
+                cmp::Ordering::Less => low = mid + 1,
+                cmp::Ordering::Greater => high = mid,
+                cmp::Ordering::Equal => return Ok((true, mid)),
+            }
+        }
+
+        Ok((false, low))
+    }
+
+    // Row r of a parity bucket is the XOR of row r of each source that has such a row. Only
+    // databases created with `new` have Parity buckets, and therefore xor.
+    fn parity(&self, sources: &[usize]) -> Vec<Tuple<K, V>> {
+        let xor = self.xor.unwrap();
+        let rows = sources
+            .iter()
+            .map(|s| self.buckets[*s].len())
+            .max()
+            .unwrap_or(0);
+
+        (0..rows)
+            .map(|r| {
+                sources
+                    .iter()
+                    .filter_map(|s| self.buckets[*s].get(r))
+                    .cloned()
+                    .reduce(xor)
+                    .unwrap()
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,355 @@
+use super::choices::ChoicesCode;
+use super::cuckoo::CuckooCode;
+use super::hasher::{CodeId, HasherSpec};
+use super::pung::PungCode;
+use super::replication::ReplicationCode;
+use super::sharding::ShardingCode;
+use super::subcube::{SubcubeCode, MAX_DIM};
+use super::view::BucketView;
+use super::{BatchCode, Strategy, Xor, MAX_BUCKETS};
+use serde::Serialize;
+use std::{cmp, error, fmt, hash};
+
+/// Version of the CodeDescriptor format. It changes whenever a descriptor of the same code
+/// could describe a different encoding.
+pub const DESCRIPTOR_VERSION: u32 = 1;
+
+/// A batch code and the parameters it was constructed with.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum CodeParams {
+    Replication {
+        k: usize,
+    },
+    Sharding {
+        k: usize,
+        replicas: usize,
+        strategy: Strategy,
+    },
+    Choices {
+        k: usize,
+        d: usize,
+        replicas: usize,
+        strategy: Strategy,
+    },
+    Cuckoo {
+        k: usize,
+        d: usize,
+        r: f64,
+        stash: usize,
+        strategy: Strategy,
+    },
+    Pung {
+        k: usize,
+    },
+    Subcube {
+        l: usize,
+        dim: usize,
+    },
+}
+
+impl CodeParams {
+    pub fn id(&self) -> CodeId {
+        match *self {
+            CodeParams::Replication { .. } => CodeId::Replication,
+            CodeParams::Sharding { .. } => CodeId::Sharding,
+            CodeParams::Choices { .. } => CodeId::Choices,
+            CodeParams::Cuckoo { .. } => CodeId::Cuckoo,
+            CodeParams::Pung { .. } => CodeId::Pung,
+            CodeParams::Subcube { .. } => CodeId::Subcube,
+        }
+    }
+
+    /// Checks the conditions under which the code's constructor would panic.
+    pub fn validate(&self) -> Result<(), DescriptorError> {
+        let invalid = |reason: &str| Err(DescriptorError::InvalidParams(reason.to_string()));
+
+        match *self {
+            CodeParams::Replication { k } if k == 0 || k > MAX_BUCKETS => {
+                invalid("replication needs 1 <= k <= MAX_BUCKETS")
+            }
+            CodeParams::Sharding { k, replicas, .. } => ShardingCode::with_replicas(k, replicas)
+                .map(|_| ())
+                .map_err(|e| DescriptorError::InvalidParams(e.to_string())),
+            CodeParams::Choices { k, d, replicas, .. } => {
+                ChoicesCode::with_replicas(k, d, replicas)
+                    .map(|_| ())
+                    .map_err(|e| DescriptorError::InvalidParams(e.to_string()))
+            }
+            CodeParams::Cuckoo { k, d, r, stash, .. } => CuckooCode::with_stash(k, d, r, stash)
+                .map(|_| ())
+                .map_err(|e| DescriptorError::InvalidParams(e.to_string())),
+            CodeParams::Pung { k }
+                if k <= 4 || k.checked_mul(9).is_none_or(|n| n > MAX_BUCKETS) =>
+            {
+                invalid("pung needs k > 4 and at most MAX_BUCKETS (9k) buckets")
+            }
+            CodeParams::Subcube { l, dim }
+                if l == 0
+                    || dim == 0
+                    || dim > MAX_DIM
+                    || u32::try_from(dim)
+                        .ok()
+                        .and_then(|dim| l.checked_add(1)?.checked_pow(dim))
+                        .is_none_or(|total| total > MAX_BUCKETS) =>
+            {
+                invalid(
+                    "subcube needs l >= 1, 1 <= dim <= MAX_DIM and at most MAX_BUCKETS sub-buckets",
+                )
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Builds the code, placing keys with the given hasher. Panics if the parameters are not
+    /// valid (see `validate`) or the hasher is not supported (see `HasherSpec::is_supported`).
+    pub fn build<K, V>(&self, hasher: HasherSpec) -> Box<dyn BatchCode<K, V>>
+    where
+        K: Clone + Serialize + Xor + cmp::Eq + hash::Hash,
+        V: Clone + Serialize + Xor,
+    {
+        match *self {
+            CodeParams::Pung { k } => {
+                let mut code = PungCode::new(k);
+                code.set_hasher(hasher.build());
+                Box::new(code)
+            }
+            CodeParams::Subcube { l, dim } => {
+                let mut code = SubcubeCode::new(l, dim);
+                code.set_hasher(hasher.build());
+                Box::new(code)
+            }
+            _ => self.build_systematic(hasher).unwrap(),
+        }
+    }
+
+    /// Same as `build`, but for keys and values that cannot be XORed. Returns None for the
+    /// codes that need XOR (see `XorCode`).
+    pub fn build_systematic<K, V>(&self, hasher: HasherSpec) -> Option<Box<dyn BatchCode<K, V>>>
+    where
+        K: Clone + Serialize + cmp::Eq + hash::Hash,
+        V: Clone + Serialize,
+    {
+        let code: Box<dyn BatchCode<K, V>> = match *self {
+            CodeParams::Replication { k } => Box::new(ReplicationCode::new(k)),
+            CodeParams::Sharding {
+                k,
+                replicas,
+                strategy,
+            } => {
+                let mut code = ShardingCode::with_replicas(k, replicas).unwrap();
+                code.set_strategy(strategy);
+                code.set_hasher(hasher.build());
+                Box::new(code)
+            }
+            CodeParams::Choices {
+                k,
+                d,
+                replicas,
+                strategy,
+            } => {
+                let mut code = ChoicesCode::with_replicas(k, d, replicas).unwrap();
+                code.set_strategy(strategy);
+                code.set_hasher(hasher.build());
+                Box::new(code)
+            }
+            CodeParams::Cuckoo {
+                k,
+                d,
+                r,
+                stash,
+                strategy,
+            } => {
+                let mut code = CuckooCode::with_stash(k, d, r, stash).unwrap();
+                code.set_strategy(strategy);
+                code.set_hasher(hasher.build());
+                Box::new(code)
+            }
+            CodeParams::Pung { .. } | CodeParams::Subcube { .. } => return None,
+        };
+
+        Some(code)
+    }
+}
+
+/// Everything a client needs to reproduce the server's encoding: the code, its parameters,
+/// the hash function that places keys (including its version), and the length of every
+/// bucket. The server can publish a serialized descriptor, and clients instantiate an
+/// identical code from it with `CodeDescriptor::code`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CodeDescriptor {
+    /// Format version (see DESCRIPTOR_VERSION).
+    pub version: u32,
+    pub params: CodeParams,
+    pub hasher: HasherSpec,
+    /// Number of entries in each bucket (after padding, if any). Sizes of Entries buckets
+    /// depend on the collection and are not checked; every copy of a bucket must have its
+    /// size, and every parity bucket the size of its largest source (see `BucketView`).
+    pub bucket_sizes: Vec<usize>,
+}
+
+impl CodeDescriptor {
+    pub fn new(params: CodeParams, hasher: HasherSpec, bucket_sizes: Vec<usize>) -> Self {
+        CodeDescriptor {
+            version: DESCRIPTOR_VERSION,
+            params,
+            hasher,
+            bucket_sizes,
+        }
+    }
+
+    /// Builds the described code. Fails if the descriptor or its hasher has a version that
+    /// this crate does not implement, if a SaltedHasher is bound to a different code, if the
+    /// parameters are invalid, or if the bucket sizes do not match the code.
+    pub fn code<K, V>(&self) -> Result<Box<dyn BatchCode<K, V>>, DescriptorError>
+    where
+        K: Clone + Serialize + Xor + cmp::Eq + hash::Hash,
+        V: Clone + Serialize + Xor,
+    {
+        self.check()?;
+        self.check_buckets(self.params.build(self.hasher))
+    }
+
+    /// Same as `code`, but for keys and values that cannot be XORed (e.g., String keys). Also
+    /// fails with `RequiresXor` if the descriptor is for a code that needs XOR.
+    pub fn systematic_code<K, V>(&self) -> Result<Box<dyn BatchCode<K, V>>, DescriptorError>
+    where
+        K: Clone + Serialize + cmp::Eq + hash::Hash,
+        V: Clone + Serialize,
+    {
+        self.check()?;
+
+        match self.params.build_systematic(self.hasher) {
+            Some(code) => self.check_buckets(code),
+            None => Err(DescriptorError::RequiresXor(self.params.id())),
+        }
+    }
+
+    fn check(&self) -> Result<(), DescriptorError> {
+        if self.version != DESCRIPTOR_VERSION {
+            return Err(DescriptorError::UnsupportedVersion {
+                version: self.version,
+            });
+        }
+
+        if !self.hasher.is_supported() {
+            return Err(DescriptorError::UnsupportedHasher {
+                version: self.hasher.version(),
+            });
+        }
+
+        if let HasherSpec::Salted { code, .. } = self.hasher {
+            if code != self.params.id() {
+                return Err(DescriptorError::HasherCodeMismatch {
+                    expected: self.params.id(),
+                    actual: code,
+                });
+            }
+        }
+
+        self.params.validate()
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn check_buckets<K, V>(
+        &self,
+        code: Box<dyn BatchCode<K, V>>,
+    ) -> Result<Box<dyn BatchCode<K, V>>, DescriptorError>
+    where
+        K: Clone + Serialize + cmp::Eq + hash::Hash,
+        V: Clone + Serialize,
+    {
+        let buckets = code
+            .encode_view(&[])
+            .map_or(vec![], |view| view.buckets().to_vec());
+
+        if buckets.len() != self.bucket_sizes.len() {
+            return Err(DescriptorError::BucketCountMismatch {
+                expected: buckets.len(),
+                actual: self.bucket_sizes.len(),
+            });
+        }
+
+        for (bucket, view) in buckets.iter().enumerate() {
+            let expected = match view {
+                BucketView::Entries(_) => continue,
+                BucketView::Copy(source) => self.bucket_sizes[*source],
+                BucketView::Parity(sources) => sources
+                    .iter()
+                    .map(|s| self.bucket_sizes[*s])
+                    .max()
+                    .unwrap_or(0),
+            };
+
+            if self.bucket_sizes[bucket] != expected {
+                return Err(DescriptorError::BucketSizeMismatch {
+                    bucket,
+                    expected,
+                    actual: self.bucket_sizes[bucket],
+                });
+            }
+        }
+
+        Ok(code)
+    }
+}
+
+/// Reasons why a CodeDescriptor cannot be turned into a code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DescriptorError {
+    /// The descriptor format has a version that this crate does not implement.
+    UnsupportedVersion { version: u32 },
+    /// The hasher has a version that this crate does not implement.
+    UnsupportedHasher { version: u32 },
+    /// The code's constructor would reject these parameters.
+    InvalidParams(String),
+    /// The SaltedHasher is bound to a different code than the descriptor's parameters.
+    HasherCodeMismatch { expected: CodeId, actual: CodeId },
+    /// The code has a different number of buckets than the descriptor lists sizes for.
+    BucketCountMismatch { expected: usize, actual: usize },
+    /// The bucket is a copy or parity of other buckets, but its size does not match theirs.
+    BucketSizeMismatch {
+        bucket: usize,
+        expected: usize,
+        actual: usize,
+    },
+    /// The code needs keys and values that can be XORed (see `CodeDescriptor::code`).
+    RequiresXor(CodeId),
+}
+
+impl fmt::Display for DescriptorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DescriptorError::UnsupportedVersion { version } => write!(
+                f,
+                "descriptor version {version} is not supported (expected {DESCRIPTOR_VERSION})"
+            ),
+            DescriptorError::UnsupportedHasher { version } => {
+                write!(f, "bucket hash version {version} is not supported")
+            }
+            DescriptorError::InvalidParams(reason) => {
+                write!(f, "invalid code parameters: {reason}")
+            }
+            DescriptorError::HasherCodeMismatch { expected, actual } => write!(
+                f,
+                "hasher is bound to {actual:?} codes but the descriptor is for {expected:?}"
+            ),
+            DescriptorError::BucketCountMismatch { expected, actual } => write!(
+                f,
+                "code has {expected} buckets but the descriptor lists {actual}"
+            ),
+            DescriptorError::BucketSizeMismatch {
+                bucket,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "bucket {bucket} should have {expected} entries but the descriptor lists {actual}"
+            ),
+            DescriptorError::RequiresXor(id) => {
+                write!(f, "{id:?} codes need keys and values that can be XORed")
+            }
+        }
+    }
+}
+
+impl error::Error for DescriptorError {}
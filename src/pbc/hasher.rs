@@ -0,0 +1,324 @@
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use num::bigint::BigUint;
+use num::cast::ToPrimitive;
+use num::Integer;
+use rand::Rng;
+use std::sync::Arc;
+
+/// Maps keys (given by their binary representation) to buckets. Codes that hash keys take a
+/// BucketHasher (e.g., `CuckooCode::set_hasher`), and the client and the server must use the
+/// same one. They can agree on it through its `HasherSpec`, which the server can publish.
+pub trait BucketHasher: Send + Sync {
+    /// Hashes data into 0..modulus. Each (id, nonce) pair selects a different hash function.
+    fn hash_and_mod(&self, id: usize, nonce: usize, data: &[u8], modulus: usize) -> usize;
+
+    /// Description from which an identical hasher can be built (see `HasherSpec::build`).
+    fn spec(&self) -> HasherSpec;
+}
+
+/// Serializable description of a BucketHasher.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum HasherSpec {
+    /// See Sha256Hasher. This is what every code uses unless told otherwise.
+    #[default]
+    Sha256,
+    /// See KeyedHasher.
+    SipHash { key: [u8; 16] },
+    /// See SaltedHasher.
+    Salted {
+        version: u32,
+        salt: [u8; 32],
+        code: CodeId,
+    },
+}
+
+impl HasherSpec {
+    /// Whether this crate implements the hasher. Specs received from elsewhere (e.g., in a
+    /// published planner::Plan) may name a SaltedHasher version that it does not.
+    pub fn is_supported(&self) -> bool {
+        match *self {
+            HasherSpec::Salted { version, .. } => version == SaltedHasher::VERSION,
+            _ => true,
+        }
+    }
+
+    /// Version of the hashing scheme. Sha256Hasher and KeyedHasher are version 1.
+    pub fn version(&self) -> u32 {
+        match *self {
+            HasherSpec::Salted { version, .. } => version,
+            _ => 1,
+        }
+    }
+
+    /// Builds the hasher. Panics if it is not supported (see `is_supported`).
+    pub fn build(&self) -> Arc<dyn BucketHasher> {
+        match *self {
+            HasherSpec::Sha256 => Arc::new(Sha256Hasher),
+            HasherSpec::SipHash { key } => Arc::new(KeyedHasher::new(key)),
+            HasherSpec::Salted {
+                version,
+                salt,
+                code,
+            } => {
+                assert_eq!(
+                    version,
+                    SaltedHasher::VERSION,
+                    "Unsupported bucket hash version"
+                );
+                Arc::new(SaltedHasher::new(salt, code))
+            }
+        }
+    }
+
+    /// The same spec, bound to the given code if it is a SaltedHasher.
+    pub fn for_code(&self, code: CodeId) -> HasherSpec {
+        match *self {
+            HasherSpec::Salted { version, salt, .. } => HasherSpec::Salted {
+                version,
+                salt,
+                code,
+            },
+            spec => spec,
+        }
+    }
+}
+
+/// Identifies a batch code in the input of SaltedHasher, so that different codes over the
+/// same database (and salt) hash keys independently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum CodeId {
+    Replication = 1,
+    Sharding = 2,
+    Choices = 3,
+    Cuckoo = 4,
+    Pung = 5,
+    Subcube = 6,
+}
+
+/// The original bucket hash: SHA-256 of the decimal id and nonce followed by the data, taken
+/// modulo the number of buckets as a little-endian big integer. Collections encoded by earlier
+/// versions of this crate use this hasher.
+///
+/// The decimal encoding is ambiguous: (id, nonce) pairs such as (1, 11) and (11, 1) select the
+/// same hash function. New deployments should use SaltedHasher or KeyedHasher instead.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sha256Hasher;
+
+impl BucketHasher for Sha256Hasher {
+    fn hash_and_mod(&self, id: usize, nonce: usize, data: &[u8], modulus: usize) -> usize {
+        let mut digest = Sha256::new();
+        digest.input_str(&format!("{id}{nonce}"));
+
+        // hash the key and get the result
+        digest.input(data);
+        let mut hash: Vec<u8> = vec![0; digest.output_bytes()];
+        digest.result(&mut hash);
+
+        // convert hash into a big integer and perform modulo k
+        let int_value = BigUint::from_bytes_le(&hash);
+        int_value
+            .mod_floor(&BigUint::from(modulus))
+            .to_usize()
+            .unwrap()
+    }
+
+    fn spec(&self) -> HasherSpec {
+        HasherSpec::Sha256
+    }
+}
+
+/// A much faster bucket hash: SipHash-2-4 keyed with a 128-bit seed, over the id and the nonce
+/// (8 bytes each, little endian) followed by the data. The seed need not be secret, but a
+/// random one keeps anyone who does not know it from picking keys that collide.
+#[derive(Debug, Clone, Copy)]
+pub struct KeyedHasher {
+    key: [u8; 16],
+}
+
+impl KeyedHasher {
+    pub fn new(key: [u8; 16]) -> KeyedHasher {
+        KeyedHasher { key }
+    }
+
+    /// Creates a hasher with a seed drawn from rng.
+    pub fn random(rng: &mut dyn Rng) -> KeyedHasher {
+        let mut key = [0u8; 16];
+        rng.fill_bytes(&mut key);
+        KeyedHasher { key }
+    }
+
+    /// SipHash-2-4, under this hasher's key, of the concatenation of parts.
+    pub fn hash(&self, parts: &[&[u8]]) -> u64 {
+        let mut state = SipState::new(&self.key);
+
+        for part in parts {
+            state.write(part);
+        }
+
+        state.finish()
+    }
+}
+
+impl BucketHasher for KeyedHasher {
+    fn hash_and_mod(&self, id: usize, nonce: usize, data: &[u8], modulus: usize) -> usize {
+        let id = (id as u64).to_le_bytes();
+        let nonce = (nonce as u64).to_le_bytes();
+
+        (self.hash(&[&id, &nonce, data]) % modulus as u64) as usize
+    }
+
+    fn spec(&self) -> HasherSpec {
+        HasherSpec::SipHash { key: self.key }
+    }
+}
+
+/// Version 2 of the SHA-256 bucket hash, which fixes the ambiguity of Sha256Hasher. The digest
+/// input is a domain separation tag, the version, the code identifier, a 32-byte per-database
+/// salt, the id, the nonce and a counter (all of fixed width), followed by the data. The digest
+/// is read as 64-bit words, and words in the biased range above the largest multiple of the
+/// modulus are rejected (moving on to the next word, and then to the next counter), so every
+/// bucket is equally likely.
+///
+/// The version, salt and code are part of the hasher's spec, so a server that publishes it
+/// (e.g., in a planner::Plan) fixes the scheme its clients use.
+#[derive(Debug, Clone, Copy)]
+pub struct SaltedHasher {
+    salt: [u8; 32],
+    code: CodeId,
+}
+
+impl SaltedHasher {
+    pub const VERSION: u32 = 2;
+    const DOMAIN: &'static [u8; 16] = b"mpir-bucket-hash";
+
+    pub fn new(salt: [u8; 32], code: CodeId) -> SaltedHasher {
+        SaltedHasher { salt, code }
+    }
+
+    /// Creates a hasher with a salt drawn from rng.
+    pub fn random(code: CodeId, rng: &mut dyn Rng) -> SaltedHasher {
+        let mut salt = [0u8; 32];
+        rng.fill_bytes(&mut salt);
+        SaltedHasher { salt, code }
+    }
+}
+
+impl BucketHasher for SaltedHasher {
+    fn hash_and_mod(&self, id: usize, nonce: usize, data: &[u8], modulus: usize) -> usize {
+        assert!(modulus > 0, "Modulus must be positive");
+
+        let modulus = modulus as u64;
+        let zone = u64::MAX - (u64::MAX - modulus + 1) % modulus; // largest multiple - 1
+
+        for counter in 0u32.. {
+            let mut digest = Sha256::new();
+            digest.input(Self::DOMAIN);
+            digest.input(&Self::VERSION.to_le_bytes());
+            digest.input(&[self.code as u8]);
+            digest.input(&self.salt);
+            digest.input(&(id as u64).to_le_bytes());
+            digest.input(&(nonce as u64).to_le_bytes());
+            digest.input(&counter.to_le_bytes());
+            digest.input(data);
+
+            let mut hash = [0u8; 32];
+            digest.result(&mut hash);
+
+            for word in hash.chunks_exact(8) {
+                let value = u64::from_le_bytes(word.try_into().unwrap());
+
+                if value <= zone {
+                    return (value % modulus) as usize;
+                }
+            }
+        }
+
+        unreachable!()
+    }
+
+    fn spec(&self) -> HasherSpec {
+        HasherSpec::Salted {
+            version: Self::VERSION,
+            salt: self.salt,
+            code: self.code,
+        }
+    }
+}
+
+// SipHash-2-4 (Aumasson and Bernstein) over input written in pieces
+struct SipState {
+    v: [u64; 4],
+    tail: u64,    // bytes not yet in a full word
+    ntail: usize, // number of such bytes
+    len: usize,
+}
+
+impl SipState {
+    fn new(key: &[u8; 16]) -> SipState {
+        let k0 = u64::from_le_bytes(key[..8].try_into().unwrap());
+        let k1 = u64::from_le_bytes(key[8..].try_into().unwrap());
+
+        SipState {
+            v: [
+                k0 ^ 0x736f_6d65_7073_6575,
+                k1 ^ 0x646f_7261_6e64_6f6d,
+                k0 ^ 0x6c79_6765_6e65_7261,
+                k1 ^ 0x7465_6462_7974_6573,
+            ],
+            tail: 0,
+            ntail: 0,
+            len: 0,
+        }
+    }
+
+    fn round(&mut self) {
+        let v = &mut self.v;
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13) ^ v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16) ^ v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21) ^ v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17) ^ v[2];
+        v[2] = v[2].rotate_left(32);
+    }
+
+    fn compress(&mut self, m: u64) {
+        self.v[3] ^= m;
+        self.round();
+        self.round();
+        self.v[0] ^= m;
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        self.len += data.len();
+
+        for &byte in data {
+            self.tail |= u64::from(byte) << (8 * self.ntail);
+            self.ntail += 1;
+
+            if self.ntail == 8 {
+                let m = self.tail;
+                self.compress(m);
+                self.tail = 0;
+                self.ntail = 0;
+            }
+        }
+    }
+
+    fn finish(mut self) -> u64 {
+        let m = self.tail | ((self.len as u64 & 0xff) << 56);
+        self.compress(m);
+
+        self.v[2] ^= 0xff;
+
+        for _ in 0..4 {
+            self.round();
+        }
+
+        self.v[0] ^ self.v[1] ^ self.v[2] ^ self.v[3]
+    }
+}
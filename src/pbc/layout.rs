@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::{cmp, hash};
+
+/// Describes where entries end up in the output of `BatchCode::encode`. It records how many rows
+/// each bucket has and which row each key occupies. Codes place entries in an order derived from
+/// key hashes (not from the order of the collection). As a result, `BatchCode::layout` can
+/// compute the layout from the set of keys in the collection alone, and a client that knows
+/// those keys can locate entries without seeing the encoded collection.
+///
+/// Buckets are grouped so that all buckets in a group share rows. Examples of groups are the
+/// replicas of a logical bucket, or the sub-buckets that a subcube code XORs together. Reading
+/// a key means reading the same row of every bucket in the group that the schedule selects.
+#[derive(Debug, Clone)]
+pub struct Layout<K> {
+    groups: Vec<usize>,                    // bucket -> group
+    lens: Vec<usize>,                      // bucket -> number of rows
+    rows: HashMap<K, Vec<(usize, usize)>>, // key -> (group, row) for each group holding the key
+}
+
+impl<K> Layout<K>
+where
+    K: cmp::Eq + hash::Hash,
+{
+    // Creates an empty layout. groups[b] is the group of bucket b.
+    pub(super) fn new(groups: Vec<usize>) -> Layout<K> {
+        Layout {
+            lens: vec![0; groups.len()],
+            groups,
+            rows: HashMap::new(),
+        }
+    }
+
+    // Appends the key to the given bucket. The key then occupies that row across the bucket's group.
+    pub(super) fn place(&mut self, key: K, bucket: usize) {
+        let row = self.lens[bucket];
+        self.lens[bucket] += 1;
+
+        self.rows
+            .entry(key)
+            .or_default()
+            .push((self.groups[bucket], row));
+    }
+
+    // Sets the number of rows of a bucket whose entries are not placed individually (e.g., a
+    // replica or a parity sub-bucket).
+    pub(super) fn set_len(&mut self, bucket: usize, len: usize) {
+        self.lens[bucket] = len;
+    }
+
+    pub fn num_buckets(&self) -> usize {
+        self.lens.len()
+    }
+
+    /// Number of rows of the given bucket (the length of that bucket in the encoded collection).
+    pub fn bucket_len(&self, bucket: usize) -> usize {
+        self.lens[bucket]
+    }
+
+    /// Whether the key is part of the collection this layout was computed from.
+    pub fn contains(&self, key: &K) -> bool {
+        self.rows.contains_key(key)
+    }
+
+    /// Returns the row to read from `bucket` when retrieving `key`. Returns None if the bucket's
+    /// group does not hold the key. It also returns None if the bucket has fewer rows than that:
+    /// a sub-bucket shorter than the others in its group contributes zero when decoding, so it
+    /// is simply not read.
+    pub fn locate(&self, key: &K, bucket: usize) -> Option<usize> {
+        let group = self.groups[bucket];
+        let &(_, row) = self.rows.get(key)?.iter().find(|(g, _)| *g == group)?;
+
+        if row < self.lens[bucket] {
+            Some(row)
+        } else {
+            None
+        }
+    }
+
+    /// Turns a schedule (as returned by `BatchCode::get_schedule`) into (bucket, row) positions,
+    /// leaving out buckets in which the key's row does not exist (see `locate`).
+    pub fn index_schedule(
+        &self,
+        schedule: HashMap<K, Vec<usize>>,
+    ) -> HashMap<K, Vec<(usize, usize)>> {
+        schedule
+            .into_iter()
+            .map(|(key, buckets)| {
+                let positions = buckets
+                    .into_iter()
+                    .filter_map(|bucket| Some((bucket, self.locate(&key, bucket)?)))
+                    .collect();
+
+                (key, positions)
+            })
+            .collect()
+    }
+}
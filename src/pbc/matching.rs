@@ -0,0 +1,223 @@
+use super::{ScheduleError, Strategy};
+use std::collections::{HashMap, VecDeque};
+use std::{cmp, hash};
+
+const UNREACHED: usize = usize::MAX;
+
+/// Computes a maximum matching between keys and buckets using Hopcroft-Karp.
+/// candidates[i] lists the buckets (in 0..num_buckets) from which key i can be retrieved.
+/// Returns, for each key, the bucket it is matched to, or None if the key could not be matched.
+/// No two keys are matched to the same bucket, and as many keys as possible are matched.
+pub fn max_matching(candidates: &[Vec<usize>], num_buckets: usize) -> Vec<Option<usize>> {
+    let num_keys = candidates.len();
+
+    let mut key_match: Vec<Option<usize>> = vec![None; num_keys];
+    let mut bucket_match: Vec<Option<usize>> = vec![None; num_buckets];
+    let mut dist = vec![UNREACHED; num_keys];
+
+    loop {
+        // BFS: layer keys by the length of the alternating path from an unmatched key.
+        let mut queue = VecDeque::new();
+
+        for key in 0..num_keys {
+            if key_match[key].is_none() {
+                dist[key] = 0;
+                queue.push_back(key);
+            } else {
+                dist[key] = UNREACHED;
+            }
+        }
+
+        let mut augmentable = false;
+
+        while let Some(key) = queue.pop_front() {
+            for &bucket in &candidates[key] {
+                match bucket_match[bucket] {
+                    None => augmentable = true,
+                    Some(other) if dist[other] == UNREACHED => {
+                        dist[other] = dist[key] + 1;
+                        queue.push_back(other);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        if !augmentable {
+            break;
+        }
+
+        // DFS (iterative): follow the layers from each unmatched key until we reach an
+        // unmatched bucket, then flip the matching along the path.
+        let mut next_edge = vec![0; num_keys];
+
+        for root in 0..num_keys {
+            if key_match[root].is_some() {
+                continue;
+            }
+
+            let mut path = vec![root];
+
+            while let Some(&key) = path.last() {
+                if next_edge[key] == candidates[key].len() {
+                    // Dead end. Remove key from the layered graph for the rest of this phase.
+                    dist[key] = UNREACHED;
+                    path.pop();
+                    continue;
+                }
+
+                let bucket = candidates[key][next_edge[key]];
+                next_edge[key] += 1;
+
+                match bucket_match[bucket] {
+                    None => {
+                        // Every key in the path takes the bucket it was last exploring
+                        for &k in &path {
+                            let b = candidates[k][next_edge[k] - 1];
+                            key_match[k] = Some(b);
+                            bucket_match[b] = Some(k);
+                        }
+
+                        break;
+                    }
+                    Some(other) if dist[other] == dist[key] + 1 => path.push(other),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    key_match
+}
+
+/// Cuckoo insertion without recursion or randomness: inserts keys one at a time, and when all of
+/// a key's candidate buckets are taken, relocates keys along the shortest eviction path to an
+/// empty bucket (found with a BFS over the key to bucket graph).
+/// Takes the same input and returns the same output as max_matching. Since a key is only left
+/// out when no eviction path exists, this also places every key whenever a schedule exists.
+pub fn bfs_insertion(candidates: &[Vec<usize>], num_buckets: usize) -> Vec<Option<usize>> {
+    let mut key_match: Vec<Option<usize>> = vec![None; candidates.len()];
+    let mut bucket_match: Vec<Option<usize>> = vec![None; num_buckets];
+
+    // parent[b] is the bucket whose occupant would move into b (UNREACHED for the new key's
+    // own candidates). Only meaningful for visited buckets.
+    let mut parent = vec![UNREACHED; num_buckets];
+    let mut visited = vec![false; num_buckets];
+    let mut touched = Vec::new(); // buckets to unmark before the next insertion
+    let mut queue = VecDeque::new();
+
+    for (key, choices) in candidates.iter().enumerate() {
+        for &bucket in &touched {
+            visited[bucket] = false;
+        }
+
+        touched.clear();
+        queue.clear();
+
+        for &bucket in choices {
+            if !visited[bucket] {
+                visited[bucket] = true;
+                parent[bucket] = UNREACHED;
+                touched.push(bucket);
+                queue.push_back(bucket);
+            }
+        }
+
+        let mut free = None;
+
+        while let Some(bucket) = queue.pop_front() {
+            let occupant = match bucket_match[bucket] {
+                None => {
+                    free = Some(bucket);
+                    break;
+                }
+                Some(occupant) => occupant,
+            };
+
+            // The occupant could be evicted to any of its other buckets
+            for &next in &candidates[occupant] {
+                if !visited[next] {
+                    visited[next] = true;
+                    parent[next] = bucket;
+                    touched.push(next);
+                    queue.push_back(next);
+                }
+            }
+        }
+
+        let mut bucket = match free {
+            Some(bucket) => bucket,
+            None => continue, // no eviction path: the key is left out
+        };
+
+        // Walk the path back, moving each occupant one step towards the free bucket
+        while parent[bucket] != UNREACHED {
+            let previous = parent[bucket];
+            let moved = bucket_match[previous].unwrap();
+
+            bucket_match[bucket] = Some(moved);
+            key_match[moved] = Some(bucket);
+            bucket = previous;
+        }
+
+        bucket_match[bucket] = Some(key);
+        key_match[key] = Some(bucket);
+    }
+
+    key_match
+}
+
+/// Gives each key the first of its candidate buckets that is still free (in order). This is the
+/// heuristic used by ChoicesCode and ShardingCode, and may leave keys out even if a schedule exists.
+pub fn first_fit(candidates: &[Vec<usize>], num_buckets: usize) -> Vec<Option<usize>> {
+    let mut used = vec![false; num_buckets];
+
+    candidates
+        .iter()
+        .map(|choices| {
+            let bucket = choices.iter().find(|&&bucket| !used[bucket])?;
+            used[*bucket] = true;
+            Some(*bucket)
+        })
+        .collect()
+}
+
+/// Assigns keys to buckets with the given strategy. The heuristic is first fit; CuckooCode runs
+/// its own random-walk heuristic instead.
+pub fn assign(
+    strategy: Strategy,
+    candidates: &[Vec<usize>],
+    num_buckets: usize,
+) -> Vec<Option<usize>> {
+    match strategy {
+        Strategy::Matching => max_matching(candidates, num_buckets),
+        Strategy::Bfs => bfs_insertion(candidates, num_buckets),
+        Strategy::Heuristic => first_fit(candidates, num_buckets),
+    }
+}
+
+/// Turns an assignment (as returned by assign) into a schedule. Keys that were not assigned are
+/// added to leftover if it is given. Otherwise, returns an error naming the first such key, in
+/// which case no schedule for the whole batch exists (unless the strategy is the heuristic).
+pub fn schedule<K>(
+    keys: &[K],
+    assignment: Vec<Option<usize>>,
+    mut leftover: Option<&mut Vec<K>>,
+) -> Result<HashMap<K, Vec<usize>>, ScheduleError>
+where
+    K: Clone + cmp::Eq + hash::Hash,
+{
+    let mut schedule = HashMap::with_capacity(keys.len());
+
+    for (index, (key, bucket)) in keys.iter().zip(assignment).enumerate() {
+        match (bucket, leftover.as_mut()) {
+            (Some(bucket), _) => {
+                schedule.insert(key.clone(), vec![bucket]);
+            }
+            (None, Some(leftover)) => leftover.push(key.clone()),
+            (None, None) => return Err(ScheduleError::Unschedulable { index }),
+        }
+    }
+
+    Ok(schedule)
+}
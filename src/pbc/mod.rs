@@ -1,26 +1,29 @@
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
-use num::bigint::BigUint;
-use num::cast::ToPrimitive;
-use num::Integer;
+use rand::Rng;
 use serde::Serialize;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ops::{BitXor, BitXorAssign};
-use std::{cmp, hash};
+use std::{cmp, error, fmt, hash};
+
+use self::hasher::BucketHasher;
+use self::layout::Layout;
+use self::view::EncodedView;
 
 #[derive(Debug, Clone, Serialize, PartialEq, Eq)]
 pub struct Tuple<K, V>
 where
-    K: BitXor + BitXorAssign + Clone + Serialize,
-    V: BitXor + BitXorAssign + Clone + Serialize,
+    K: Clone + Serialize,
+    V: Clone + Serialize,
 {
     pub t: (K, V),
 }
 
+// Entries of XOR-based codes (see XorCode) are combined by XORing both the key and the value
 impl<K, V> BitXor for Tuple<K, V>
 where
-    K: BitXor<Output = K> + BitXorAssign + Clone + Serialize,
-    V: BitXor<Output = V> + BitXorAssign + Clone + Serialize,
+    K: Xor + Clone + Serialize,
+    V: Xor + Clone + Serialize,
 {
     type Output = Tuple<K, V>;
 
@@ -33,8 +36,8 @@ where
 
 impl<K, V> BitXorAssign for Tuple<K, V>
 where
-    K: BitXor<Output = K> + BitXorAssign + Clone + Serialize,
-    V: BitXor<Output = V> + BitXorAssign + Clone + Serialize,
+    K: Xor + Clone + Serialize,
+    V: Xor + Clone + Serialize,
 {
     fn bitxor_assign(&mut self, other: Tuple<K, V>) {
         self.t.0 ^= other.t.0;
@@ -42,31 +45,366 @@ where
     }
 }
 
+/// Reasons why a batch code may fail to encode a collection, schedule a batch of keys,
+/// or decode a set of results. Positions refer to the index of the offending key in the
+/// slice of keys passed to `get_schedule`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScheduleError {
+    /// The batch has more keys than the code was built for.
+    TooManyKeys { requested: usize, k: usize },
+    /// The key is not part of the collection that the layout was computed from.
+    UnknownKey { index: usize },
+    /// An entry with this key is already in the encoded collection.
+    KeyExists,
+    /// No entry with this key is in the encoded collection.
+    KeyNotFound,
+    /// The bucket has more entries than the length it should be padded to.
+    BucketOverflow { bucket: usize, len: usize },
+    /// The key also appears earlier in the batch.
+    DuplicateKey { index: usize },
+    /// Cuckoo insertion gave up on a key after making this many evictions for the batch.
+    EvictionsExhausted { evictions: usize },
+    /// More keys were left without a bucket than there are stash buckets.
+    StashOverflow { size: usize },
+    /// Every replica of the key's buckets (up to the retry bound) is already in use.
+    RetryBoundExhausted { index: usize, bound: usize },
+    /// No assignment of the batch to distinct buckets exists; this key could not be placed.
+    Unschedulable { index: usize },
+    /// Every recovery set of the key overlaps with buckets already in use.
+    RecoverySetsExhausted { index: usize },
+    /// A key could not be serialized in order to be hashed.
+    Serialization(String),
+    /// Decode was given a number of results that does not match the code.
+    InvalidResults { expected: usize, actual: usize },
+    /// Decode was given a number of results outside the range the code accepts (e.g., PungCode
+    /// takes 1 to 4, depending on the recovery set and on which sub-buckets have the row).
+    ResultsOutOfRange {
+        min: usize,
+        max: usize,
+        actual: usize,
+    },
+    /// The encoding has Parity buckets, which need keys and values that can be XORed (see
+    /// XorCode).
+    RequiresXor,
+}
+
+impl fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScheduleError::TooManyKeys { requested, k } => {
+                write!(
+                    f,
+                    "batch has {requested} keys but the code supports at most {k}"
+                )
+            }
+            ScheduleError::UnknownKey { index } => {
+                write!(f, "key {index} is not in the encoded collection")
+            }
+            ScheduleError::KeyExists => write!(f, "key is already in the encoded collection"),
+            ScheduleError::KeyNotFound => write!(f, "key is not in the encoded collection"),
+            ScheduleError::BucketOverflow { bucket, len } => {
+                write!(f, "bucket {bucket} has more than {len} entries")
+            }
+            ScheduleError::DuplicateKey { index } => {
+                write!(f, "key {index} appears more than once in the batch")
+            }
+            ScheduleError::EvictionsExhausted { evictions } => {
+                write!(f, "cuckoo insertion failed after {evictions} evictions")
+            }
+            ScheduleError::StashOverflow { size } => {
+                write!(
+                    f,
+                    "more keys could not be placed than fit in the stash of {size}"
+                )
+            }
+            ScheduleError::RetryBoundExhausted { index, bound } => {
+                write!(f, "key {index} found all {bound} replicas in use")
+            }
+            ScheduleError::Unschedulable { index } => {
+                write!(f, "no schedule exists: key {index} could not be placed")
+            }
+            ScheduleError::RecoverySetsExhausted { index } => {
+                write!(f, "key {index} has no recovery set with unused buckets")
+            }
+            ScheduleError::Serialization(e) => write!(f, "could not serialize key: {e}"),
+            ScheduleError::InvalidResults { expected, actual } => {
+                write!(f, "expected {expected} results to decode but got {actual}")
+            }
+            ScheduleError::ResultsOutOfRange { min, max, actual } => {
+                write!(
+                    f,
+                    "expected {min} to {max} results to decode but got {actual}"
+                )
+            }
+            ScheduleError::RequiresXor => {
+                write!(f, "encoding has parity buckets, which need XOR")
+            }
+        }
+    }
+}
+
+impl error::Error for ScheduleError {}
+
+/// Largest number of buckets (or sub-buckets) a code may have. Every bucket is a separate PIR
+/// database, so codes with more than this are not practical, and rejecting them keeps
+/// parameters received from elsewhere (e.g., in a descriptor) from exhausting memory.
+pub const MAX_BUCKETS: usize = 1 << 20;
+
+/// Reasons why a code cannot be constructed with the requested parameters.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParamError {
+    /// A parameter is out of range (e.g., k = 0 or a target outside (0, 1)).
+    InvalidParam { name: &'static str, reason: String },
+    /// The code would need this many replicas of each of its k logical buckets. Since each
+    /// replica holds about n / k entries, replicas >= k costs at least as much storage and
+    /// server work as a ReplicationCode, which never fails.
+    WorseThanReplication { replicas: usize, k: usize },
+}
+
+impl fmt::Display for ParamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParamError::InvalidParam { name, reason } => write!(f, "invalid {name}: {reason}"),
+            ParamError::WorseThanReplication { replicas, k } => write!(
+                f,
+                "{replicas} replicas of {k} buckets is no better than replication"
+            ),
+        }
+    }
+}
+
+impl error::Error for ParamError {}
+
+// Smallest number of replicas b of k logical buckets such that k * P[Bin(k, p) > b] is at most
+// target, i.e., such that (by a union bound) no logical bucket is hit by more than b of the k
+// keys of a batch, except with probability target.
+fn replicas_for(k: usize, p: f64, target: f64) -> Result<usize, ParamError> {
+    if !(target > 0.0 && target < 1.0) {
+        return Err(ParamError::InvalidParam {
+            name: "target",
+            reason: format!("{target} is not in (0, 1)"),
+        });
+    }
+
+    // The tail vanishes at b = k, so the search always ends
+    let replicas = (1..=k)
+        .find(|&b| k as f64 * padding::binomial_tail(k, p, b + 1) <= target)
+        .unwrap_or(k);
+
+    check_replicas(k, replicas)?;
+    Ok(replicas)
+}
+
+// Checks that replicas of k logical buckets is a sensible configuration
+fn check_replicas(k: usize, replicas: usize) -> Result<(), ParamError> {
+    if k == 0 {
+        return Err(ParamError::InvalidParam {
+            name: "k",
+            reason: "batches must have at least one key".to_string(),
+        });
+    }
+
+    if replicas == 0 {
+        return Err(ParamError::InvalidParam {
+            name: "replicas",
+            reason: "every logical bucket needs at least one replica".to_string(),
+        });
+    }
+
+    if replicas >= k {
+        return Err(ParamError::WorseThanReplication { replicas, k });
+    }
+
+    if k.checked_mul(replicas)
+        .is_none_or(|total| total > MAX_BUCKETS)
+    {
+        return Err(ParamError::InvalidParam {
+            name: "k",
+            reason: format!("{k} * {replicas} replicas is more than {MAX_BUCKETS} buckets"),
+        });
+    }
+
+    Ok(())
+}
+
+/// Types that can be XORed, as required of the keys and values of an XorCode.
+pub trait Xor: BitXor<Output = Self> + BitXorAssign + Sized {}
+
+impl<T: BitXor<Output = T> + BitXorAssign> Xor for T {}
+
+/// Codes that decode by XORing several entries together (PungCode and SubcubeCode), and
+/// therefore need keys and values that can be XORed. Systematic codes, which return an entry
+/// as is (ReplicationCode, ShardingCode, ChoicesCode and CuckooCode), only implement BatchCode,
+/// and work with any key that can be serialized and hashed (e.g., String or Vec<u8>).
+pub trait XorCode<K, V>: BatchCode<K, V>
+where
+    K: Clone + Serialize + Xor + cmp::Eq + hash::Hash,
+    V: Clone + Serialize + Xor,
+{
+}
+
+/// Result of `BatchCode::get_partial_schedule`: a schedule for some of the keys, and the keys
+/// that are not in it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartialSchedule<K>
+where
+    K: cmp::Eq + hash::Hash,
+{
+    pub schedule: HashMap<K, Vec<usize>>,
+    pub leftover: Vec<K>,
+}
+
+/// Algorithm used by codes with several candidate buckets per key (CuckooCode, ChoicesCode
+/// and ShardingCode) to assign each key in a batch to a distinct bucket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Strategy {
+    /// The code's own heuristic: random-walk insertion for CuckooCode and first fit for
+    /// ChoicesCode and ShardingCode. These are fast but may fail even if a schedule exists.
+    #[default]
+    Heuristic,
+    /// Maximum bipartite matching of keys to buckets, which finds a schedule whenever one exists.
+    Matching,
+    /// Iterative cuckoo insertion that relocates keys along the shortest eviction path (found
+    /// with a BFS). Uses no recursion or randomness, and finds a schedule whenever one exists.
+    Bfs,
+}
+
 pub trait BatchCode<K, V>
 where
-    K: Clone + Serialize + BitXor<Output = K> + BitXorAssign + cmp::Eq + hash::Hash,
-    V: Clone + Serialize + BitXor<Output = V> + BitXorAssign,
+    K: Clone + Serialize + cmp::Eq + hash::Hash,
+    V: Clone + Serialize,
 {
     /// Encodes a collection into m collections such that k items can be
     /// retrieved by querying each of the m collections at most once (with high prob).
     /// This is typically called by the server.
-    fn encode(&self, collection: &[Tuple<K, V>]) -> Vec<Vec<Tuple<K, V>>>;
+    ///
+    /// The default implementation materializes the output of `encode_view`, which must not have
+    /// Parity buckets: XOR-based codes (see XorCode) override this method.
+    fn encode(&self, collection: &[Tuple<K, V>]) -> Result<Vec<Vec<Tuple<K, V>>>, ScheduleError> {
+        let view = self.encode_view(collection)?;
+
+        Ok((0..view.num_buckets())
+            .map(|bucket| {
+                view.materialize_systematic(bucket, collection)
+                    .expect("Codes with Parity buckets must override encode")
+            })
+            .collect())
+    }
+
+    /// Same as `encode`, but each bucket refers to entries by their position in the collection
+    /// instead of holding copies of them (see `EncodedView`).
+    fn encode_view(&self, collection: &[Tuple<K, V>]) -> Result<EncodedView, ScheduleError>;
 
     /// This function takes as input a set of keys and returns a possible schedule (i.e., which
-    /// collection or collections to get each key from), or None if no such schedule can be found.
-    /// Note that this does not mean that the key exists in the collections. It only means that
-    /// if the key were to exist, it would be found in those collections.
+    /// collection or collections to get each key from), or an error if no such schedule can be
+    /// found. Note that this does not mean that the key exists in the collections. It only means
+    /// that if the key were to exist, it would be found in those collections.
     /// This function is typically called by the client.
     ///
-    /// WARNING: Assumes unique keys (this is not fundamental and does not change the performance,
-    /// but we're using a hashmap as output so duplicate keys will be overwritten...).
-    fn get_schedule(&self, keys: &[K]) -> Option<HashMap<K, Vec<usize>>>;
+    /// Keys must be unique: a batch with repeated keys is rejected with `DuplicateKey`.
+    fn get_schedule(&self, keys: &[K]) -> Result<HashMap<K, Vec<usize>>, ScheduleError>;
+
+    /// Same as `get_schedule`, but any randomness used by the scheduling algorithm is drawn
+    /// from the given rng. Passing a seeded rng (e.g., ChaChaRng) makes the schedule
+    /// reproducible. Codes whose scheduling is deterministic ignore the rng.
+    fn get_schedule_with_rng(
+        &self,
+        keys: &[K],
+        _rng: &mut dyn Rng,
+    ) -> Result<HashMap<K, Vec<usize>>, ScheduleError> {
+        self.get_schedule(keys)
+    }
+
+    /// Same as `get_schedule_with_rng`, but also adds to `retries` the number of times the
+    /// scheduling heuristic had to give up on an option: evictions for CuckooCode, occupied
+    /// replicas for ShardingCode and ChoicesCode, and busy recovery sets for PungCode. The count
+    /// is updated even if scheduling fails. Codes (and strategies) that never retry leave it
+    /// unchanged. This is meant for simulations (see the simulation module).
+    fn get_schedule_with_retries(
+        &self,
+        keys: &[K],
+        rng: &mut dyn Rng,
+        _retries: &mut usize,
+    ) -> Result<HashMap<K, Vec<usize>>, ScheduleError> {
+        self.get_schedule_with_rng(keys, rng)
+    }
+
+    /// Same as `get_schedule`, but instead of failing when some keys cannot be placed, it
+    /// schedules as many keys as it can and returns the rest as leftovers (in no particular
+    /// order), to be fetched in a later batch. Batches may have more keys than the code
+    /// supports, in which case the extra keys are left over too. Batches with repeated keys are
+    /// still rejected with `DuplicateKey`.
+    ///
+    /// The default implementation adds keys one at a time, keeping each one that
+    /// `get_schedule` can still schedule along with the ones already kept.
+    fn get_partial_schedule(&self, keys: &[K]) -> Result<PartialSchedule<K>, ScheduleError> {
+        check_batch(keys, usize::MAX)?;
+
+        let mut schedule = HashMap::new();
+        let mut kept = Vec::with_capacity(keys.len());
+        let mut leftover = Vec::new();
+
+        for key in keys {
+            kept.push(key.clone());
+
+            match self.get_schedule(&kept) {
+                Ok(new_schedule) => schedule = new_schedule,
+                Err(e @ ScheduleError::Serialization(_)) => return Err(e),
+                Err(_) => leftover.extend(kept.pop()),
+            }
+        }
+
+        Ok(PartialSchedule { schedule, leftover })
+    }
+
+    /// Same as `get_partial_schedule`, but any randomness used by the scheduling algorithm is
+    /// drawn from the given rng (see `get_schedule_with_rng`). Codes whose scheduling is
+    /// deterministic ignore the rng.
+    fn get_partial_schedule_with_rng(
+        &self,
+        keys: &[K],
+        _rng: &mut dyn Rng,
+    ) -> Result<PartialSchedule<K>, ScheduleError> {
+        self.get_partial_schedule(keys)
+    }
 
     /// This function takes a vector of tuples and combines them together into the
     /// desired tuple. In many cases, the vector contains only one entry in which case it is
     /// the result (K, V). In other cases, XORing or some other operation is performed.
     /// This function is typically called by the client
-    fn decode(&self, results: &[Tuple<K, V>]) -> Tuple<K, V>;
+    fn decode(&self, results: &[Tuple<K, V>]) -> Result<Tuple<K, V>, ScheduleError>;
+
+    /// Computes the layout of the collections returned by `encode`, given only the keys of the
+    /// collection (in any order). This can be called by the client, provided it knows which keys
+    /// the server holds.
+    fn layout(&self, collection_keys: &[K]) -> Result<Layout<K>, ScheduleError>;
+
+    /// Number of entries that no bucket exceeds, except with probability 2^-security, when
+    /// encoding a collection of n entries. This is meant as a length to pad buckets to (see
+    /// padding::pad) that depends only on n and the code parameters.
+    fn bucket_bound(&self, n: usize, security: u32) -> usize;
+
+    /// Upper bound on the probability that `get_schedule` fails on a batch of k distinct keys,
+    /// assuming the hash function behaves like a random function. None if the code (or its
+    /// strategy) has no known bound, in which case the failure probability can be estimated
+    /// with `simulation::estimate_failure`.
+    fn failure_bound(&self) -> Option<f64>;
+
+    /// Same as `get_schedule`, but gives the position of each key as (bucket, row) pairs, which
+    /// are the indices to retrieve from each bucket. Buckets in which the key's row does not
+    /// exist (see `Layout::locate`) are left out, and the results of the remaining ones are
+    /// passed to `decode`. Keys that are not in the layout are rejected with `UnknownKey`.
+    fn get_indexed_schedule(
+        &self,
+        keys: &[K],
+        layout: &Layout<K>,
+    ) -> Result<HashMap<K, Vec<(usize, usize)>>, ScheduleError> {
+        if let Some(index) = keys.iter().position(|key| !layout.contains(key)) {
+            return Err(ScheduleError::UnknownKey { index });
+        }
+
+        Ok(layout.index_schedule(self.get_schedule(keys)?))
+    }
 }
 
 #[macro_export]
@@ -80,29 +418,126 @@ macro_rules! retry_bound {
     };
 }
 
-// utility function that computes a hash of the key and mods it by the given modulus
-fn hash_and_mod(id: usize, nonce: usize, data: &[u8], modulus: usize) -> usize {
-    let mut digest = Sha256::new();
-    digest.input_str(&format!("{id}{nonce}"));
+// utility function that checks that a batch of keys fits in the code and has no repeats
+fn check_batch<K>(keys: &[K], k: usize) -> Result<(), ScheduleError>
+where
+    K: cmp::Eq + hash::Hash,
+{
+    if keys.len() > k {
+        return Err(ScheduleError::TooManyKeys {
+            requested: keys.len(),
+            k,
+        });
+    }
+
+    let mut seen = HashSet::with_capacity(keys.len());
 
-    // hash the key and get the result
-    digest.input(data);
+    for (index, key) in keys.iter().enumerate() {
+        if !seen.insert(key) {
+            return Err(ScheduleError::DuplicateKey { index });
+        }
+    }
+
+    Ok(())
+}
+
+// utility function for get_partial_schedule that checks a batch of any size for repeats and
+// splits it into the first k keys (to be scheduled) and the rest (left over)
+fn split_batch<K>(keys: &[K], k: usize) -> Result<(&[K], Vec<K>), ScheduleError>
+where
+    K: Clone + cmp::Eq + hash::Hash,
+{
+    check_batch(keys, usize::MAX)?;
+    let (batch, rest) = keys.split_at(cmp::min(k, keys.len()));
+    Ok((batch, rest.to_vec()))
+}
+
+// utility function that returns the binary representation of a key
+fn key_bytes<K: Serialize>(key: &K) -> Result<Vec<u8>, ScheduleError> {
+    bincode::serialize(key).map_err(|e| ScheduleError::Serialization(e.to_string()))
+}
+
+// utility function that sorts keys by the SHA-256 digest of their binary representation. It
+// returns the position of each key and its binary representation. Codes place entries in this
+// order, so that the layout of an encoded collection only depends on the set of keys.
+fn slot_order<'a, K, I>(keys: I) -> Result<Vec<(usize, Vec<u8>)>, ScheduleError>
+where
+    K: Serialize + 'a,
+    I: Iterator<Item = &'a K>,
+{
+    let mut order = Vec::new();
+
+    for (i, key) in keys.enumerate() {
+        order.push((slot_key(key)?, i));
+    }
+
+    order.sort_unstable();
+    Ok(order
+        .into_iter()
+        .map(|((_, bytes), i)| (i, bytes))
+        .collect())
+}
+
+// utility function that returns what slot_order sorts a key by: the SHA-256 digest of its binary
+// representation, and the representation itself
+fn slot_key<K: Serialize>(key: &K) -> Result<(Vec<u8>, Vec<u8>), ScheduleError> {
+    let bytes = key_bytes(key)?;
+
+    let mut digest = Sha256::new();
+    digest.input(&bytes);
     let mut hash: Vec<u8> = vec![0; digest.output_bytes()];
     digest.result(&mut hash);
 
-    // convert hash into a big integer and perform modulo k
-    let int_value = BigUint::from_bytes_le(&hash);
-    int_value
-        .mod_floor(&BigUint::from(modulus))
-        .to_usize()
-        .unwrap()
+    Ok((hash, bytes))
+}
+
+// utility function that checks that decode received the expected number of results
+fn check_results(expected: usize, actual: usize) -> Result<(), ScheduleError> {
+    if expected != actual {
+        return Err(ScheduleError::InvalidResults { expected, actual });
+    }
+
+    Ok(())
+}
+
+// utility function that maps a key to d distinct buckets (out of modulus)
+fn bucket_choices(hasher: &dyn BucketHasher, bytes: &[u8], d: usize, modulus: usize) -> Vec<usize> {
+    let mut choices = Vec::with_capacity(d);
+
+    for id in 0..d {
+        let mut nonce = 0;
+
+        // The following computes bucket = sha_id(key) % modulus
+        let mut bucket = hasher.hash_and_mod(id, nonce, bytes, modulus);
+
+        // Ensure each key maps to *different* buckets
+        while choices.contains(&bucket) {
+            nonce += 1;
+            bucket = hasher.hash_and_mod(id, nonce, bytes, modulus);
+        }
+
+        choices.push(bucket);
+    }
+
+    choices
 }
 
+pub mod block;
 pub mod choices;
 pub mod cuckoo;
+pub mod database;
+pub mod descriptor;
+pub mod hasher;
+pub mod layout;
+pub mod matching;
+pub mod padding;
 pub mod pung;
 pub mod replication;
 pub mod sharding;
+pub mod simulation;
+pub mod stats;
+pub mod subcube;
+pub mod view;
 
 #[cfg(test)]
 mod test;
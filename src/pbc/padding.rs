@@ -0,0 +1,102 @@
+use super::ScheduleError;
+use std::cmp;
+
+/// Length to which `pad` extends every bucket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Padding {
+    /// The length of the longest bucket.
+    Max,
+    /// A fixed length, typically `BatchCode::bucket_bound`, which (unlike Max) does not reveal
+    /// anything about the collection beyond its size.
+    Fixed(usize),
+}
+
+/// Appends copies of `dummy` to every bucket (as returned by `BatchCode::encode`) until all of
+/// them have the same length, and returns that length. All buckets can then be served with a
+/// single set of PIR parameters (see `MultiPirClient::new_uniform`).
+///
+/// Dummy entries go after the real ones, so the rows given by `BatchCode::layout` are
+/// unchanged. `dummy` should have a key that is not in the collection.
+pub fn pad<T: Clone>(
+    buckets: &mut [Vec<T>],
+    padding: Padding,
+    dummy: &T,
+) -> Result<usize, ScheduleError> {
+    let len = match padding {
+        Padding::Max => buckets.iter().map(|b| b.len()).max().unwrap_or(0),
+        Padding::Fixed(len) => len,
+    };
+
+    if let Some(bucket) = buckets.iter().position(|b| b.len() > len) {
+        return Err(ScheduleError::BucketOverflow { bucket, len });
+    }
+
+    for bucket in buckets.iter_mut() {
+        bucket.resize(len, dummy.clone());
+    }
+
+    Ok(len)
+}
+
+/// Bound on the load of the fullest of `bins` bins when `balls` balls land in bins chosen
+/// uniformly at random, which is exceeded with probability at most 2^-security. A code that
+/// places each entry in d distinct buckets counts d balls per entry: the load of each bucket is
+/// still a sum of independent indicators (one per entry) with the same mean.
+///
+/// This uses the Chernoff bound Pr[X >= (1 + t) * mu] <= exp(-t^2 * mu / (2 + t)) for each bin
+/// and a union bound over the bins.
+pub fn load_bound(balls: usize, bins: usize, security: u32) -> usize {
+    assert!(bins > 0, "There must be at least one bin");
+
+    if balls == 0 {
+        return 0;
+    }
+
+    let mu = balls as f64 / bins as f64;
+    let target = (bins as f64).ln() + f64::from(security) * 2f64.ln();
+
+    // Smallest t with t^2 * mu / (2 + t) >= target
+    let t = (target + (target * target + 8.0 * mu * target).sqrt()) / (2.0 * mu);
+
+    cmp::min(balls, ((1.0 + t) * mu).ceil() as usize)
+}
+
+/// Probability that a binomial random variable with n trials and success probability p is at
+/// least j, computed exactly (in log space, so it does not overflow for large n).
+pub fn binomial_tail(n: usize, p: f64, j: usize) -> f64 {
+    assert!((0.0..=1.0).contains(&p), "p must be a probability");
+
+    if j == 0 {
+        return 1.0;
+    }
+
+    if j > n || p == 0.0 {
+        return 0.0;
+    }
+
+    if p == 1.0 {
+        return 1.0;
+    }
+
+    let ln_fact = ln_factorials(n);
+    let tail: f64 = (j..=n)
+        .map(|i| {
+            let ln_choose = ln_fact[n] - ln_fact[i] - ln_fact[n - i];
+            (ln_choose + i as f64 * p.ln() + (n - i) as f64 * (1.0 - p).ln()).exp()
+        })
+        .sum();
+
+    tail.min(1.0)
+}
+
+/// ln(i!) for i in 0..=n.
+pub(super) fn ln_factorials(n: usize) -> Vec<f64> {
+    let mut ln_fact = Vec::with_capacity(n + 1);
+    ln_fact.push(0.0);
+
+    for i in 1..=n {
+        ln_fact.push(ln_fact[i - 1] + (i as f64).ln());
+    }
+
+    ln_fact
+}
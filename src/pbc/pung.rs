@@ -1,226 +1,237 @@
-use super::BatchCode;
-use super::Tuple;
-use bincode::serialize;
+use super::hasher::{BucketHasher, HasherSpec};
+use super::layout::Layout;
+use super::subcube::Subcube;
+use super::view::EncodedView;
+use super::{BatchCode, PartialSchedule, ScheduleError, Tuple, Xor, XorCode, MAX_BUCKETS};
+use rand::Rng;
 use serde::Serialize;
 use std::collections::HashMap;
-use std::ops::{BitXor, BitXorAssign};
+use std::sync::Arc;
 use std::{cmp, hash};
 
-pub struct PungCode<T> {
+pub struct PungCode {
     k: usize,
-    labels: HashMap<T, Vec<usize>>,
+    subcube: Subcube, // the (n, 9/4*n, 4, 9)-subcube code applied to each bucket
+    hasher: Arc<dyn BucketHasher>,
 }
 
-impl<T> PungCode<T>
-where
-    T: Clone + Serialize + cmp::Eq + hash::Hash,
-{
-    pub fn new(k: usize) -> PungCode<T> {
+impl PungCode {
+    /// Panics if k <= 4 or the code has more than MAX_BUCKETS buckets (9 per bucket of k).
+    pub fn new(k: usize) -> PungCode {
         assert!(k > 4, "You are better off using a subcube batch code");
 
+        let subcube = Subcube::new(2, 2);
+        assert!(
+            k.checked_mul(subcube.num_buckets())
+                .is_some_and(|total| total <= MAX_BUCKETS),
+            "Too many buckets"
+        );
+
         PungCode {
             k,
-            labels: HashMap::new(),
+            subcube,
+            hasher: HasherSpec::default().build(),
         }
     }
 
-    pub fn set_labels(&mut self, labels: HashMap<T, Vec<usize>>) {
-        self.labels = labels;
+    /// Sets the hash function that places keys in buckets (SHA-256 by default, see
+    /// hasher::Sha256Hasher). The client and the server must use the same one.
+    pub fn set_hasher(&mut self, hasher: Arc<dyn BucketHasher>) {
+        self.hasher = hasher;
     }
-}
 
-fn encode_bucket<T>(mut bucket: Vec<T>) -> Vec<Vec<T>>
-where
-    T: Clone + BitXor<Output = T>,
-{
-    let mut len = bucket.len();
+    pub fn hasher(&self) -> &dyn BucketHasher {
+        self.hasher.as_ref()
+    }
 
-    // split bucket (which has all the tuples) in half
-    let mut bucket_2 = bucket.split_off((len + 1) / 2);
+    // This implements Pung's get schedule algorithm
+    // The candidate buckets and sub-buckets are computed from the key alone (as in encode),
+    // so this does not depend on the encoded collection.
+    // Recovery sets that are skipped because they overlap a used bucket are counted in retries.
+    fn schedule<K>(
+        &self,
+        keys: &[K],
+        retries: &mut usize,
+        mut leftover: Option<&mut Vec<K>>,
+    ) -> Result<HashMap<K, Vec<usize>>, ScheduleError>
+    where
+        K: Clone + Serialize + cmp::Eq + hash::Hash,
+    {
+        super::check_batch(keys, self.k)?;
 
-    len = bucket.len();
+        let mut schedule = HashMap::new();
+        let mut used = Vec::new();
 
-    // split bucket (which has half the tuples) in half again
-    let bucket_1 = bucket.split_off((len + 1) / 2);
+        for (index, key) in keys.iter().enumerate() {
+            let bytes = super::key_bytes(key)?;
+            let sub_bucket = self.subcube.data_bucket(self.hasher.as_ref(), &bytes);
 
-    len = bucket_2.len();
+            let mut bucket_choices: Vec<Vec<usize>> = Vec::new();
 
-    // split bucket 2 (which has the other half of tuples) in half
-    let bucket_3 = bucket_2.split_off((len + 1) / 2);
+            // Each of the key's 2 buckets has 4 recovery sets for its sub-bucket
+            for bucket in super::bucket_choices(self.hasher.as_ref(), &bytes, 2, self.k) {
+                let base = bucket * self.subcube.num_buckets();
 
-    // Now we have 4 buckets with 1/4 of the tuples in each.
-    let mut encodings = vec![bucket, bucket_1, bucket_2, bucket_3];
+                for set in self.subcube.recovery_sets(sub_bucket) {
+                    bucket_choices.push(set.iter().map(|b| base + b).collect());
+                }
+            }
 
-    // Encode (XOR) collections as follows
-    let plan = [(0, 1), (2, 3), (0, 2), (1, 3), (6, 7)];
+            let mut found = false;
 
-    for &(c1, c2) in &plan {
-        let mut bucket_i: Vec<T> = encodings[c1]
-            .iter()
-            .zip(&encodings[c2])
-            .map(|(a, b)| a.clone() ^ b.clone())
-            .collect();
+            // Find a collection that has not been used.
+            for mut bucket in bucket_choices.drain(..) {
+                if !used.iter().any(|e| bucket.contains(e)) {
+                    schedule.insert(key.clone(), bucket.clone());
+                    used.append(&mut bucket);
+                    found = true;
+                    break;
+                }
+
+                *retries += 1;
+            }
 
-        // Missing one of them due to odd number of tuples. Get it from bucket c1.
-        if bucket_i.len() != encodings[c1].len() {
-            bucket_i.push(encodings[c1][encodings.len() - 1].clone());
+            if !found {
+                match leftover.as_mut() {
+                    Some(leftover) => leftover.push(key.clone()),
+                    None => return Err(ScheduleError::RecoverySetsExhausted { index }),
+                }
+            }
         }
 
-        encodings.push(bucket_i);
+        // maps each index into a vector of 1 entry containing that index
+        // We do this only to meet the return type: each entry represents the
+        // set of indices that must be queried (in our case, our encoding is
+        // systematic so we don't need to have multiple indices per item).
+        Ok(schedule)
     }
+}
 
-    encodings
+impl<K, V> XorCode<K, V> for PungCode
+where
+    K: Clone + Serialize + Xor + cmp::Eq + hash::Hash,
+    V: Clone + Serialize + Xor,
+{
 }
 
-impl<K, V> BatchCode<K, V> for PungCode<K>
+impl<K, V> BatchCode<K, V> for PungCode
 where
-    K: Clone + Serialize + BitXor<Output = K> + BitXorAssign + cmp::Eq + hash::Hash,
-    V: Clone + Serialize + BitXor<Output = V> + BitXorAssign,
+    K: Clone + Serialize + Xor + cmp::Eq + hash::Hash,
+    V: Clone + Serialize + Xor,
 {
+    fn encode(&self, collection: &[Tuple<K, V>]) -> Result<Vec<Vec<Tuple<K, V>>>, ScheduleError> {
+        let view = self.encode_view(collection)?;
+
+        Ok((0..view.num_buckets())
+            .map(|bucket| view.materialize(bucket, collection))
+            .collect())
+    }
+
     // Encoding is placing each entry to 2 buckets (out of k).
     // Then encoding each of the k buckets with a (n, 9/4*n, 4, 9)-subcube batch code.
     // This creates a total of 9k buckets
-    fn encode(&self, collection: &[Tuple<K, V>]) -> Vec<Vec<Tuple<K, V>>> {
-        let mut buckets: Vec<Vec<Tuple<K, V>>> = Vec::with_capacity(self.k);
-
-        for _ in 0..self.k {
-            buckets.push(Vec::new());
-        }
+    // Both the buckets and the data sub-bucket within them are derived from the key's hash.
+    fn encode_view(&self, collection: &[Tuple<K, V>]) -> Result<EncodedView, ScheduleError> {
+        // buckets[i][j] is the j-th data sub-bucket of bucket i
+        let mut buckets: Vec<Vec<Vec<usize>>> =
+            vec![vec![Vec::new(); self.subcube.num_data()]; self.k];
 
-        for entry in collection {
-            // First get the binary representation of the key
-            let bytes = serialize(&entry.t.0).unwrap();
-
-            let mut bucket_choices = Vec::with_capacity(2);
+        for (i, bytes) in super::slot_order(collection.iter().map(|e| &e.t.0))? {
+            let sub_bucket = self.subcube.data_bucket(self.hasher.as_ref(), &bytes);
 
             // Map entry's key to 2 buckets (no repeats)
-            for id in 0..2 {
-                let mut nonce = 0;
-
-                // The following computes bucket = sha_id(key) % k;
-                let mut bucket = super::hash_and_mod(id, nonce, &bytes, self.k);
-
-                // Ensure each key maps to *different* buckets
-                while bucket_choices.contains(&bucket) {
-                    nonce += 1;
-                    bucket = super::hash_and_mod(id, nonce, &bytes, self.k);
-                }
-
-                bucket_choices.push(bucket);
-                buckets[bucket].push(entry.clone());
+            for bucket in super::bucket_choices(self.hasher.as_ref(), &bytes, 2, self.k) {
+                buckets[bucket][sub_bucket].push(i);
             }
         }
 
-        let total_buckets = self.k * 9;
-        let mut collections: Vec<Vec<Tuple<K, V>>> = Vec::with_capacity(total_buckets);
+        let total_buckets = self.k * self.subcube.num_buckets();
+        let mut collections = Vec::with_capacity(total_buckets);
 
         // Encode each bucket
-        for bucket in buckets.drain(..) {
-            collections.append(&mut encode_bucket(bucket));
+        for parts in buckets.drain(..) {
+            let base = collections.len();
+            collections.append(&mut self.subcube.view_parts(parts, base));
         }
 
         assert_eq!(collections.len(), total_buckets);
-        collections
+        Ok(EncodedView::new(collections))
     }
 
-    // This implements Pung's get schedule algorithm
-    // Unlike other codes, this one is state-dependent
-    fn get_schedule(&self, keys: &[K]) -> Option<HashMap<K, Vec<usize>>> {
-        // Make sure mapping has been initialized. This code is state-dependent.
-        assert!(keys.len() <= self.labels.len());
-        assert!(keys.len() <= self.k);
+    fn get_schedule(&self, keys: &[K]) -> Result<HashMap<K, Vec<usize>>, ScheduleError> {
+        self.schedule(keys, &mut 0, None)
+    }
 
-        let mut schedule = HashMap::new();
-        let mut used = Vec::new();
+    fn get_schedule_with_retries(
+        &self,
+        keys: &[K],
+        _rng: &mut dyn Rng,
+        retries: &mut usize,
+    ) -> Result<HashMap<K, Vec<usize>>, ScheduleError> {
+        self.schedule(keys, retries, None)
+    }
 
-        for key in keys {
-            // Get index of bucket
-            let mut choices: Vec<usize> = self.labels[key].clone();
+    fn get_partial_schedule(&self, keys: &[K]) -> Result<PartialSchedule<K>, ScheduleError> {
+        let (batch, mut leftover) = super::split_batch(keys, self.k)?;
+        let schedule = self.schedule(batch, &mut 0, Some(&mut leftover))?;
+        Ok(PartialSchedule { schedule, leftover })
+    }
 
-            let mut bucket_choices: Vec<Vec<usize>> = Vec::new();
+    fn decode(&self, results: &[Tuple<K, V>]) -> Result<Tuple<K, V>, ScheduleError> {
+        // Recovery sets have 1, 2, or 4 buckets (fewer if some sub-buckets lack the row)
+        if results.is_empty() || results.len() > self.subcube.max_recovery_size() {
+            return Err(ScheduleError::ResultsOutOfRange {
+                min: 1,
+                max: self.subcube.max_recovery_size(),
+                actual: results.len(),
+            });
+        }
 
-            for sub_bucket in choices.drain(..) {
-                let offset = sub_bucket % 9; // each bucket has 9 sub buckets
-
-                let mut entries = match offset {
-                    0 => vec![
-                        vec![sub_bucket],
-                        vec![sub_bucket + 1, sub_bucket + 4],
-                        vec![sub_bucket + 2, sub_bucket + 6],
-                        vec![
-                            sub_bucket + 3,
-                            sub_bucket + 5,
-                            sub_bucket + 7,
-                            sub_bucket + 8,
-                        ],
-                    ],
-                    1 => vec![
-                        vec![sub_bucket + 1],
-                        vec![sub_bucket, sub_bucket + 4],
-                        vec![sub_bucket + 3, sub_bucket + 7],
-                        vec![
-                            sub_bucket + 2,
-                            sub_bucket + 5,
-                            sub_bucket + 6,
-                            sub_bucket + 8,
-                        ],
-                    ],
-                    2 => vec![
-                        vec![sub_bucket + 2],
-                        vec![sub_bucket + 3, sub_bucket + 5],
-                        vec![sub_bucket, sub_bucket + 6],
-                        vec![
-                            sub_bucket + 1,
-                            sub_bucket + 4,
-                            sub_bucket + 7,
-                            sub_bucket + 8,
-                        ],
-                    ],
-                    3 => vec![
-                        vec![sub_bucket + 3],
-                        vec![sub_bucket + 2, sub_bucket + 5],
-                        vec![sub_bucket + 1, sub_bucket + 7],
-                        vec![sub_bucket, sub_bucket + 4, sub_bucket + 6, sub_bucket + 8],
-                    ],
-                    _ => continue, // Data is unencoded in the first 4 sub_buckets
-                };
-
-                bucket_choices.append(&mut entries);
-            }
+        let mut decoded = results[0].clone();
 
-            let mut found = false;
+        for result in &results[1..] {
+            decoded ^= result.clone();
+        }
 
-            // Find a collection that has not been used.
-            for mut bucket in bucket_choices.drain(..) {
-                if !used.iter().any(|e| bucket.contains(e)) {
-                    schedule.insert(key.clone(), bucket.clone());
-                    used.append(&mut bucket);
-                    found = true;
-                    break;
-                }
-            }
+        Ok(decoded)
+    }
 
-            if !found {
-                return None;
-            }
-        }
+    // The greedy choice of recovery sets has no known bound
+    fn failure_bound(&self) -> Option<f64> {
+        None
+    }
 
-        // maps each index into a vector of 1 entry containing that index
-        // We do this only to meet the return type: each entry represents the
-        // set of indices that must be queried (in our case, our encoding is
-        // systematic so we don't need to have multiple indices per item).
-        Some(schedule)
+    // Each entry is in a data sub-bucket of 2 of the k buckets, and parity sub-buckets are as
+    // long as the longest data sub-bucket
+    fn bucket_bound(&self, n: usize, security: u32) -> usize {
+        let data_buckets = self.k * self.subcube.num_data();
+        cmp::min(n, super::padding::load_bound(n * 2, data_buckets, security))
     }
 
-    fn decode(&self, results: &[Tuple<K, V>]) -> Tuple<K, V> {
-        assert!(!results.is_empty() && results.len() <= 4);
+    // The 9 sub-buckets of each bucket form a group
+    fn layout(&self, collection_keys: &[K]) -> Result<Layout<K>, ScheduleError> {
+        let group_size = self.subcube.num_buckets();
+        let total_buckets = self.k * group_size;
+        let mut layout = Layout::new((0..total_buckets).map(|i| i / group_size).collect());
 
-        let mut decoded = results[0].clone();
+        for (i, bytes) in super::slot_order(collection_keys.iter())? {
+            let sub_bucket = self.subcube.data_bucket(self.hasher.as_ref(), &bytes);
 
-        for result in results {
-            decoded ^= result.clone();
+            for bucket in super::bucket_choices(self.hasher.as_ref(), &bytes, 2, self.k) {
+                layout.place(collection_keys[i].clone(), bucket * group_size + sub_bucket);
+            }
+        }
+
+        for base in (0..total_buckets).step_by(group_size) {
+            let data_lens: Vec<usize> = (0..self.subcube.num_data())
+                .map(|b| layout.bucket_len(base + b))
+                .collect();
+
+            for (b, len) in self.subcube.bucket_lens(&data_lens).into_iter().enumerate() {
+                layout.set_len(base + b, len);
+            }
         }
 
-        decoded
+        Ok(layout)
     }
 }
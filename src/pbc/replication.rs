@@ -1,8 +1,8 @@
-use super::BatchCode;
-use super::Tuple;
+use super::layout::Layout;
+use super::view::{BucketView, EncodedView};
+use super::{BatchCode, ScheduleError, Tuple, MAX_BUCKETS};
 use serde::Serialize;
 use std::collections::HashMap;
-use std::ops::{BitXor, BitXorAssign};
 use std::{cmp, hash};
 
 pub struct ReplicationCode {
@@ -10,40 +10,73 @@ pub struct ReplicationCode {
 }
 
 impl ReplicationCode {
+    /// Panics if k is more than MAX_BUCKETS.
     pub fn new(k: usize) -> ReplicationCode {
+        assert!(k <= MAX_BUCKETS, "Too many buckets");
         ReplicationCode { k }
     }
 }
 
 impl<K, V> BatchCode<K, V> for ReplicationCode
 where
-    K: Clone + Serialize + BitXor<Output = K> + BitXorAssign + cmp::Eq + hash::Hash,
-    V: Clone + Serialize + BitXor<Output = V> + BitXorAssign,
+    K: Clone + Serialize + cmp::Eq + hash::Hash,
+    V: Clone + Serialize,
 {
-    fn encode(&self, collection: &[Tuple<K, V>]) -> Vec<Vec<Tuple<K, V>>> {
-        let mut collections: Vec<Vec<Tuple<K, V>>> = Vec::with_capacity(self.k);
-        let copy: Vec<Tuple<K, V>> = collection.to_vec();
+    // Every bucket is a copy of the first one
+    fn encode_view(&self, collection: &[Tuple<K, V>]) -> Result<EncodedView, ScheduleError> {
+        let mut buckets: Vec<BucketView> = Vec::with_capacity(self.k);
+        let copy: Vec<usize> = super::slot_order(collection.iter().map(|e| &e.t.0))?
+            .into_iter()
+            .map(|(i, _)| i)
+            .collect();
 
-        for _ in 0..self.k {
-            collections.push(copy.clone());
+        buckets.push(BucketView::Entries(copy));
+
+        for _ in 1..self.k {
+            buckets.push(BucketView::Copy(0));
         }
 
-        collections
+        Ok(EncodedView::new(buckets))
     }
 
-    fn get_schedule(&self, keys: &[K]) -> Option<HashMap<K, Vec<usize>>> {
-        assert!(keys.len() <= self.k);
+    fn get_schedule(&self, keys: &[K]) -> Result<HashMap<K, Vec<usize>>, ScheduleError> {
+        super::check_batch(keys, self.k)?;
         let mut schedule = HashMap::new();
 
         for (i, key) in keys.iter().enumerate() {
             schedule.insert(key.clone(), vec![i]);
         }
 
-        Some(schedule)
+        Ok(schedule)
+    }
+
+    fn decode(&self, results: &[Tuple<K, V>]) -> Result<Tuple<K, V>, ScheduleError> {
+        super::check_results(1, results.len())?;
+        Ok(results[0].clone())
+    }
+
+    // Any k keys can be retrieved, one from each replica
+    fn failure_bound(&self) -> Option<f64> {
+        Some(0.0)
+    }
+
+    // Every bucket holds the entire collection
+    fn bucket_bound(&self, n: usize, _security: u32) -> usize {
+        n
     }
 
-    fn decode(&self, results: &[Tuple<K, V>]) -> Tuple<K, V> {
-        assert_eq!(results.len(), 1);
-        results[0].clone()
+    // Every bucket is a copy of the collection, so they all form one group
+    fn layout(&self, collection_keys: &[K]) -> Result<Layout<K>, ScheduleError> {
+        let mut layout = Layout::new(vec![0; self.k]);
+
+        for (i, _) in super::slot_order(collection_keys.iter())? {
+            layout.place(collection_keys[i].clone(), 0);
+        }
+
+        for bucket in 1..self.k {
+            layout.set_len(bucket, layout.bucket_len(0));
+        }
+
+        Ok(layout)
     }
 }
@@ -1,13 +1,18 @@
-use super::BatchCode;
-use super::Tuple;
-use bincode::serialize;
+use super::hasher::{BucketHasher, HasherSpec};
+use super::layout::Layout;
+use super::view::{BucketView, EncodedView};
+use super::{BatchCode, ParamError, PartialSchedule, ScheduleError, Strategy, Tuple};
+use rand::Rng;
 use serde::Serialize;
 use std::collections::HashMap;
-use std::ops::{BitXor, BitXorAssign};
+use std::sync::Arc;
 use std::{cmp, hash};
 
 pub struct ShardingCode {
     k: usize,
+    replicas: usize, // replicas of each logical bucket
+    strategy: Strategy,
+    hasher: Arc<dyn BucketHasher>,
 }
 
 impl ShardingCode {
@@ -15,55 +20,85 @@ impl ShardingCode {
         assert!(k > 2, "Bound is not defined for k <= 2");
         let bound = retry_bound!(k);
         assert!(bound < k, "You are better off using replication");
-        ShardingCode { k }
+        ShardingCode {
+            k,
+            replicas: bound,
+            strategy: Strategy::default(),
+            hasher: HasherSpec::default().build(),
+        }
     }
-}
-
-impl<K, V> BatchCode<K, V> for ShardingCode
-where
-    K: Clone + Serialize + BitXor<Output = K> + BitXorAssign + cmp::Eq + hash::Hash,
-    V: Clone + Serialize + BitXor<Output = V> + BitXorAssign,
-{
-    // Encoding is placing each entry in a logical bucket
-    // We also replicate each logical bucket b times (b = retry bound).
-    fn encode(&self, collection: &[Tuple<K, V>]) -> Vec<Vec<Tuple<K, V>>> {
-        let bound = retry_bound!(self.k);
 
-        let total_buckets = self.k * bound;
+    /// Creates a sharding code that keeps the given number of replicas of each of its k
+    /// logical buckets (instead of the retry bound used by `new`).
+    pub fn with_replicas(k: usize, replicas: usize) -> Result<ShardingCode, ParamError> {
+        super::check_replicas(k, replicas)?;
+
+        Ok(ShardingCode {
+            k,
+            replicas,
+            strategy: Strategy::default(),
+            hasher: HasherSpec::default().build(),
+        })
+    }
 
-        let mut collections: Vec<Vec<Tuple<K, V>>> = Vec::with_capacity(total_buckets);
+    /// Creates a sharding code with the fewest replicas for which a batch of k keys fails with
+    /// probability at most target (see `failure_bound`).
+    pub fn with_failure(k: usize, target: f64) -> Result<ShardingCode, ParamError> {
+        let replicas = super::replicas_for(k, 1.0 / k as f64, target)?;
+        ShardingCode::with_replicas(k, replicas)
+    }
 
-        for _ in 0..self.k {
-            collections.push(Vec::new());
-        }
+    pub fn replicas(&self) -> usize {
+        self.replicas
+    }
 
-        for entry in collection {
-            // The following computes bucket = sha256(key) % k;
-            let bytes = serialize(&entry.t.0).unwrap();
-            let bucket = super::hash_and_mod(0, 0, &bytes, self.k);
-            collections[bucket].push(entry.clone());
-        }
+    pub fn set_strategy(&mut self, strategy: Strategy) {
+        self.strategy = strategy;
+    }
 
-        // Replicate each of the k logical bucket into b buckets
-        // Every i mod k has the same collection where 0 <= i < b.
-        for i in self.k..total_buckets {
-            let clone = collections[i % self.k].clone();
-            collections.push(clone);
-        }
+    /// Sets the hash function that places keys in buckets (SHA-256 by default, see
+    /// hasher::Sha256Hasher). The client and the server must use the same one.
+    pub fn set_hasher(&mut self, hasher: Arc<dyn BucketHasher>) {
+        self.hasher = hasher;
+    }
 
-        assert_eq!(collections.len(), total_buckets);
-        collections
+    pub fn hasher(&self) -> &dyn BucketHasher {
+        self.hasher.as_ref()
     }
 
-    fn get_schedule(&self, keys: &[K]) -> Option<HashMap<K, Vec<usize>>> {
-        assert!(keys.len() <= self.k);
-        let bound = retry_bound!(self.k);
+    // Occupied replicas that are skipped are counted in retries.
+    fn schedule<K>(
+        &self,
+        keys: &[K],
+        retries: &mut usize,
+        mut leftover: Option<&mut Vec<K>>,
+    ) -> Result<HashMap<K, Vec<usize>>, ScheduleError>
+    where
+        K: Clone + Serialize + cmp::Eq + hash::Hash,
+    {
+        super::check_batch(keys, self.k)?;
+        let bound = self.replicas;
+
+        if self.strategy != Strategy::Heuristic {
+            let mut candidates: Vec<Vec<usize>> = Vec::with_capacity(keys.len());
+
+            // A key can be found in any replica of its logical bucket
+            for key in keys {
+                let bytes = super::key_bytes(key)?;
+                let bucket = self.hasher.hash_and_mod(0, 0, &bytes, self.k);
+                candidates.push((0..bound).map(|i| bucket + i * self.k).collect());
+            }
+
+            let assignment = super::matching::assign(self.strategy, &candidates, self.k * bound);
+            return super::matching::schedule(keys, assignment, leftover);
+        }
 
         let mut schedule = HashMap::new();
 
-        for key in keys {
-            let bytes = serialize(&key).unwrap();
-            let bucket = super::hash_and_mod(0, 0, &bytes, self.k);
+        for (index, key) in keys.iter().enumerate() {
+            let bytes = super::key_bytes(key)?;
+            let bucket = self.hasher.hash_and_mod(0, 0, &bytes, self.k);
+            let mut found = false;
 
             // Find a bucket that's not being used.
             for i in 0..bound {
@@ -71,11 +106,17 @@ where
 
                 if !schedule.values().any(|e| e == &entry) {
                     schedule.insert(key.clone(), entry);
+                    found = true;
                     break;
                 }
 
-                if i == bound {
-                    return None;
+                *retries += 1;
+            }
+
+            if !found {
+                match leftover.as_mut() {
+                    Some(leftover) => leftover.push(key.clone()),
+                    None => return Err(ScheduleError::RetryBoundExhausted { index, bound }),
                 }
             }
         }
@@ -84,11 +125,91 @@ where
         // We do this only to meet the return type: each entry represents the
         // set of indices that must be queried (in our case, our encoding is
         // systematic so we don't need to have multiple indices per item).
-        Some(schedule)
+        Ok(schedule)
+    }
+}
+
+impl<K, V> BatchCode<K, V> for ShardingCode
+where
+    K: Clone + Serialize + cmp::Eq + hash::Hash,
+    V: Clone + Serialize,
+{
+    // Encoding is placing each entry in a logical bucket
+    // We also replicate each logical bucket b times (b = replicas).
+    fn encode_view(&self, collection: &[Tuple<K, V>]) -> Result<EncodedView, ScheduleError> {
+        let total_buckets = self.k * self.replicas;
+
+        let mut collections: Vec<Vec<usize>> = vec![Vec::new(); self.k];
+
+        for (i, bytes) in super::slot_order(collection.iter().map(|e| &e.t.0))? {
+            // The following computes bucket = sha256(key) % k;
+            let bucket = self.hasher.hash_and_mod(0, 0, &bytes, self.k);
+            collections[bucket].push(i);
+        }
+
+        let mut buckets: Vec<BucketView> = Vec::with_capacity(total_buckets);
+        buckets.extend(collections.into_iter().map(BucketView::Entries));
+
+        // Replicate each of the k logical bucket into b buckets
+        // Every i mod k has the same collection where 0 <= i < b.
+        for i in self.k..total_buckets {
+            buckets.push(BucketView::Copy(i % self.k));
+        }
+
+        assert_eq!(buckets.len(), total_buckets);
+        Ok(EncodedView::new(buckets))
+    }
+
+    fn get_schedule(&self, keys: &[K]) -> Result<HashMap<K, Vec<usize>>, ScheduleError> {
+        self.schedule(keys, &mut 0, None)
+    }
+
+    fn get_schedule_with_retries(
+        &self,
+        keys: &[K],
+        _rng: &mut dyn Rng,
+        retries: &mut usize,
+    ) -> Result<HashMap<K, Vec<usize>>, ScheduleError> {
+        self.schedule(keys, retries, None)
+    }
+
+    fn get_partial_schedule(&self, keys: &[K]) -> Result<PartialSchedule<K>, ScheduleError> {
+        let (batch, mut leftover) = super::split_batch(keys, self.k)?;
+        let schedule = self.schedule(batch, &mut 0, Some(&mut leftover))?;
+        Ok(PartialSchedule { schedule, leftover })
+    }
+
+    fn decode(&self, results: &[Tuple<K, V>]) -> Result<Tuple<K, V>, ScheduleError> {
+        super::check_results(1, results.len())?;
+        Ok(results[0].clone())
+    }
+
+    // A batch can only fail if more than b of its keys map to the same logical bucket (b =
+    // replicas). Union bound over the k logical buckets.
+    fn failure_bound(&self) -> Option<f64> {
+        let tail = super::padding::binomial_tail(self.k, 1.0 / self.k as f64, self.replicas + 1);
+        Some((self.k as f64 * tail).min(1.0))
     }
 
-    fn decode(&self, results: &[Tuple<K, V>]) -> Tuple<K, V> {
-        assert_eq!(results.len(), 1);
-        results[0].clone()
+    // Each entry is in one of k logical buckets
+    fn bucket_bound(&self, n: usize, security: u32) -> usize {
+        super::padding::load_bound(n, self.k, security)
+    }
+
+    // The replicas of each logical bucket form a group
+    fn layout(&self, collection_keys: &[K]) -> Result<Layout<K>, ScheduleError> {
+        let total_buckets = self.k * self.replicas;
+        let mut layout = Layout::new((0..total_buckets).map(|i| i % self.k).collect());
+
+        for (i, bytes) in super::slot_order(collection_keys.iter())? {
+            let bucket = self.hasher.hash_and_mod(0, 0, &bytes, self.k);
+            layout.place(collection_keys[i].clone(), bucket);
+        }
+
+        for i in self.k..total_buckets {
+            layout.set_len(i, layout.bucket_len(i % self.k));
+        }
+
+        Ok(layout)
     }
 }
@@ -0,0 +1,123 @@
+use super::stats::{load_stats, LoadStats};
+use super::{BatchCode, ScheduleError};
+use rand::Rng;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::{cmp, hash};
+
+/// Outcome of running a code's scheduling algorithm on many random batches (see
+/// `estimate_failure`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct FailureEstimate {
+    pub trials: usize,
+    /// Number of batches for which no schedule was found.
+    pub failures: usize,
+    /// Retries taken by each batch (see `BatchCode::get_schedule_with_retries`), including the
+    /// ones that failed.
+    pub retries: LoadStats,
+}
+
+impl FailureEstimate {
+    /// Fraction of the batches that failed.
+    pub fn failure_rate(&self) -> f64 {
+        if self.trials == 0 {
+            return 0.0;
+        }
+
+        self.failures as f64 / self.trials as f64
+    }
+
+    /// Wilson score interval for the failure probability, where z is the standard normal
+    /// quantile of the desired confidence (e.g., 1.96 for 95%). Unlike the normal
+    /// approximation, this gives a meaningful upper bound when no batch failed.
+    pub fn interval(&self, z: f64) -> (f64, f64) {
+        if self.trials == 0 {
+            return (0.0, 1.0);
+        }
+
+        let n = self.trials as f64;
+        let p = self.failure_rate();
+        let z2 = z * z;
+
+        let center = (p + z2 / (2.0 * n)) / (1.0 + z2 / n);
+        let spread = z / (1.0 + z2 / n) * (p * (1.0 - p) / n + z2 / (4.0 * n * n)).sqrt();
+
+        // The interval ends at 0 (resp. 1) when no (resp. every) batch failed
+        let low = if self.failures == 0 {
+            0.0
+        } else {
+            (center - spread).max(0.0)
+        };
+        let high = if self.failures == self.trials {
+            1.0
+        } else {
+            (center + spread).min(1.0)
+        };
+
+        (low, high)
+    }
+}
+
+/// Number of trials without a single failure needed for the upper end of the Wilson interval
+/// (see `FailureEstimate::interval`) to drop to `target` (e.g., 2^-20).
+pub fn trials_needed(target: f64, z: f64) -> usize {
+    assert!(target > 0.0 && target < 1.0, "Target must be in (0, 1)");
+
+    // With no failures, the upper end is z^2 / (n + z^2)
+    (z * z * (1.0 - target) / target).ceil() as usize
+}
+
+/// Estimates how often `get_schedule` fails for batches of `batch_size` distinct keys. Each
+/// trial draws keys with `sample` until it has `batch_size` distinct ones, and schedules them
+/// with `get_schedule_with_retries`. Both the keys and the scheduling algorithm use `rng`, so a
+/// seeded rng makes the estimate reproducible.
+///
+/// `sample` must be able to produce at least `batch_size` distinct keys. Errors that do not
+/// depend on the batch (e.g., `TooManyKeys` or `Serialization`) are returned instead of being
+/// counted as failures.
+pub fn estimate_failure<K, V, F>(
+    code: &dyn BatchCode<K, V>,
+    batch_size: usize,
+    trials: usize,
+    mut sample: F,
+    rng: &mut dyn Rng,
+) -> Result<FailureEstimate, ScheduleError>
+where
+    K: Clone + Serialize + cmp::Eq + hash::Hash,
+    V: Clone + Serialize,
+    F: FnMut(&mut dyn Rng) -> K,
+{
+    let mut failures = 0;
+    let mut retries = Vec::with_capacity(trials);
+
+    for _ in 0..trials {
+        let mut seen = HashSet::with_capacity(batch_size);
+        let mut keys = Vec::with_capacity(batch_size);
+
+        while keys.len() < batch_size {
+            let key = sample(rng);
+
+            if seen.insert(key.clone()) {
+                keys.push(key);
+            }
+        }
+
+        let mut count = 0;
+
+        match code.get_schedule_with_retries(&keys, rng, &mut count) {
+            Ok(_) => (),
+            Err(e @ ScheduleError::TooManyKeys { .. })
+            | Err(e @ ScheduleError::DuplicateKey { .. })
+            | Err(e @ ScheduleError::Serialization(_)) => return Err(e),
+            Err(_) => failures += 1,
+        }
+
+        retries.push(count);
+    }
+
+    Ok(FailureEstimate {
+        trials,
+        failures,
+        retries: load_stats(&retries),
+    })
+}
@@ -0,0 +1,244 @@
+use super::{ScheduleError, Tuple};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::{cmp, fmt, hash};
+
+/// Summary of a list of counts (e.g., the number of entries in each bucket).
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadStats {
+    pub min: usize,
+    pub max: usize,
+    pub mean: f64,
+    pub stddev: f64,
+    /// Maps each count to the number of times it occurs.
+    pub histogram: BTreeMap<usize, usize>,
+}
+
+/// Summarizes a list of counts. All fields are 0 (and the histogram empty) if the list is empty.
+pub fn load_stats(counts: &[usize]) -> LoadStats {
+    let mut histogram = BTreeMap::new();
+
+    for count in counts {
+        *histogram.entry(*count).or_insert(0) += 1;
+    }
+
+    if counts.is_empty() {
+        return LoadStats {
+            min: 0,
+            max: 0,
+            mean: 0.0,
+            stddev: 0.0,
+            histogram,
+        };
+    }
+
+    let n = counts.len() as f64;
+    let mean = counts.iter().sum::<usize>() as f64 / n;
+    let variance = counts
+        .iter()
+        .map(|c| (*c as f64 - mean).powi(2))
+        .sum::<f64>()
+        / n;
+
+    LoadStats {
+        min: *counts.iter().min().unwrap(),
+        max: *counts.iter().max().unwrap(),
+        mean,
+        stddev: variance.sqrt(),
+        histogram,
+    }
+}
+
+/// SealPIR parameters, as passed to `MultiPirClient::new` and `MultiPirServer::new`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PirParams {
+    pub poly_degree: u32,
+    pub log_plain_mod: u32,
+    pub d: u32,
+}
+
+/// Projected cost of serving buckets with SealPIR. Sizes are in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct PirCost {
+    /// Memory taken by the preprocessed database on the server.
+    pub server_memory: u64,
+    pub query_size: u64,
+    pub reply_size: u64,
+    /// Plaintext-ciphertext multiplications needed to answer a query, which make up most of
+    /// the server's CPU time.
+    pub server_ops: u64,
+}
+
+/// Bits of the ciphertext coefficient modulus that SealPIR uses for a given polynomial degree
+/// (SEAL 2.3.1's default 128-bit security parameters). None if the degree is not supported.
+pub fn coeff_modulus_bits(poly_degree: u32) -> Option<u32> {
+    match poly_degree {
+        1024 => Some(27),
+        2048 => Some(54),
+        4096 => Some(109),
+        8192 => Some(218),
+        16384 => Some(438),
+        32768 => Some(881),
+        _ => None,
+    }
+}
+
+/// Projects the cost of a SealPIR instance over ele_num elements of ele_size bytes, following
+/// SealPIR's layout: elements are packed into plaintexts, the plaintexts are arranged in a
+/// d-dimensional hypercube, the query has one ciphertext per dimension (more if a dimension
+/// exceeds the polynomial degree), and the reply has F^(d-1) ciphertexts, where F is the
+/// ciphertext expansion factor. The server multiplies every plaintext by the first dimension's
+/// query, and each of the side^(d-i) ciphertexts left after dimension i (expanded into F
+/// plaintexts) by the next one. These are estimates, not measurements.
+///
+/// Returns None if the polynomial degree is not supported, d is 0, or an element does not fit
+/// in a plaintext.
+pub fn pir_cost(ele_num: usize, ele_size: usize, params: PirParams) -> Option<PirCost> {
+    if params.d == 0 {
+        return None;
+    }
+
+    let n = params.poly_degree as u64;
+    let log_q = u64::from(coeff_modulus_bits(params.poly_degree)?);
+    let log_t = u64::from(params.log_plain_mod);
+
+    let coeffs_per_ele = (8 * ele_size as u64).div_ceil(log_t);
+    let ele_per_ptxt = n / cmp::max(coeffs_per_ele, 1);
+
+    if ele_per_ptxt == 0 {
+        return None;
+    }
+
+    let num_ptxts = cmp::max((ele_num as u64).div_ceil(ele_per_ptxt), 1);
+
+    // Side of the hypercube (the database is padded to side^d plaintexts)
+    let mut side = (num_ptxts as f64).powf(1.0 / f64::from(params.d)).ceil() as u64;
+
+    while side.pow(params.d) < num_ptxts {
+        side += 1;
+    }
+
+    let ciphertext = 2 * n * log_q / 8;
+    let primes = log_q.div_ceil(60); // 64-bit words per coefficient
+    let expansion = 2 * log_q.div_ceil(log_t);
+
+    let server_ops = (0..params.d)
+        .map(|i| side.pow(params.d - i) * expansion.pow(i))
+        .sum();
+
+    Some(PirCost {
+        server_memory: side.pow(params.d) * n * primes * 8,
+        query_size: u64::from(params.d) * side.div_ceil(n) * ciphertext,
+        reply_size: expansion.pow(params.d - 1) * ciphertext,
+        server_ops,
+    })
+}
+
+/// Statistics about the output of `BatchCode::encode` for a given collection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EncodingStats {
+    /// Number of entries in each bucket.
+    pub bucket_lens: Vec<usize>,
+    /// Entries per bucket.
+    pub load: LoadStats,
+    pub input_entries: usize,
+    pub stored_entries: usize,
+    /// Ratio of stored entries to input entries.
+    pub blowup: f64,
+    /// Number of buckets holding a copy of each entry of the collection. Parity entries (e.g.,
+    /// in PungCode) only count if they are not combined with any other entry.
+    pub replication: LoadStats,
+}
+
+/// Computes statistics about `buckets`, the output of `BatchCode::encode` on `collection`.
+pub fn encoding_stats<K, V>(
+    collection: &[Tuple<K, V>],
+    buckets: &[Vec<Tuple<K, V>>],
+) -> Result<EncodingStats, ScheduleError>
+where
+    K: Clone + Serialize + cmp::Eq + hash::Hash,
+    V: Clone + Serialize,
+{
+    let bucket_lens: Vec<usize> = buckets.iter().map(|b| b.len()).collect();
+    let stored_entries: usize = bucket_lens.iter().sum();
+
+    let positions: HashMap<&K, usize> = collection
+        .iter()
+        .enumerate()
+        .map(|(i, e)| (&e.t.0, i))
+        .collect();
+
+    // An entry is a copy if its key and its value match the collection's entry
+    let mut copies = vec![0; collection.len()];
+
+    for entry in buckets.iter().flatten() {
+        if let Some(&i) = positions.get(&entry.t.0) {
+            if super::key_bytes(entry)? == super::key_bytes(&collection[i])? {
+                copies[i] += 1;
+            }
+        }
+    }
+
+    let blowup = if collection.is_empty() {
+        0.0
+    } else {
+        stored_entries as f64 / collection.len() as f64
+    };
+
+    Ok(EncodingStats {
+        load: load_stats(&bucket_lens),
+        bucket_lens,
+        input_entries: collection.len(),
+        stored_entries,
+        blowup,
+        replication: load_stats(&copies),
+    })
+}
+
+impl EncodingStats {
+    /// Projects the cost of serving every bucket with SealPIR (see pir_cost), where each entry
+    /// takes ele_size bytes. None if any bucket cannot be served with these parameters.
+    pub fn pir_cost(&self, ele_size: usize, params: PirParams) -> Option<PirCost> {
+        let mut total = PirCost::default();
+
+        for len in &self.bucket_lens {
+            let cost = pir_cost(*len, ele_size, params)?;
+            total.server_memory += cost.server_memory;
+            total.query_size += cost.query_size;
+            total.reply_size += cost.reply_size;
+            total.server_ops += cost.server_ops;
+        }
+
+        Some(total)
+    }
+}
+
+impl fmt::Display for LoadStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "min {}, max {}, mean {:.2}, stddev {:.2}",
+            self.min, self.max, self.mean, self.stddev
+        )
+    }
+}
+
+impl fmt::Display for EncodingStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "buckets: {}", self.bucket_lens.len())?;
+        writeln!(f, "bucket load: {}", self.load)?;
+        writeln!(
+            f,
+            "entries: {} input, {} stored (blowup {:.2})",
+            self.input_entries, self.stored_entries, self.blowup
+        )?;
+        writeln!(f, "copies per entry: {}", self.replication)?;
+        writeln!(f, "load histogram:")?;
+
+        for (load, count) in &self.load.histogram {
+            writeln!(f, "  {load}: {count}")?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,426 @@
+use super::hasher::{BucketHasher, HasherSpec};
+use super::layout::Layout;
+use super::view::{BucketView, EncodedView};
+use super::{BatchCode, ScheduleError, Tuple, Xor, XorCode, MAX_BUCKETS};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::{cmp, hash};
+
+// Hash id used to place keys in data sub-buckets. PungCode uses ids 0 and 1 to choose its
+// two buckets, so this one must differ from those.
+const PLACEMENT_ID: usize = 2;
+
+/// Largest number of dimensions of a subcube. SubcubeCode schedules a batch of 2^dim keys by
+/// searching for disjoint recovery sets among the 2^dim of each key, which takes exponential
+/// time in the worst case, so dim is kept small enough for the search to always finish quickly.
+pub const MAX_DIM: usize = 3;
+
+/// Layout of a subcube batch code (Ishai et al., STOC '04). A bucket is split into l^dim data
+/// sub-buckets arranged in a dim-dimensional cube of side l, and every line of the cube gets an
+/// extra parity sub-bucket (the XOR of the line). This gives (l + 1)^dim sub-buckets in total,
+/// any 2^dim entries of which can be retrieved by reading each sub-bucket at most once.
+///
+/// Each sub-bucket has coordinates in {0, ..., l}^dim, where coordinate l denotes parity.
+/// Sub-buckets are numbered in lexicographic order of their coordinates, with the l^dim data
+/// sub-buckets first and the parity sub-buckets after them. Subcube::new(2, 2) is the
+/// (n, 9n/4, 4, 9) code used by PungCode.
+#[derive(Debug, Clone)]
+pub struct Subcube {
+    l: usize,
+    dim: usize,
+    coords: Vec<Vec<usize>>,           // sub-bucket -> coordinates
+    index: HashMap<Vec<usize>, usize>, // coordinates -> sub-bucket
+    plan: Vec<Vec<usize>>,             // sub-bucket -> data sub-buckets XORed into it
+}
+
+impl Subcube {
+    /// Panics if l or dim is 0, dim is more than MAX_DIM, or there are more than MAX_BUCKETS
+    /// sub-buckets.
+    pub fn new(l: usize, dim: usize) -> Subcube {
+        assert!(
+            l > 0 && dim > 0,
+            "The subcube needs at least one part and one dimension"
+        );
+        assert!(dim <= MAX_DIM, "The subcube has too many dimensions");
+
+        let total = u32::try_from(dim)
+            .ok()
+            .and_then(|dim| l.checked_add(1)?.checked_pow(dim))
+            .filter(|&total| total <= MAX_BUCKETS)
+            .expect("The subcube has too many sub-buckets");
+
+        // Enumerate coordinates in lexicographic order (first coordinate is the most significant)
+        let mut all: Vec<Vec<usize>> = Vec::with_capacity(total);
+
+        for i in 0..total {
+            let mut c = vec![0; dim];
+            let mut rest = i;
+
+            for j in (0..dim).rev() {
+                c[j] = rest % (l + 1);
+                rest /= l + 1;
+            }
+
+            all.push(c);
+        }
+
+        // Data sub-buckets first, parity sub-buckets after
+        let (mut coords, parity): (Vec<Vec<usize>>, Vec<Vec<usize>>) =
+            all.into_iter().partition(|c| c.iter().all(|&x| x < l));
+        coords.extend(parity);
+
+        let index: HashMap<Vec<usize>, usize> = coords
+            .iter()
+            .enumerate()
+            .map(|(i, c)| (c.clone(), i))
+            .collect();
+
+        let num_data = l.pow(dim as u32);
+
+        // A sub-bucket is the XOR of all data sub-buckets that agree with it on its
+        // non-parity coordinates.
+        let plan = coords
+            .iter()
+            .map(|c| {
+                (0..num_data)
+                    .filter(|&x| {
+                        coords[x]
+                            .iter()
+                            .zip(c)
+                            .all(|(&xi, &ci)| ci == l || xi == ci)
+                    })
+                    .collect()
+            })
+            .collect();
+
+        Subcube {
+            l,
+            dim,
+            coords,
+            index,
+            plan,
+        }
+    }
+
+    /// Number of data sub-buckets (l^dim).
+    pub fn num_data(&self) -> usize {
+        self.l.pow(self.dim as u32)
+    }
+
+    /// Total number of sub-buckets ((l + 1)^dim).
+    pub fn num_buckets(&self) -> usize {
+        self.coords.len()
+    }
+
+    /// Number of entries that can be retrieved from one encoded bucket (2^dim).
+    pub fn batch_size(&self) -> usize {
+        1 << self.dim
+    }
+
+    /// Ratio between the encoded and the original storage (((l + 1) / l)^dim).
+    pub fn storage_overhead(&self) -> f64 {
+        (self.num_buckets() as f64) / (self.num_data() as f64)
+    }
+
+    /// For each sub-bucket, the data sub-buckets that are XORed together to produce it.
+    /// Data sub-buckets map to themselves.
+    pub fn plan(&self) -> &[Vec<usize>] {
+        &self.plan
+    }
+
+    /// The 2^dim disjoint sets of sub-buckets from which an entry of the given data sub-bucket
+    /// can be recovered (by XORing the entries at the same row). The first set is the data
+    /// sub-bucket itself.
+    pub fn recovery_sets(&self, data: usize) -> Vec<Vec<usize>> {
+        assert!(data < self.num_data());
+        let x = &self.coords[data];
+
+        // Each set of dimensions S yields one recovery set: all sub-buckets that agree with x
+        // outside S and differ from x in every dimension of S.
+        (0..self.batch_size())
+            .map(|mask| {
+                let mut set: Vec<Vec<usize>> = vec![vec![]];
+
+                for (i, &xi) in x.iter().enumerate() {
+                    let values: Vec<usize> = if mask & (1 << (self.dim - 1 - i)) == 0 {
+                        vec![xi]
+                    } else {
+                        (0..=self.l).filter(|&v| v != xi).collect()
+                    };
+
+                    set = set
+                        .iter()
+                        .flat_map(|prefix| {
+                            values.iter().map(move |&v| {
+                                let mut c = prefix.clone();
+                                c.push(v);
+                                c
+                            })
+                        })
+                        .collect();
+                }
+
+                let mut set: Vec<usize> = set.iter().map(|c| self.index[c]).collect();
+                set.sort_unstable();
+                set
+            })
+            .collect()
+    }
+
+    /// Largest number of results needed to decode an entry (l^dim). Recovery sets have
+    /// l^|S| sub-buckets, but sub-buckets that lack the entry's row are not read, so decoding
+    /// may take any number of results between 1 and this.
+    pub fn max_recovery_size(&self) -> usize {
+        self.num_data()
+    }
+
+    /// Same as encode_parts, but for data sub-buckets given as positions in a collection (see
+    /// EncodedView). The returned parity sub-buckets refer to the data sub-buckets assuming that
+    /// these start at bucket `base` of the view.
+    pub fn view_parts(&self, parts: Vec<Vec<usize>>, base: usize) -> Vec<BucketView> {
+        assert_eq!(parts.len(), self.num_data());
+
+        let mut buckets: Vec<BucketView> = parts.into_iter().map(BucketView::Entries).collect();
+
+        for sources in &self.plan[self.num_data()..] {
+            buckets.push(BucketView::Parity(
+                sources.iter().map(|s| base + s).collect(),
+            ));
+        }
+
+        buckets
+    }
+
+    /// Given the number of rows of each data sub-bucket, returns the number of rows of every
+    /// sub-bucket (as produced by encode_parts).
+    pub fn bucket_lens(&self, data_lens: &[usize]) -> Vec<usize> {
+        assert_eq!(data_lens.len(), self.num_data());
+
+        self.plan
+            .iter()
+            .map(|sources| sources.iter().map(|&s| data_lens[s]).max().unwrap())
+            .collect()
+    }
+
+    /// The data sub-bucket of a key (given its binary representation). This depends only on the
+    /// key, so clients can compute it without access to the encoded collection.
+    pub fn data_bucket(&self, hasher: &dyn BucketHasher, bytes: &[u8]) -> usize {
+        hasher.hash_and_mod(PLACEMENT_ID, 0, bytes, self.num_data())
+    }
+
+    /// Takes the l^dim data sub-buckets (see data_bucket) and appends the parity sub-buckets.
+    /// Row r of a parity sub-bucket is the XOR of row r of every data sub-bucket in its plan
+    /// that has such a row (missing rows count as zero).
+    pub fn encode_parts<T>(&self, mut encodings: Vec<Vec<T>>) -> Vec<Vec<T>>
+    where
+        T: Clone + Xor,
+    {
+        assert_eq!(encodings.len(), self.num_data());
+
+        for sources in &self.plan[self.num_data()..] {
+            let rows = sources.iter().map(|&s| encodings[s].len()).max().unwrap();
+
+            let parity: Vec<T> = (0..rows)
+                .map(|r| {
+                    sources
+                        .iter()
+                        .filter_map(|&s| encodings[s].get(r))
+                        .cloned()
+                        .reduce(|a, b| a ^ b)
+                        .unwrap()
+                })
+                .collect();
+
+            encodings.push(parity);
+        }
+
+        encodings
+    }
+}
+
+/// A standalone subcube batch code: encodes the whole collection into (l + 1)^dim buckets, any
+/// 2^dim entries of which can be retrieved. Keys are placed in data sub-buckets by hashing, so
+/// scheduling only needs the code parameters.
+pub struct SubcubeCode {
+    subcube: Subcube,
+    hasher: Arc<dyn BucketHasher>,
+}
+
+impl SubcubeCode {
+    pub fn new(l: usize, dim: usize) -> SubcubeCode {
+        SubcubeCode {
+            subcube: Subcube::new(l, dim),
+            hasher: HasherSpec::default().build(),
+        }
+    }
+
+    pub fn subcube(&self) -> &Subcube {
+        &self.subcube
+    }
+
+    /// Sets the hash function that places keys in buckets (SHA-256 by default, see
+    /// hasher::Sha256Hasher). The client and the server must use the same one.
+    pub fn set_hasher(&mut self, hasher: Arc<dyn BucketHasher>) {
+        self.hasher = hasher;
+    }
+
+    pub fn hasher(&self) -> &dyn BucketHasher {
+        self.hasher.as_ref()
+    }
+}
+
+// Assigns a disjoint recovery set to each entry (backtracking). options[i] holds the recovery
+// sets of entry i, and deepest records the furthest entry that the search could not place.
+fn assign_sets(
+    options: &[Vec<Vec<usize>>],
+    used: &mut Vec<bool>,
+    chosen: &mut Vec<usize>,
+    deepest: &mut usize,
+) -> bool {
+    let i = chosen.len();
+
+    if i == options.len() {
+        return true;
+    }
+
+    *deepest = cmp::max(*deepest, i);
+
+    for (j, set) in options[i].iter().enumerate() {
+        if set.iter().any(|&b| used[b]) {
+            continue;
+        }
+
+        for &b in set {
+            used[b] = true;
+        }
+
+        chosen.push(j);
+
+        if assign_sets(options, used, chosen, deepest) {
+            return true;
+        }
+
+        chosen.pop();
+
+        for &b in set {
+            used[b] = false;
+        }
+    }
+
+    false
+}
+
+impl<K, V> XorCode<K, V> for SubcubeCode
+where
+    K: Clone + Serialize + Xor + cmp::Eq + hash::Hash,
+    V: Clone + Serialize + Xor,
+{
+}
+
+impl<K, V> BatchCode<K, V> for SubcubeCode
+where
+    K: Clone + Serialize + Xor + cmp::Eq + hash::Hash,
+    V: Clone + Serialize + Xor,
+{
+    fn encode(&self, collection: &[Tuple<K, V>]) -> Result<Vec<Vec<Tuple<K, V>>>, ScheduleError> {
+        let view = self.encode_view(collection)?;
+
+        Ok((0..view.num_buckets())
+            .map(|bucket| view.materialize(bucket, collection))
+            .collect())
+    }
+
+    fn encode_view(&self, collection: &[Tuple<K, V>]) -> Result<EncodedView, ScheduleError> {
+        let mut parts: Vec<Vec<usize>> = vec![Vec::new(); self.subcube.num_data()];
+
+        for (i, bytes) in super::slot_order(collection.iter().map(|e| &e.t.0))? {
+            parts[self.subcube.data_bucket(self.hasher.as_ref(), &bytes)].push(i);
+        }
+
+        Ok(EncodedView::new(self.subcube.view_parts(parts, 0)))
+    }
+
+    // Unlike PungCode, which picks recovery sets greedily, this searches for a disjoint
+    // assignment of recovery sets, so any 2^dim keys can be scheduled.
+    fn get_schedule(&self, keys: &[K]) -> Result<HashMap<K, Vec<usize>>, ScheduleError> {
+        super::check_batch(keys, self.subcube.batch_size())?;
+
+        let mut options = Vec::with_capacity(keys.len());
+
+        for key in keys {
+            let bytes = super::key_bytes(key)?;
+            options.push(
+                self.subcube
+                    .recovery_sets(self.subcube.data_bucket(self.hasher.as_ref(), &bytes)),
+            );
+        }
+
+        let mut used = vec![false; self.subcube.num_buckets()];
+        let mut chosen = Vec::with_capacity(keys.len());
+        let mut deepest = 0;
+
+        if !assign_sets(&options, &mut used, &mut chosen, &mut deepest) {
+            return Err(ScheduleError::RecoverySetsExhausted { index: deepest });
+        }
+
+        Ok(keys
+            .iter()
+            .zip(options)
+            .zip(chosen)
+            .map(|((key, mut sets), j)| (key.clone(), sets.swap_remove(j)))
+            .collect())
+    }
+
+    fn decode(&self, results: &[Tuple<K, V>]) -> Result<Tuple<K, V>, ScheduleError> {
+        if results.is_empty() || results.len() > self.subcube.max_recovery_size() {
+            return Err(ScheduleError::ResultsOutOfRange {
+                min: 1,
+                max: self.subcube.max_recovery_size(),
+                actual: results.len(),
+            });
+        }
+
+        let mut decoded = results[0].clone();
+
+        for result in &results[1..] {
+            decoded ^= result.clone();
+        }
+
+        Ok(decoded)
+    }
+
+    // Scheduling searches for disjoint recovery sets, which always exist for 2^dim keys (and
+    // the search always ends, since dim <= MAX_DIM)
+    fn failure_bound(&self) -> Option<f64> {
+        Some(0.0)
+    }
+
+    // Each entry is in one of the data sub-buckets, and parity sub-buckets are as long as the
+    // longest data sub-bucket
+    fn bucket_bound(&self, n: usize, security: u32) -> usize {
+        super::padding::load_bound(n, self.subcube.num_data(), security)
+    }
+
+    // All sub-buckets share rows, so they form one group
+    fn layout(&self, collection_keys: &[K]) -> Result<Layout<K>, ScheduleError> {
+        let mut layout = Layout::new(vec![0; self.subcube.num_buckets()]);
+
+        for (i, bytes) in super::slot_order(collection_keys.iter())? {
+            layout.place(
+                collection_keys[i].clone(),
+                self.subcube.data_bucket(self.hasher.as_ref(), &bytes),
+            );
+        }
+
+        let data_lens: Vec<usize> = (0..self.subcube.num_data())
+            .map(|b| layout.bucket_len(b))
+            .collect();
+
+        for (b, len) in self.subcube.bucket_lens(&data_lens).into_iter().enumerate() {
+            layout.set_len(b, len);
+        }
+
+        Ok(layout)
+    }
+}
@@ -1,25 +1,37 @@
 use rand;
-use rand::Rng;
-use std::collections::HashMap;
+use rand::{ChaChaRng, Rng, SeedableRng};
+use std::collections::{HashMap, HashSet};
 
+use super::block::{Block, BlockError};
 use super::choices::ChoicesCode;
 use super::cuckoo::CuckooCode;
+use super::database::EncodedDatabase;
+use super::descriptor::{CodeDescriptor, CodeParams, DescriptorError, DESCRIPTOR_VERSION};
+use super::hasher::{BucketHasher, CodeId, HasherSpec, KeyedHasher, SaltedHasher, Sha256Hasher};
+use super::matching::{bfs_insertion, first_fit, max_matching};
+use super::padding::{binomial_tail, load_bound, pad, Padding};
 use super::pung::PungCode;
 use super::replication::ReplicationCode;
 use super::sharding::ShardingCode;
-use super::BatchCode;
-use super::Tuple;
+use super::simulation::{estimate_failure, trials_needed};
+use super::stats::{encoding_stats, load_stats, pir_cost, PirParams};
+use super::subcube::{Subcube, SubcubeCode, MAX_DIM};
+use super::view::BucketView;
+use super::{BatchCode, ParamError, PartialSchedule, ScheduleError, Strategy, Tuple, MAX_BUCKETS};
 
 fn do_test(code: &dyn BatchCode<usize, usize>, k: usize, tuples: &[Tuple<usize, usize>]) {
     // server
-    let db: Vec<Vec<Tuple<usize, usize>>> = code.encode(tuples);
+    let db: Vec<Vec<Tuple<usize, usize>>> = code.encode(tuples).unwrap();
     let keys: Vec<usize> = (0..k).collect();
 
     // client
     let schedule: HashMap<usize, Vec<usize>> = code.get_schedule(&keys).unwrap();
 
-    // verify schedule is valid
+    // verify schedule is valid (each bucket is queried at most once)
+    let mut used = HashSet::new();
+
     for (key, buckets) in schedule {
+        assert!(buckets.iter().all(|b| used.insert(*b)));
         assert!(db[buckets[0]].contains(
             &(Tuple {
                 t: (key, key * key),
@@ -84,6 +96,323 @@ fn test_cuckoo() {
     }
 }
 
+#[test]
+fn test_cuckoo_stash() {
+    let tuples: Vec<Tuple<usize, usize>> = (0..500).map(|e| Tuple { t: (e, e * e) }).collect();
+
+    // With 2 choices and no spare buckets, insertion regularly fails. A stash as large as
+    // the batch always absorbs the keys that could not be placed.
+    for k in 12..40 {
+        let code = CuckooCode::with_stash(k, 2, 1.0, k).unwrap();
+        do_test(&code, k, &tuples);
+    }
+
+    let code = CuckooCode::with_stash(16, 2, 1.0, 2).unwrap();
+    let db = (&code as &dyn BatchCode<usize, usize>)
+        .encode(&tuples)
+        .unwrap();
+    assert_eq!(db.len(), 18);
+    assert_eq!(db[16].len(), tuples.len());
+    assert_eq!(db[17].len(), tuples.len());
+}
+
+#[test]
+fn test_cuckoo_evictions() {
+    // Without a stash, a failed insertion reports the evictions made for the batch, which
+    // are also added to the caller's running count
+    let code = CuckooCode::new(32, 2, 1.0);
+    let code = &code as &dyn BatchCode<usize, usize>;
+    let mut rng = ChaChaRng::from_seed(&[1][..]);
+    let mut failures = 0;
+
+    for start in 0..100 {
+        let keys: Vec<usize> = (start * 32..(start + 1) * 32).collect();
+        let mut retries = 7;
+
+        if let Err(e) = code.get_schedule_with_retries(&keys, &mut rng, &mut retries) {
+            assert!(retries > 7);
+            assert_eq!(
+                e,
+                ScheduleError::EvictionsExhausted {
+                    evictions: retries - 7
+                }
+            );
+            failures += 1;
+        }
+    }
+
+    assert!(failures > 0);
+}
+
+#[test]
+fn test_cuckoo_seeded_rng() {
+    // Few spare buckets so that insertion needs many random evictions
+    let code = CuckooCode::with_stash(64, 2, 1.1, 64).unwrap();
+    let code = &code as &dyn BatchCode<usize, usize>;
+    let keys: Vec<usize> = (0..64).collect();
+
+    for seed in 0..10 {
+        let mut rng_1 = ChaChaRng::from_seed(&[seed][..]);
+        let mut rng_2 = ChaChaRng::from_seed(&[seed][..]);
+
+        let schedule_1 = code.get_schedule_with_rng(&keys, &mut rng_1).unwrap();
+        let schedule_2 = code.get_schedule_with_rng(&keys, &mut rng_2).unwrap();
+        assert_eq!(schedule_1, schedule_2);
+    }
+
+    // So is a partial schedule of a batch that does not fit
+    let code = CuckooCode::with_stash(64, 2, 1.1, 0).unwrap();
+    let code = &code as &dyn BatchCode<usize, usize>;
+    let keys: Vec<usize> = (0..80).collect();
+
+    for seed in 0..10 {
+        let mut rng_1 = ChaChaRng::from_seed(&[seed][..]);
+        let mut rng_2 = ChaChaRng::from_seed(&[seed][..]);
+
+        let mut partial_1 = code
+            .get_partial_schedule_with_rng(&keys, &mut rng_1)
+            .unwrap();
+        let mut partial_2 = code
+            .get_partial_schedule_with_rng(&keys, &mut rng_2)
+            .unwrap();
+        partial_1.leftover.sort();
+        partial_2.leftover.sort();
+        assert_eq!(partial_1, partial_2);
+    }
+}
+
+#[test]
+fn test_max_matching() {
+    // First fit would give bucket 0 to key 0 and then fail to place key 1
+    let candidates = vec![vec![0, 1], vec![0], vec![1, 2]];
+    assert_eq!(
+        max_matching(&candidates, 3),
+        vec![Some(1), Some(0), Some(2)]
+    );
+
+    // Three keys competing for two buckets: only two can be matched
+    let candidates = vec![vec![0, 1], vec![0, 1], vec![1, 0]];
+    let matching = max_matching(&candidates, 2);
+    assert_eq!(matching.iter().filter(|m| m.is_some()).count(), 2);
+
+    // Long augmenting path: key i can use buckets i and i + 1, but bucket 0 is taken last
+    let n = 50;
+    let mut candidates: Vec<Vec<usize>> = (0..n).map(|i| vec![i, i + 1]).collect();
+    candidates.push(vec![0]);
+    let matching = max_matching(&candidates, n + 1);
+    let mut used = HashSet::new();
+    assert!(matching.iter().all(|m| used.insert(m.unwrap())));
+}
+
+#[test]
+fn test_bfs_insertion() {
+    let candidates = vec![vec![0, 1], vec![0], vec![1, 2]];
+    assert_eq!(first_fit(&candidates, 3), vec![Some(0), None, Some(1)]);
+    assert_eq!(
+        bfs_insertion(&candidates, 3),
+        vec![Some(1), Some(0), Some(2)]
+    );
+
+    // On random graphs, inserting along shortest eviction paths places as many keys as a
+    // maximum matching does.
+    let mut rng = rand::thread_rng();
+
+    for _ in 0..200 {
+        let num_buckets = 20 + (rng.next_u32() % 20) as usize;
+        let candidates: Vec<Vec<usize>> = (0..20)
+            .map(|_| {
+                (0..2)
+                    .map(|_| rng.next_u32() as usize % num_buckets)
+                    .collect()
+            })
+            .collect();
+
+        let bfs = bfs_insertion(&candidates, num_buckets);
+        let matching = max_matching(&candidates, num_buckets);
+
+        let mut used = HashSet::new();
+        for (key, bucket) in bfs.iter().enumerate() {
+            if let Some(bucket) = bucket {
+                assert!(candidates[key].contains(bucket));
+                assert!(used.insert(*bucket));
+            }
+        }
+
+        assert_eq!(
+            bfs.iter().filter(|b| b.is_some()).count(),
+            matching.iter().filter(|b| b.is_some()).count()
+        );
+    }
+}
+
+#[test]
+fn test_matching_strategy() {
+    let mut rng = rand::thread_rng();
+
+    for strategy in &[Strategy::Matching, Strategy::Bfs] {
+        for i in 0..50 {
+            let k = 12 + i + (rng.next_u32() % 8) as usize;
+            let tuples: Vec<Tuple<usize, usize>> =
+                (0..500 + i).map(|e| Tuple { t: (e, e * e) }).collect();
+
+            let mut code = ShardingCode::new(k);
+            code.set_strategy(*strategy);
+            do_test(&code, k, &tuples);
+
+            let mut code = ChoicesCode::new(k, 2);
+            code.set_strategy(*strategy);
+            do_test(&code, k, &tuples);
+
+            let mut code = CuckooCode::new(k, 3, 1.3);
+            code.set_strategy(*strategy);
+            do_test(&code, k, &tuples);
+        }
+    }
+}
+
+#[test]
+fn test_matching_dominates_heuristic() {
+    // With 2 choices and no spare buckets, random-walk insertion often fails. Matching must
+    // succeed whenever the random walk does.
+    let mut matching = CuckooCode::new(32, 2, 1.0);
+    matching.set_strategy(Strategy::Matching);
+    let heuristic = CuckooCode::new(32, 2, 1.0);
+
+    for start in 0..100 {
+        let keys: Vec<usize> = (start * 32..(start + 1) * 32).collect();
+        let heuristic = (&heuristic as &dyn BatchCode<usize, usize>).get_schedule(&keys);
+        let matching = (&matching as &dyn BatchCode<usize, usize>).get_schedule(&keys);
+
+        if heuristic.is_ok() {
+            assert!(matching.is_ok());
+        }
+    }
+}
+
+#[test]
+fn test_subcube_pung_layout() {
+    // Subcube::new(2, 2) must match the layout of Pung's (n, 9n/4, 4, 9) code
+    let subcube = Subcube::new(2, 2);
+    assert_eq!(subcube.num_buckets(), 9);
+    assert_eq!(subcube.batch_size(), 4);
+
+    assert_eq!(
+        &subcube.plan()[4..],
+        &[
+            vec![0, 1],
+            vec![2, 3],
+            vec![0, 2],
+            vec![1, 3],
+            vec![0, 1, 2, 3]
+        ]
+    );
+
+    assert_eq!(
+        subcube.recovery_sets(0),
+        vec![vec![0], vec![1, 4], vec![2, 6], vec![3, 5, 7, 8]]
+    );
+    assert_eq!(
+        subcube.recovery_sets(1),
+        vec![vec![1], vec![0, 4], vec![3, 7], vec![2, 5, 6, 8]]
+    );
+    assert_eq!(
+        subcube.recovery_sets(2),
+        vec![vec![2], vec![3, 5], vec![0, 6], vec![1, 4, 7, 8]]
+    );
+    assert_eq!(
+        subcube.recovery_sets(3),
+        vec![vec![3], vec![2, 5], vec![1, 7], vec![0, 4, 6, 8]]
+    );
+}
+
+#[test]
+fn test_subcube_recovery() {
+    for &(l, dim) in &[(1, 2), (2, 1), (3, 1), (2, 2), (3, 2), (2, 3), (4, 2)] {
+        let subcube = Subcube::new(l, dim);
+        let rows = 5;
+        let parts: Vec<Vec<Tuple<usize, usize>>> = (0..subcube.num_data())
+            .map(|p| {
+                (p * rows..(p + 1) * rows)
+                    .map(|e| Tuple { t: (e, e * e) })
+                    .collect()
+            })
+            .collect();
+
+        let db = subcube.encode_parts(parts);
+        assert_eq!(db.len(), subcube.num_buckets());
+
+        for data in 0..subcube.num_data() {
+            let sets = subcube.recovery_sets(data);
+            assert_eq!(sets.len(), subcube.batch_size());
+
+            // recovery sets of an entry are disjoint
+            let mut used = HashSet::new();
+            assert!(sets.iter().flatten().all(|b| used.insert(*b)));
+
+            for (r, expected) in db[data].iter().enumerate() {
+                for set in &sets {
+                    let decoded = set
+                        .iter()
+                        .map(|&b| db[b][r].clone())
+                        .reduce(|a, b| a ^ b)
+                        .unwrap();
+                    assert_eq!(&decoded, expected);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_subcube_code() {
+    let mut rng = rand::thread_rng();
+
+    for &(l, dim) in &[(2, 1), (3, 1), (2, 2), (3, 2), (2, 3)] {
+        let code = SubcubeCode::new(l, dim);
+
+        let tuples: Vec<Tuple<usize, usize>> = (0..1008).map(|e| Tuple { t: (e, e * e) }).collect();
+        let db = (&code as &dyn BatchCode<usize, usize>)
+            .encode(&tuples)
+            .unwrap();
+
+        for _ in 0..20 {
+            // Keys packed into a narrow range often share a data sub-bucket
+            let start = rng.next_u32() as usize % 900;
+            let mut keys: Vec<usize> = (start..start + 100).collect();
+            rng.shuffle(&mut keys);
+            keys.truncate(code.subcube().batch_size());
+
+            let schedule = (&code as &dyn BatchCode<usize, usize>)
+                .get_schedule(&keys)
+                .unwrap();
+
+            let mut used = HashSet::new();
+
+            for (key, buckets) in schedule {
+                assert!(buckets.iter().all(|b| used.insert(*b)));
+
+                // XOR the entries at the key's row in every bucket of its recovery set
+                let data = (0..code.subcube().num_data())
+                    .find(|&b| db[b].iter().any(|e| e.t.0 == key))
+                    .unwrap();
+                let row = db[data].iter().position(|e| e.t.0 == key).unwrap();
+                // Sub-buckets may have different lengths; missing rows count as zero
+                let results: Vec<Tuple<usize, usize>> = buckets
+                    .iter()
+                    .map(|&b| db[b].get(row).cloned().unwrap_or(Tuple { t: (0, 0) }))
+                    .collect();
+
+                assert_eq!(
+                    code.decode(&results).unwrap(),
+                    Tuple {
+                        t: (key, key * key)
+                    }
+                );
+            }
+        }
+    }
+}
+
 #[test]
 fn test_pung() {
     let mut rng = rand::thread_rng();
@@ -93,27 +422,1292 @@ fn test_pung() {
         let tuples: Vec<Tuple<usize, usize>> =
             (0..5000 + i).map(|e| Tuple { t: (e, e * e) }).collect();
 
-        let mut code: PungCode<usize> = PungCode::new(k);
+        let code = PungCode::new(k);
 
         // server
-        let db: Vec<Vec<Tuple<usize, usize>>> = code.encode(&tuples);
+        let db: Vec<Vec<Tuple<usize, usize>>> = code.encode(&tuples).unwrap();
         let keys: Vec<usize> = (0..k).collect();
 
-        // Get label mapping
-        let mut labels: HashMap<usize, Vec<usize>> = HashMap::new();
+        // client
+        let schedule = (&code as &dyn BatchCode<usize, usize>)
+            .get_schedule(&keys)
+            .unwrap();
+
+        assert_eq!(schedule.len(), k);
+
+        for (key, buckets) in schedule {
+            // Find the key's row in the data sub-buckets of the bucket it is read from
+            let base = (buckets[0] / 9) * 9;
+            let row = (base..base + 4)
+                .find_map(|b| db[b].iter().position(|e| e.t.0 == key))
+                .unwrap();
+
+            let results: Vec<Tuple<usize, usize>> = buckets
+                .iter()
+                .map(|&b| db[b].get(row).cloned().unwrap_or(Tuple { t: (0, 0) }))
+                .collect();
+
+            assert_eq!(
+                code.decode(&results).unwrap(),
+                Tuple {
+                    t: (key, key * key)
+                }
+            );
+        }
+    }
+}
+
+#[test]
+fn test_schedule_errors() {
+    let code = ReplicationCode::new(4);
+    let schedule = |keys: &[usize]| (&code as &dyn BatchCode<usize, usize>).get_schedule(keys);
+
+    assert_eq!(
+        schedule(&[0, 1, 2, 3, 4]),
+        Err(ScheduleError::TooManyKeys { requested: 5, k: 4 })
+    );
+    assert_eq!(
+        schedule(&[0, 1, 0]),
+        Err(ScheduleError::DuplicateKey { index: 2 })
+    );
+
+    let code = ReplicationCode::new(4);
+    let result = (&code as &dyn BatchCode<usize, usize>).decode(&[]);
+    assert_eq!(
+        result,
+        Err(ScheduleError::InvalidResults {
+            expected: 1,
+            actual: 0
+        })
+    );
+}
+
+#[test]
+fn test_pung_decode() {
+    let code = PungCode::new(8);
+    let a = Tuple { t: (3, 9) };
+    let b = Tuple { t: (5, 25) };
+    let c = Tuple { t: (6, 36) };
+    let d = Tuple { t: (7, 49) };
 
-        for (i, vec_tuple) in db.iter().enumerate() {
-            for tuple in vec_tuple {
-                let entry = labels.entry(tuple.t.0).or_default();
-                entry.push(i);
+    // Every result is XORed in exactly once. Decode used to XOR the first result into itself,
+    // which cancelled it: a single result decoded to zero and [a ^ b, b] decoded to b.
+    assert_eq!(code.decode(std::slice::from_ref(&a)).unwrap(), a);
+    assert_eq!(code.decode(&[a.clone() ^ b.clone(), b.clone()]).unwrap(), a);
+    assert_eq!(
+        code.decode(&[a.clone() ^ b.clone() ^ c.clone() ^ d.clone(), b, c, d])
+            .unwrap(),
+        a
+    );
+    assert_eq!(
+        code.decode(&[a.clone(), a.clone(), a.clone(), a.clone(), a]),
+        Err(ScheduleError::ResultsOutOfRange {
+            min: 1,
+            max: 4,
+            actual: 5
+        })
+    );
+    assert_eq!(
+        code.decode(&Vec::<Tuple<usize, usize>>::new()),
+        Err(ScheduleError::ResultsOutOfRange {
+            min: 1,
+            max: 4,
+            actual: 0
+        })
+    );
+}
+
+#[test]
+fn test_pung_uneven_buckets() {
+    // A parity sub-bucket is as long as its longest source, and rows that only that source has
+    // are copied from it. The original Pung encoder copied such rows from the wrong sub-bucket
+    // (indexing it with the number of sub-buckets instead of its own length).
+    let subcube = Subcube::new(2, 2);
+    let parts: Vec<Vec<Tuple<usize, usize>>> = [3, 2, 2, 1]
+        .iter()
+        .enumerate()
+        .map(|(p, &len)| {
+            (10 * p..10 * p + len)
+                .map(|e| Tuple { t: (e, e * e) })
+                .collect()
+        })
+        .collect();
+
+    let db = subcube.encode_parts(parts.clone());
+    assert_eq!(
+        db[4],
+        vec![
+            parts[0][0].clone() ^ parts[1][0].clone(),
+            parts[0][1].clone() ^ parts[1][1].clone(),
+            parts[0][2].clone()
+        ]
+    );
+
+    // Every row decodes from every recovery set, leaving out the sub-buckets that lack it
+    let code = PungCode::new(8);
+
+    for data in 0..subcube.num_data() {
+        for (row, expected) in db[data].iter().enumerate() {
+            for set in subcube.recovery_sets(data) {
+                let results: Vec<Tuple<usize, usize>> = set
+                    .iter()
+                    .filter_map(|&b| db[b].get(row).cloned())
+                    .collect();
+                assert_eq!(&code.decode(&results).unwrap(), expected);
             }
         }
+    }
+}
 
-        code.set_labels(labels);
+#[test]
+fn test_layout() {
+    let mut rng = rand::thread_rng();
+    let k = 32;
 
-        // client
-        let _schedule = (&code as &dyn BatchCode<usize, usize>)
-            .get_schedule(&keys)
-            .unwrap();
+    // Each code with the size of the batches it retrieves
+    let codes: Vec<(Box<dyn BatchCode<usize, usize>>, usize)> = vec![
+        (Box::new(ReplicationCode::new(k)), k),
+        (Box::new(ShardingCode::new(k)), k),
+        (Box::new(ChoicesCode::new(k, 2)), k),
+        (Box::new(CuckooCode::new(k, 3, 1.5)), k),
+        (Box::new(CuckooCode::with_stash(k, 3, 1.5, 2).unwrap()), k),
+        (Box::new(PungCode::new(k)), k),
+        (Box::new(SubcubeCode::new(2, 3)), 8),
+    ];
+
+    for (code, batch) in &codes {
+        let tuples: Vec<Tuple<usize, usize>> = (0..3000).map(|e| Tuple { t: (e, e * e) }).collect();
+        let db = code.encode(&tuples).unwrap();
+
+        // The client only knows the keys, and not in the server's order
+        let mut collection_keys: Vec<usize> = (0..3000).collect();
+        rng.shuffle(&mut collection_keys);
+        let layout = code.layout(&collection_keys).unwrap();
+
+        assert_eq!(layout.num_buckets(), db.len());
+
+        for (bucket, entries) in db.iter().enumerate() {
+            assert_eq!(layout.bucket_len(bucket), entries.len());
+        }
+
+        let start = rng.next_u32() as usize % 2000;
+        let keys: Vec<usize> = (start..start + *batch).collect();
+        let schedule = code.get_indexed_schedule(&keys, &layout).unwrap();
+
+        for (key, positions) in schedule {
+            let results: Vec<Tuple<usize, usize>> = positions
+                .iter()
+                .map(|&(bucket, row)| db[bucket][row].clone())
+                .collect();
+
+            assert_eq!(
+                code.decode(&results).unwrap(),
+                Tuple {
+                    t: (key, key * key)
+                }
+            );
+        }
+    }
+
+    let code: &dyn BatchCode<usize, usize> = &ReplicationCode::new(4);
+    let layout = code.layout(&[0, 1, 2]).unwrap();
+    assert_eq!(
+        code.get_indexed_schedule(&[1, 5], &layout),
+        Err(ScheduleError::UnknownKey { index: 1 })
+    );
+}
+
+#[test]
+fn test_encode_view() {
+    let k = 32;
+
+    let codes: Vec<Box<dyn BatchCode<usize, usize>>> = vec![
+        Box::new(ReplicationCode::new(k)),
+        Box::new(ShardingCode::new(k)),
+        Box::new(ChoicesCode::new(k, 2)),
+        Box::new(CuckooCode::with_stash(k, 3, 1.5, 2).unwrap()),
+        Box::new(PungCode::new(k)),
+        Box::new(SubcubeCode::new(3, 2)),
+    ];
+
+    let tuples: Vec<Tuple<usize, usize>> = (0..2000).map(|e| Tuple { t: (e, e * e) }).collect();
+
+    for code in &codes {
+        let view = code.encode_view(&tuples).unwrap();
+        let db = code.encode(&tuples).unwrap();
+
+        assert_eq!(view.num_buckets(), db.len());
+
+        for (bucket, entries) in db.iter().enumerate() {
+            assert_eq!(view.bucket_len(bucket), entries.len());
+            assert_eq!(view.row(bucket, entries.len(), &tuples), None);
+
+            for (row, entry) in entries.iter().enumerate() {
+                assert_eq!(view.row(bucket, row, &tuples).as_ref(), Some(entry));
+            }
+        }
     }
+
+    // Replicas refer to the first bucket instead of copying it
+    let view = (&ReplicationCode::new(4) as &dyn BatchCode<usize, usize>)
+        .encode_view(&tuples)
+        .unwrap();
+    assert!(matches!(view.buckets()[0], BucketView::Entries(ref p) if p.len() == tuples.len()));
+    assert!(view.buckets()[1..]
+        .iter()
+        .all(|b| *b == BucketView::Copy(0)));
+
+    // Parity sub-buckets match the ones computed by encode_parts
+    let subcube = Subcube::new(2, 2);
+    let parts: Vec<Vec<usize>> = vec![vec![0, 1, 2], vec![3], vec![], vec![4, 5]];
+    let view = super::view::EncodedView::new(subcube.view_parts(parts.clone(), 0));
+    let encoded = subcube.encode_parts(
+        parts
+            .iter()
+            .map(|p| p.iter().map(|&i| tuples[i].clone()).collect())
+            .collect(),
+    );
+
+    for (bucket, entries) in encoded.iter().enumerate() {
+        assert_eq!(&view.materialize(bucket, &tuples), entries);
+    }
+}
+
+fn do_database_test<C: BatchCode<usize, usize>>(code: C) {
+    let mut rng = rand::thread_rng();
+
+    // The collection holds the even keys below 1000
+    let mut collection: HashMap<usize, usize> = (0..500).map(|e| (2 * e, e)).collect();
+    let tuples: Vec<Tuple<usize, usize>> = collection
+        .iter()
+        .map(|(k, v)| Tuple { t: (*k, *v) })
+        .collect();
+    let mut db = EncodedDatabase::new(code, &tuples).unwrap();
+
+    for _ in 0..50 {
+        let key = rng.next_u32() as usize % 1000;
+        let value = rng.next_u32() as usize;
+        let before = db.buckets().to_vec();
+
+        let dirty = match rng.next_u32() % 3 {
+            0 => {
+                let result = db.insert(Tuple { t: (key, value) });
+
+                if collection.contains_key(&key) {
+                    assert_eq!(result, Err(ScheduleError::KeyExists));
+                    assert_eq!(db.buckets(), &before[..]);
+                    continue;
+                }
+
+                collection.insert(key, value);
+                result.unwrap()
+            }
+            1 => {
+                let result = db.remove(&key);
+
+                if collection.remove(&key).is_none() {
+                    assert_eq!(result, Err(ScheduleError::KeyNotFound));
+                    assert_eq!(db.buckets(), &before[..]);
+                    continue;
+                }
+
+                result.unwrap()
+            }
+            _ => {
+                let result = db.update(Tuple { t: (key, value) });
+
+                if !collection.contains_key(&key) {
+                    assert_eq!(result, Err(ScheduleError::KeyNotFound));
+                    assert_eq!(db.buckets(), &before[..]);
+                    continue;
+                }
+
+                collection.insert(key, value);
+                result.unwrap()
+            }
+        };
+
+        // Same buckets as encoding the whole collection again
+        let tuples: Vec<Tuple<usize, usize>> = collection
+            .iter()
+            .map(|(k, v)| Tuple { t: (*k, *v) })
+            .collect();
+        assert_eq!(db.buckets(), &db.code().encode(&tuples).unwrap()[..]);
+
+        // Every bucket that changed is reported
+        for (bucket, entries) in before.iter().enumerate() {
+            if entries != &db.buckets()[bucket] {
+                assert!(dirty.contains(&bucket));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_encoded_database() {
+    let k = 16;
+
+    do_database_test(ReplicationCode::new(4));
+    do_database_test(ShardingCode::new(k));
+    do_database_test(ChoicesCode::new(k, 2));
+    do_database_test(CuckooCode::with_stash(k, 3, 1.5, 2).unwrap());
+    do_database_test(PungCode::new(k));
+    do_database_test(SubcubeCode::new(2, 2));
+}
+
+#[test]
+fn test_padding() {
+    let k = 32;
+    let n = 5000;
+
+    let codes: Vec<Box<dyn BatchCode<usize, usize>>> = vec![
+        Box::new(ReplicationCode::new(k)),
+        Box::new(ShardingCode::new(k)),
+        Box::new(ChoicesCode::new(k, 2)),
+        Box::new(CuckooCode::new(k, 3, 1.5)),
+        Box::new(PungCode::new(k)),
+        Box::new(SubcubeCode::new(2, 2)),
+    ];
+
+    let tuples: Vec<Tuple<usize, usize>> = (0..n).map(|e| Tuple { t: (e, e * e) }).collect();
+    let dummy = Tuple { t: (usize::MAX, 0) };
+
+    for code in &codes {
+        let mut db = code.encode(&tuples).unwrap();
+        let layout = code.layout(&(0..n).collect::<Vec<usize>>()).unwrap();
+        let bound = code.bucket_bound(n, 40);
+
+        assert!(db.iter().all(|b| b.len() <= bound));
+        assert_eq!(pad(&mut db, Padding::Fixed(bound), &dummy), Ok(bound));
+        assert!(db.iter().all(|b| b.len() == bound));
+
+        // Real entries stay where the layout puts them
+        let keys: Vec<usize> = (100..104).collect();
+        let schedule = code.get_indexed_schedule(&keys, &layout).unwrap();
+
+        for (key, positions) in schedule {
+            let results: Vec<Tuple<usize, usize>> = positions
+                .iter()
+                .map(|&(bucket, row)| db[bucket][row].clone())
+                .collect();
+
+            assert_eq!(code.decode(&results).unwrap().t.0, key);
+        }
+    }
+
+    let mut db = vec![vec![1], vec![1, 2, 3], vec![]];
+    assert_eq!(pad(&mut db, Padding::Max, &0), Ok(3));
+    assert_eq!(db, vec![vec![1, 0, 0], vec![1, 2, 3], vec![0, 0, 0]]);
+    assert_eq!(
+        pad(&mut db, Padding::Fixed(2), &0),
+        Err(ScheduleError::BucketOverflow { bucket: 0, len: 2 })
+    );
+
+    // The bound grows with the security parameter and stays above the mean
+    assert_eq!(load_bound(0, 10, 20), 0);
+    assert!(load_bound(10000, 100, 20) > 100);
+    assert!(load_bound(10000, 100, 20) < load_bound(10000, 100, 40));
+    assert!(load_bound(10, 100, 40) <= 10);
+}
+
+#[test]
+fn test_stats() {
+    let load = load_stats(&[2, 4, 4, 4, 5, 5, 7, 9]);
+    assert_eq!((load.min, load.max), (2, 9));
+    assert_eq!(load.mean, 5.0);
+    assert_eq!(load.stddev, 2.0);
+    assert_eq!(load.histogram[&4], 3);
+    assert_eq!(load.histogram.values().sum::<usize>(), 8);
+
+    let tuples: Vec<Tuple<usize, usize>> = (0..1000).map(|e| Tuple { t: (e, e * e) }).collect();
+
+    let code: &dyn BatchCode<usize, usize> = &ReplicationCode::new(4);
+    let stats = encoding_stats(&tuples, &code.encode(&tuples).unwrap()).unwrap();
+    assert_eq!(stats.stored_entries, 4000);
+    assert_eq!(stats.blowup, 4.0);
+    assert_eq!((stats.replication.min, stats.replication.max), (4, 4));
+
+    let code: &dyn BatchCode<usize, usize> = &CuckooCode::new(16, 3, 1.5);
+    let stats = encoding_stats(&tuples, &code.encode(&tuples).unwrap()).unwrap();
+    assert_eq!(stats.bucket_lens.len(), 24);
+    assert_eq!(stats.blowup, 3.0);
+    assert_eq!(stats.load.mean, 125.0);
+    assert_eq!((stats.replication.min, stats.replication.max), (3, 3));
+
+    // Parity entries that combine entries are not copies: each entry is in a data sub-bucket of
+    // 2 buckets (and in parity rows where no other sub-bucket has its row). Values are
+    // scrambled (and key 0 left out) so that no XOR of entries looks like an entry.
+    let tuples: Vec<Tuple<usize, usize>> = (1..1001usize)
+        .map(|e| Tuple {
+            t: (e, e.wrapping_mul(0x9e37_79b9_7f4a_7c15)),
+        })
+        .collect();
+    let code: &dyn BatchCode<usize, usize> = &PungCode::new(16);
+    let stats = encoding_stats(&tuples, &code.encode(&tuples).unwrap()).unwrap();
+    assert_eq!(stats.replication.min, 2);
+    assert!(stats.blowup > 2.0);
+
+    // 288-byte elements with 20-bit plaintexts take 116 coefficients, so 17 fit in a
+    // plaintext. 2^16 elements then take 3856 plaintexts, padded to 63^2.
+    let params = PirParams {
+        poly_degree: 2048,
+        log_plain_mod: 20,
+        d: 2,
+    };
+    let cost = pir_cost(1 << 16, 288, params).unwrap();
+    let ciphertext = 2 * 2048 * 54 / 8;
+    assert_eq!(cost.server_memory, 63 * 63 * 2048 * 8);
+    assert_eq!(cost.query_size, 2 * ciphertext);
+    assert_eq!(cost.reply_size, 6 * ciphertext);
+    assert_eq!(cost.server_ops, 63 * 63 + 63 * 6);
+
+    assert_eq!(pir_cost(10, 100_000, params), None);
+    assert_eq!(
+        pir_cost(
+            10,
+            288,
+            PirParams {
+                poly_degree: 3000,
+                ..params
+            }
+        ),
+        None
+    );
+
+    let total = stats.pir_cost(288, params).unwrap();
+    assert_eq!(total.reply_size, 16 * 9 * 6 * ciphertext);
+}
+
+#[test]
+fn test_simulation() {
+    let mut rng = ChaChaRng::from_seed(&[7][..]);
+
+    // Replication never fails or retries
+    let code: &dyn BatchCode<usize, usize> = &ReplicationCode::new(16);
+    let estimate =
+        estimate_failure(code, 16, 200, |rng| rng.next_u64() as usize, &mut rng).unwrap();
+    assert_eq!((estimate.trials, estimate.failures), (200, 0));
+    assert_eq!(estimate.retries.max, 0);
+
+    let (low, high) = estimate.interval(1.96);
+    assert_eq!(low, 0.0);
+    assert!(high > 0.0 && high < 0.02);
+
+    // With no spare buckets, cuckoo insertion evicts often and sometimes fails
+    let code: &dyn BatchCode<usize, usize> = &CuckooCode::new(32, 2, 1.0);
+    let estimate =
+        estimate_failure(code, 32, 200, |rng| rng.next_u64() as usize, &mut rng).unwrap();
+    assert!(estimate.failures > 0);
+    assert!(estimate.retries.mean > 0.0);
+
+    let (low, high) = estimate.interval(1.96);
+    assert!(low <= estimate.failure_rate() && estimate.failure_rate() <= high);
+
+    // A maximum matching never retries
+    let mut matching = CuckooCode::new(32, 2, 1.0);
+    matching.set_strategy(Strategy::Matching);
+    let code: &dyn BatchCode<usize, usize> = &matching;
+    let exact = estimate_failure(code, 32, 200, |rng| rng.next_u64() as usize, &mut rng).unwrap();
+    assert_eq!(exact.retries.max, 0);
+
+    // Batches larger than k are not failures of the code
+    assert!(matches!(
+        estimate_failure(code, 33, 1, |rng| rng.next_u64() as usize, &mut rng),
+        Err(ScheduleError::TooManyKeys { .. })
+    ));
+
+    // z^2 / (n + z^2) <= 2^-20
+    let n = trials_needed(2f64.powi(-20), 1.96);
+    assert!(1.96f64.powi(2) / (n as f64 + 1.96f64.powi(2)) <= 2f64.powi(-20));
+    assert!(1.96f64.powi(2) / (n as f64 - 1.0 + 1.96f64.powi(2)) > 2f64.powi(-20));
+}
+
+#[test]
+fn test_failure_bound() {
+    // Pr[Bin(4, 1/2) >= 3] = 5/16
+    assert!((binomial_tail(4, 0.5, 3) - 5.0 / 16.0).abs() < 1e-12);
+    assert_eq!(binomial_tail(4, 0.5, 0), 1.0);
+    assert_eq!(binomial_tail(4, 0.5, 5), 0.0);
+    assert!(binomial_tail(10_000, 0.001, 40) < 1e-12);
+
+    let k = 64;
+    let replication: &dyn BatchCode<usize, usize> = &ReplicationCode::new(k);
+    let sharding: &dyn BatchCode<usize, usize> = &ShardingCode::new(k);
+    let pung: &dyn BatchCode<usize, usize> = &PungCode::new(k);
+    let heuristic: &dyn BatchCode<usize, usize> = &CuckooCode::new(k, 3, 1.5);
+
+    assert_eq!(replication.failure_bound(), Some(0.0));
+    assert!(sharding.failure_bound().unwrap() < 1e-5);
+    assert_eq!(pung.failure_bound(), None);
+    assert_eq!(heuristic.failure_bound(), None);
+
+    // More buckets or more choices lower the bound of a maximum matching
+    let bound = |d: usize, r: f64| {
+        let mut code = CuckooCode::new(k, d, r);
+        code.set_strategy(Strategy::Matching);
+        (&code as &dyn BatchCode<usize, usize>)
+            .failure_bound()
+            .unwrap()
+    };
+
+    assert!(bound(3, 2.0) < bound(3, 1.5));
+    assert!(bound(4, 1.5) < bound(3, 1.5));
+    assert!(bound(3, 2.0) < 1e-9);
+
+    // The bound is consistent with simulated failures
+    let mut code = CuckooCode::new(k, 3, 1.25);
+    code.set_strategy(Strategy::Matching);
+    let code: &dyn BatchCode<usize, usize> = &code;
+    let mut rng = ChaChaRng::from_seed(&[3][..]);
+    let estimate = estimate_failure(code, k, 500, |rng| rng.next_u64() as usize, &mut rng).unwrap();
+    assert!(estimate.interval(1.96).0 <= code.failure_bound().unwrap());
+}
+
+#[test]
+fn test_hasher() {
+    // SipHash-2-4 test vector (key 00..0f, message 00..0e), written in two pieces
+    let mut key = [0u8; 16];
+    key.iter_mut().enumerate().for_each(|(i, b)| *b = i as u8);
+    let message: Vec<u8> = (0..15).collect();
+    let keyed = KeyedHasher::new(key);
+    assert_eq!(
+        keyed.hash(&[&message[..5], &message[5..]]),
+        0xa129_ca61_49be_45e5
+    );
+
+    // A hasher built from a published spec places keys in the same buckets
+    let mut rng = ChaChaRng::from_seed(&[5][..]);
+    let keyed = KeyedHasher::random(&mut rng);
+    let spec: HasherSpec =
+        bincode::deserialize(&bincode::serialize(&keyed.spec()).unwrap()).unwrap();
+    let place = |hasher: &dyn BucketHasher| -> Vec<usize> {
+        (0..100u8)
+            .map(|i| hasher.hash_and_mod(1, 0, &[i], 1000))
+            .collect()
+    };
+
+    assert_eq!(place(&keyed), place(spec.build().as_ref()));
+    assert_ne!(place(&keyed), place(&KeyedHasher::random(&mut rng)));
+    assert_ne!(place(&keyed), place(&Sha256Hasher));
+    assert_eq!(HasherSpec::default().build().spec(), HasherSpec::Sha256);
+
+    // Every code that hashes keys works with the keyed hasher
+    let k = 32;
+    let n = 1000;
+    let tuples: Vec<Tuple<usize, usize>> = (0..n).map(|e| Tuple { t: (e, e * e) }).collect();
+
+    let mut sharding = ShardingCode::new(k);
+    sharding.set_hasher(spec.build());
+    let mut choices = ChoicesCode::new(k, 2);
+    choices.set_hasher(spec.build());
+    let mut cuckoo = CuckooCode::new(k, 3, 1.5);
+    cuckoo.set_hasher(spec.build());
+    let mut pung = PungCode::new(k);
+    pung.set_hasher(spec.build());
+    let mut subcube = SubcubeCode::new(2, 2);
+    subcube.set_hasher(spec.build());
+
+    let codes: Vec<(Box<dyn BatchCode<usize, usize>>, usize)> = vec![
+        (Box::new(sharding), k),
+        (Box::new(choices), k),
+        (Box::new(cuckoo), k),
+        (Box::new(pung), k),
+        (Box::new(subcube), 4),
+    ];
+
+    for (code, batch) in &codes {
+        let db = code.encode(&tuples).unwrap();
+        let layout = code.layout(&(0..n).collect::<Vec<usize>>()).unwrap();
+        let keys: Vec<usize> = (100..100 + batch).collect();
+
+        for (key, positions) in code.get_indexed_schedule(&keys, &layout).unwrap() {
+            let results: Vec<Tuple<usize, usize>> = positions
+                .iter()
+                .map(|&(bucket, row)| db[bucket][row].clone())
+                .collect();
+
+            assert_eq!(code.decode(&results).unwrap().t, (key, key * key));
+        }
+    }
+
+    // Client and server must agree on the hasher: the default one places keys elsewhere
+    let server = codes[2].0.layout(&(0..n).collect::<Vec<usize>>()).unwrap();
+    let client = (&CuckooCode::new(k, 3, 1.5) as &dyn BatchCode<usize, usize>)
+        .layout(&(0..n).collect::<Vec<usize>>())
+        .unwrap();
+    let lens = |layout: &super::layout::Layout<usize>| -> Vec<usize> {
+        (0..layout.num_buckets())
+            .map(|b| layout.bucket_len(b))
+            .collect()
+    };
+
+    assert_ne!(lens(&server), lens(&client));
+}
+
+#[test]
+fn test_salted_hasher() {
+    let key = [7u8; 20];
+    let modulus = 1_000_003;
+
+    // The legacy hasher cannot tell (1, 11) from (11, 1); the versioned one can
+    let salted = SaltedHasher::new([0u8; 32], CodeId::Cuckoo);
+    assert_eq!(
+        Sha256Hasher.hash_and_mod(1, 11, &key, modulus),
+        Sha256Hasher.hash_and_mod(11, 1, &key, modulus)
+    );
+    assert_ne!(
+        salted.hash_and_mod(1, 11, &key, modulus),
+        salted.hash_and_mod(11, 1, &key, modulus)
+    );
+
+    // The salt and the code identifier select different hash functions
+    let place = |hasher: &dyn BucketHasher| -> Vec<usize> {
+        (0..100u8)
+            .map(|i| hasher.hash_and_mod(0, 0, &[i], modulus))
+            .collect()
+    };
+
+    let mut rng = ChaChaRng::from_seed(&[6][..]);
+    let other_salt = SaltedHasher::random(CodeId::Cuckoo, &mut rng);
+    let other_code = SaltedHasher::new([0u8; 32], CodeId::Choices);
+    assert_ne!(place(&salted), place(&other_salt));
+    assert_ne!(place(&salted), place(&other_code));
+
+    // A spec with the same version rebuilds the hasher; other versions are not supported
+    let spec: HasherSpec =
+        bincode::deserialize(&bincode::serialize(&other_salt.spec()).unwrap()).unwrap();
+    let salt_of = |spec: HasherSpec| match spec {
+        HasherSpec::Salted { salt, .. } => salt,
+        _ => panic!("Not a salted hasher"),
+    };
+    assert!(spec.is_supported());
+    assert_eq!(place(&other_salt), place(spec.build().as_ref()));
+    assert_eq!(
+        place(spec.for_code(CodeId::Choices).build().as_ref()),
+        place(&SaltedHasher::new(salt_of(spec), CodeId::Choices))
+    );
+    assert_eq!(
+        HasherSpec::Sha256.for_code(CodeId::Choices),
+        HasherSpec::Sha256
+    );
+
+    let future = HasherSpec::Salted {
+        version: SaltedHasher::VERSION + 1,
+        salt: [0u8; 32],
+        code: CodeId::Cuckoo,
+    };
+    assert!(!future.is_supported());
+
+    // With a modulus of 3 * 2^62, a quarter of the words are rejected. Without rejection,
+    // buckets below 2^62 would be twice as likely as the others (1/2 instead of 1/3).
+    let modulus = 3usize << 62;
+    let low = (0..2000u32)
+        .map(|i| salted.hash_and_mod(0, 0, &i.to_le_bytes(), modulus))
+        .filter(|&b| {
+            assert!(b < modulus);
+            b < 1 << 62
+        })
+        .count();
+    assert!(low > 580 && low < 750);
+
+    // Codes place keys consistently with the salted hasher
+    let k = 16;
+    let tuples: Vec<Tuple<usize, usize>> = (0..500).map(|e| Tuple { t: (e, e + 1) }).collect();
+    let mut cuckoo = CuckooCode::new(k, 3, 1.5);
+    cuckoo.set_hasher(other_salt.spec().build());
+    let code: &dyn BatchCode<usize, usize> = &cuckoo;
+    let db = code.encode(&tuples).unwrap();
+    let keys: Vec<usize> = (200..200 + k).collect();
+    let schedule = code.get_schedule(&keys).unwrap();
+
+    for (key, buckets) in schedule {
+        let results: Vec<Tuple<usize, usize>> = buckets
+            .iter()
+            .map(|&b| db[b].iter().find(|t| t.t.0 == key).unwrap().clone())
+            .collect();
+        assert_eq!(code.decode(&results).unwrap().t, (key, key + 1));
+    }
+}
+
+#[test]
+fn test_descriptor() {
+    let n = 1000;
+    let tuples: Vec<Tuple<usize, usize>> = (0..n).map(|e| Tuple { t: (e, 3 * e) }).collect();
+    let collection_keys: Vec<usize> = (0..n).collect();
+    let mut rng = ChaChaRng::from_seed(&[7][..]);
+    let hasher = SaltedHasher::random(CodeId::Cuckoo, &mut rng).spec();
+
+    let all = [
+        CodeParams::Replication { k: 8 },
+        CodeParams::Sharding {
+            k: 32,
+            replicas: 5,
+            strategy: Strategy::Matching,
+        },
+        CodeParams::Choices {
+            k: 32,
+            d: 2,
+            replicas: 4,
+            strategy: Strategy::Heuristic,
+        },
+        CodeParams::Cuckoo {
+            k: 32,
+            d: 3,
+            r: 1.5,
+            stash: 1,
+            strategy: Strategy::Bfs,
+        },
+        CodeParams::Pung { k: 32 },
+        CodeParams::Subcube { l: 2, dim: 2 },
+    ];
+
+    for params in &all {
+        // The server encodes with its code and publishes the descriptor
+        let hasher = hasher.for_code(params.id());
+        let server: Box<dyn BatchCode<usize, usize>> = params.build(hasher);
+        let db = server.encode(&tuples).unwrap();
+        let descriptor = CodeDescriptor::new(*params, hasher, db.iter().map(|b| b.len()).collect());
+        let published = bincode::serialize(&descriptor).unwrap();
+
+        // The client's code computes the same layout
+        let descriptor: CodeDescriptor = bincode::deserialize(&published).unwrap();
+        let client = descriptor.code::<usize, usize>().unwrap();
+        let layout = client.layout(&collection_keys).unwrap();
+
+        for (bucket, size) in descriptor.bucket_sizes.iter().enumerate() {
+            assert_eq!(layout.bucket_len(bucket), *size);
+        }
+
+        for (key, positions) in client.get_indexed_schedule(&[5, 50, 500], &layout).unwrap() {
+            let results: Vec<Tuple<usize, usize>> = positions
+                .iter()
+                .map(|&(bucket, row)| db[bucket][row].clone())
+                .collect();
+
+            assert_eq!(client.decode(&results).unwrap().t, (key, 3 * key));
+        }
+    }
+
+    // Construction fails on versions this crate does not implement
+    let good = CodeDescriptor::new(all[3], hasher, vec![0; 49]);
+    assert!(good.code::<usize, usize>().is_ok());
+
+    let mut bad = good.clone();
+    bad.version = DESCRIPTOR_VERSION + 1;
+    assert_eq!(
+        bad.code::<usize, usize>().err(),
+        Some(DescriptorError::UnsupportedVersion {
+            version: DESCRIPTOR_VERSION + 1
+        })
+    );
+
+    let mut bad = good.clone();
+    bad.hasher = HasherSpec::Salted {
+        version: SaltedHasher::VERSION + 1,
+        salt: [0u8; 32],
+        code: CodeId::Cuckoo,
+    };
+    assert_eq!(
+        bad.code::<usize, usize>().err(),
+        Some(DescriptorError::UnsupportedHasher {
+            version: SaltedHasher::VERSION + 1
+        })
+    );
+
+    // ... and on parameters or bucket sizes that do not match a code
+    let mut bad = good.clone();
+    bad.bucket_sizes.pop();
+    assert_eq!(
+        bad.code::<usize, usize>().err(),
+        Some(DescriptorError::BucketCountMismatch {
+            expected: 49,
+            actual: 48
+        })
+    );
+
+    // Replicas must have the size of the bucket they copy
+    let params = all[1];
+    let code: Box<dyn BatchCode<usize, usize>> = params.build(hasher.for_code(params.id()));
+    let view = code.encode_view(&tuples).unwrap();
+    let mut sizes: Vec<usize> = (0..view.num_buckets())
+        .map(|b| view.bucket_len(b))
+        .collect();
+    let copy = view
+        .buckets()
+        .iter()
+        .position(|b| matches!(b, BucketView::Copy(_)))
+        .unwrap();
+
+    let mut bad = CodeDescriptor::new(params, hasher.for_code(params.id()), sizes.clone());
+    assert!(bad.code::<usize, usize>().is_ok());
+
+    sizes[copy] += 1;
+    bad.bucket_sizes = sizes.clone();
+    assert_eq!(
+        bad.code::<usize, usize>().err(),
+        Some(DescriptorError::BucketSizeMismatch {
+            bucket: copy,
+            expected: sizes[copy] - 1,
+            actual: sizes[copy]
+        })
+    );
+
+    // A salted hasher must be bound to the descriptor's code
+    let mut bad = good.clone();
+    bad.hasher = hasher.for_code(CodeId::Choices);
+    assert_eq!(
+        bad.code::<usize, usize>().err(),
+        Some(DescriptorError::HasherCodeMismatch {
+            expected: CodeId::Cuckoo,
+            actual: CodeId::Choices
+        })
+    );
+
+    let invalid = [
+        CodeParams::Pung { k: 4 },
+        CodeParams::Sharding {
+            k: 32,
+            replicas: 32,
+            strategy: Strategy::Heuristic,
+        },
+        CodeParams::Choices {
+            k: 32,
+            d: 0,
+            replicas: 4,
+            strategy: Strategy::Heuristic,
+        },
+        CodeParams::Cuckoo {
+            k: 32,
+            d: 3,
+            r: f64::NAN,
+            stash: 0,
+            strategy: Strategy::Heuristic,
+        },
+        // Keys need d distinct buckets out of ceil(k * r) = 2
+        CodeParams::Cuckoo {
+            k: 32,
+            d: 3,
+            r: 0.05,
+            stash: 0,
+            strategy: Strategy::Heuristic,
+        },
+        CodeParams::Cuckoo {
+            k: 32,
+            d: 3,
+            r: 1e300,
+            stash: 0,
+            strategy: Strategy::Heuristic,
+        },
+        CodeParams::Cuckoo {
+            k: 32,
+            d: 3,
+            r: 1.5,
+            stash: MAX_BUCKETS,
+            strategy: Strategy::Heuristic,
+        },
+        CodeParams::Subcube {
+            l: 2,
+            dim: usize::MAX,
+        },
+        CodeParams::Subcube { l: 1, dim: 40 },
+        CodeParams::Subcube {
+            l: 2,
+            dim: MAX_DIM + 1,
+        },
+        CodeParams::Replication { k: usize::MAX },
+        CodeParams::Sharding {
+            k: 1 << 40,
+            replicas: 2,
+            strategy: Strategy::Heuristic,
+        },
+        CodeParams::Choices {
+            k: MAX_BUCKETS,
+            d: 2,
+            replicas: 2,
+            strategy: Strategy::Heuristic,
+        },
+        CodeParams::Pung { k: usize::MAX / 2 },
+        CodeParams::Pung {
+            k: MAX_BUCKETS / 9 + 1,
+        },
+        CodeParams::Subcube {
+            l: MAX_BUCKETS,
+            dim: 1,
+        },
+    ];
+
+    for params in &invalid {
+        let descriptor = CodeDescriptor::new(*params, HasherSpec::Sha256, vec![]);
+
+        match descriptor.code::<usize, usize>() {
+            Err(DescriptorError::InvalidParams(_)) => (),
+            other => panic!("{:?} was not rejected: {:?}", params, other.err()),
+        }
+    }
+
+    // The constructor rejects the same parameters
+    assert!(CuckooCode::with_stash(32, 3, 0.05, 0).is_err());
+    assert!(CuckooCode::with_stash(32, 0, 1.5, 0).is_err());
+    assert!(CuckooCode::with_stash(0, 3, 1.5, 0).is_err());
+    assert!(CuckooCode::with_stash(32, 3, f64::INFINITY, 0).is_err());
+    assert!(CuckooCode::with_stash(32, 3, 0.1, 0).is_ok());
+}
+
+#[test]
+fn test_replicas() {
+    let target = 2f64.powi(-20);
+
+    // The fewest replicas that meet the target: one fewer does not
+    let sharding = ShardingCode::with_failure(64, target).unwrap();
+    let bound = |code: &dyn BatchCode<usize, usize>| code.failure_bound().unwrap();
+    let fewer = ShardingCode::with_replicas(64, sharding.replicas() - 1).unwrap();
+    assert!(bound(&sharding) <= target);
+    assert!(bound(&fewer) > target);
+
+    let choices = ChoicesCode::with_failure(64, 2, target).unwrap();
+    let fewer = ChoicesCode::with_replicas(64, 2, choices.replicas() - 1).unwrap();
+    assert!(bound(&choices) <= target);
+    assert!(bound(&fewer) > target);
+
+    // The default constructors keep using the retry bound
+    assert_eq!(ShardingCode::new(64).replicas(), retry_bound!(64));
+    assert_eq!(ChoicesCode::new(64, 2).replicas(), retry_bound!(64, 2));
+
+    // Configurations that are no better than replication are rejected
+    assert_eq!(
+        ShardingCode::with_failure(4, 1e-9).err(),
+        Some(ParamError::WorseThanReplication { replicas: 4, k: 4 })
+    );
+    assert_eq!(
+        ChoicesCode::with_replicas(16, 2, 16).err(),
+        Some(ParamError::WorseThanReplication {
+            replicas: 16,
+            k: 16
+        })
+    );
+
+    // ... and so are invalid parameters
+    for result in [
+        ShardingCode::with_replicas(0, 1).err(),
+        ShardingCode::with_replicas(16, 0).err(),
+        ShardingCode::with_failure(16, 0.0).err(),
+        ShardingCode::with_failure(16, 1.0).err(),
+        ShardingCode::with_replicas(1 << 40, 2).err(),
+    ] {
+        assert!(matches!(result, Some(ParamError::InvalidParam { .. })));
+    }
+
+    for result in [
+        ChoicesCode::with_replicas(16, 0, 3).err(),
+        ChoicesCode::with_replicas(16, 17, 3).err(),
+        ChoicesCode::with_failure(16, 2, f64::NAN).err(),
+        ChoicesCode::with_replicas(MAX_BUCKETS, 2, 2).err(),
+    ] {
+        assert!(matches!(result, Some(ParamError::InvalidParam { .. })));
+    }
+
+    // Codes with an explicit replica count encode and schedule as usual
+    let k = 16;
+    let tuples: Vec<Tuple<usize, usize>> = (0..500).map(|e| Tuple { t: (e, e + 7) }).collect();
+    let sharding = ShardingCode::with_replicas(k, 6).unwrap();
+    let choices = ChoicesCode::with_replicas(k, 3, 5).unwrap();
+    let codes: [(&dyn BatchCode<usize, usize>, usize); 2] = [(&sharding, 6), (&choices, 5)];
+
+    for (code, replicas) in codes {
+        let db = code.encode(&tuples).unwrap();
+        assert_eq!(db.len(), k * replicas);
+
+        let keys: Vec<usize> = (0..k).map(|i| i * 31).collect();
+        let layout = code.layout(&(0..500).collect::<Vec<usize>>()).unwrap();
+
+        for (key, positions) in code.get_indexed_schedule(&keys, &layout).unwrap() {
+            let (bucket, row) = positions[0];
+            assert_eq!(db[bucket][row].t, (key, key + 7));
+        }
+    }
+}
+
+// Checks that a partial schedule covers every key once, uses each bucket at most once, and
+// retrieves the right entries
+fn check_partial(
+    code: &dyn BatchCode<usize, usize>,
+    db: &[Vec<Tuple<usize, usize>>],
+    keys: &[usize],
+    partial: &PartialSchedule<usize>,
+) {
+    let mut covered: Vec<usize> = partial.schedule.keys().cloned().collect();
+    covered.extend(partial.leftover.iter().cloned());
+    covered.sort_unstable();
+    let mut expected = keys.to_vec();
+    expected.sort_unstable();
+    assert_eq!(covered, expected);
+
+    let mut used = HashSet::new();
+
+    for (key, buckets) in &partial.schedule {
+        let mut results = Vec::new();
+
+        for &bucket in buckets {
+            assert!(used.insert(bucket));
+            results.extend(db[bucket].iter().find(|t| t.t.0 == *key).cloned());
+        }
+
+        // Parity buckets (of PungCode and SubcubeCode) do not hold the key as such
+        if results.len() == buckets.len() {
+            assert_eq!(code.decode(&results).unwrap().t, (*key, *key + 1));
+        }
+    }
+}
+
+#[test]
+fn test_partial_schedule() {
+    let n = 2000;
+    let tuples: Vec<Tuple<usize, usize>> = (0..n).map(|e| Tuple { t: (e, e + 1) }).collect();
+
+    // With a single replica, colliding keys cannot all be scheduled
+    let k = 32;
+    let keys: Vec<usize> = (0..k).map(|i| i * 17).collect();
+    let sharding = ShardingCode::with_replicas(k, 1).unwrap();
+    let code: &dyn BatchCode<usize, usize> = &sharding;
+    let db = code.encode(&tuples).unwrap();
+
+    assert!(matches!(
+        code.get_schedule(&keys),
+        Err(ScheduleError::RetryBoundExhausted { .. })
+    ));
+
+    let partial = code.get_partial_schedule(&keys).unwrap();
+    assert!(!partial.leftover.is_empty());
+    check_partial(code, &db, &keys, &partial);
+
+    // Leftovers can be fetched in later batches
+    let mut rest = partial.leftover;
+    let mut rounds = 1;
+
+    while !rest.is_empty() {
+        let partial = code.get_partial_schedule(&rest).unwrap();
+        assert!(!partial.schedule.is_empty());
+        check_partial(code, &db, &rest, &partial);
+        rest = partial.leftover;
+        rounds += 1;
+    }
+
+    assert!(rounds > 1);
+
+    // Every code schedules at most k keys of a larger batch and leaves the rest over
+    let mut sharding = ShardingCode::new(k);
+    sharding.set_strategy(Strategy::Matching);
+    let mut choices = ChoicesCode::new(k, 2);
+    choices.set_strategy(Strategy::Bfs);
+    let mut cuckoo = CuckooCode::new(k, 2, 1.0);
+    cuckoo.set_strategy(Strategy::Matching);
+
+    let codes: Vec<(Box<dyn BatchCode<usize, usize>>, usize)> = vec![
+        (Box::new(ReplicationCode::new(k)), k),
+        (Box::new(ShardingCode::new(k)), k),
+        (Box::new(sharding), k),
+        (Box::new(ChoicesCode::new(k, 2)), k),
+        (Box::new(choices), k),
+        (Box::new(CuckooCode::new(k, 2, 1.0)), k),
+        (Box::new(CuckooCode::with_stash(k, 2, 1.0, 1).unwrap()), k),
+        (Box::new(cuckoo), k),
+        (Box::new(PungCode::new(k)), k),
+        (Box::new(SubcubeCode::new(2, 2)), 4),
+    ];
+
+    for (code, batch) in &codes {
+        let db = code.encode(&tuples).unwrap();
+        let keys: Vec<usize> = (0..2 * batch).map(|i| i * 13 + 5).collect();
+
+        let partial = code.get_partial_schedule(&keys).unwrap();
+        assert!(partial.schedule.len() <= *batch);
+        assert!(partial.leftover.len() >= *batch);
+        check_partial(code.as_ref(), &db, &keys, &partial);
+
+        // A batch that can be scheduled in full has no leftovers
+        let full = code.get_partial_schedule(&keys[..1]).unwrap();
+        assert!(full.leftover.is_empty());
+
+        assert_eq!(
+            code.get_partial_schedule(&[1, 2, 1]).err(),
+            Some(ScheduleError::DuplicateKey { index: 2 })
+        );
+    }
+}
+
+fn do_key_test<K, V>(code: &dyn BatchCode<K, V>, tuples: &[Tuple<K, V>], keys: &[K])
+where
+    K: Clone + serde::Serialize + Eq + std::hash::Hash + std::fmt::Debug,
+    V: Clone + serde::Serialize + PartialEq + std::fmt::Debug,
+{
+    let db = code.encode(tuples).unwrap();
+    let collection_keys: Vec<K> = tuples.iter().map(|e| e.t.0.clone()).collect();
+    let layout = code.layout(&collection_keys).unwrap();
+
+    for (key, positions) in code.get_indexed_schedule(keys, &layout).unwrap() {
+        let results: Vec<Tuple<K, V>> = positions
+            .iter()
+            .map(|(bucket, row)| db[*bucket][*row].clone())
+            .collect();
+        let expected = tuples.iter().find(|e| e.t.0 == key).unwrap();
+        assert_eq!(code.decode(&results).unwrap().t, expected.t);
+    }
+
+    let partial = code.get_partial_schedule(&collection_keys).unwrap();
+    assert_eq!(
+        partial.schedule.len() + partial.leftover.len(),
+        collection_keys.len()
+    );
+}
+
+#[test]
+fn test_string_keys() {
+    let k = 16;
+    let n = 500;
+
+    // Usernames mapped to public key fingerprints
+    let users: Vec<Tuple<String, Vec<u8>>> = (0..n)
+        .map(|i| Tuple {
+            t: (format!("user{i}"), vec![i as u8; 32]),
+        })
+        .collect();
+    let usernames: Vec<String> = (0..k).map(|i| format!("user{}", i * 29)).collect();
+
+    let mut sharding = ShardingCode::new(k);
+    sharding.set_strategy(Strategy::Matching);
+
+    let codes: Vec<Box<dyn BatchCode<String, Vec<u8>>>> = vec![
+        Box::new(ReplicationCode::new(k)),
+        Box::new(sharding),
+        Box::new(ChoicesCode::new(k, 2)),
+        Box::new(CuckooCode::with_stash(k, 3, 1.5, 1).unwrap()),
+    ];
+
+    for code in &codes {
+        do_key_test(code.as_ref(), &users, &usernames);
+    }
+
+    // Fingerprints mapped to usernames
+    let fingerprints: Vec<Tuple<Vec<u8>, String>> = (0..n)
+        .map(|i| Tuple {
+            t: ((i as u32).to_le_bytes().repeat(4), format!("user{i}")),
+        })
+        .collect();
+    let wanted: Vec<Vec<u8>> = (0..k).map(|i| fingerprints[i * 31].t.0.clone()).collect();
+    do_key_test(&CuckooCode::new(k, 3, 1.5), &fingerprints, &wanted);
+
+    // Descriptors build systematic codes for such keys, but not codes that need XOR
+    let hasher = HasherSpec::default();
+    let params = CodeParams::Cuckoo {
+        k,
+        d: 3,
+        r: 1.5,
+        stash: 0,
+        strategy: Strategy::Bfs,
+    };
+    let server = params.build_systematic::<String, Vec<u8>>(hasher).unwrap();
+    let sizes = server
+        .encode(&users)
+        .unwrap()
+        .iter()
+        .map(|b| b.len())
+        .collect();
+    let client = CodeDescriptor::new(params, hasher, sizes)
+        .systematic_code::<String, Vec<u8>>()
+        .unwrap();
+    do_key_test(client.as_ref(), &users, &usernames);
+
+    let pung = CodeDescriptor::new(CodeParams::Pung { k }, hasher, vec![0; 9 * k]);
+    assert_eq!(
+        pung.systematic_code::<String, Vec<u8>>().err(),
+        Some(DescriptorError::RequiresXor(CodeId::Pung))
+    );
+
+    // Databases of systematic codes can be updated with such keys
+    let mut db = EncodedDatabase::new_systematic(CuckooCode::new(k, 3, 1.5), &users).unwrap();
+    let mut current = users.clone();
+
+    let alice = Tuple {
+        t: ("alice".to_string(), vec![1; 32]),
+    };
+    assert!(!db.insert(alice.clone()).unwrap().is_empty());
+    current.push(alice);
+
+    let bob = Tuple {
+        t: ("user7".to_string(), vec![2; 32]),
+    };
+    assert!(!db.update(bob.clone()).unwrap().is_empty());
+    current[7] = bob;
+
+    assert!(!db.remove(&"user8".to_string()).unwrap().is_empty());
+    current.remove(8);
+    assert_eq!(db.buckets(), &db.code().encode(&current).unwrap()[..]);
+
+    // but not those of codes that need XOR
+    let tuples: Vec<Tuple<usize, usize>> = (0..n).map(|i| Tuple { t: (i, i) }).collect();
+    assert_eq!(
+        EncodedDatabase::new_systematic(PungCode::new(k), &tuples).err(),
+        Some(ScheduleError::RequiresXor)
+    );
+    assert!(EncodedDatabase::new_systematic(ChoicesCode::new(k, 2), &tuples).is_ok());
+}
+
+#[test]
+fn test_block() {
+    type Small = Block<16>;
+    assert_eq!(Small::CAPACITY, 12);
+
+    for len in 0..=Small::CAPACITY {
+        let value: Vec<u8> = (1..=len as u8).collect();
+        let block = Small::encode(&value).unwrap();
+        assert_eq!(block.decode().unwrap(), &value[..]);
+
+        // Serializes to exactly N bytes, and back
+        let bytes = bincode::serialize(&block).unwrap();
+        assert_eq!(bytes.len(), 16);
+        assert_eq!(bincode::deserialize::<Small>(&bytes).unwrap(), block);
+    }
+
+    assert_eq!(
+        Small::encode(&[1; 13]),
+        Err(BlockError::TooLong {
+            len: 13,
+            capacity: 12
+        })
+    );
+    assert_eq!(Small::default().decode().unwrap(), &[] as &[u8]);
+    assert!(bincode::deserialize::<Small>(&[0; 15]).is_err());
+
+    // XOR recovers either block from the other
+    let a = Small::encode(b"alice").unwrap();
+    let b = Small::encode(b"bob").unwrap();
+    let mut c = a ^ b;
+    assert_eq!((c ^ b).decode().unwrap(), b"alice");
+    c ^= a;
+    assert_eq!(c.decode().unwrap(), b"bob");
+
+    // Corrupted blocks are rejected
+    let mut bytes = *a.as_bytes();
+    bytes[0] = 13;
+    assert_eq!(
+        Small::from(bytes).decode(),
+        Err(BlockError::InvalidLength {
+            len: 13,
+            capacity: 12
+        })
+    );
+    bytes[0] = 2;
+    assert_eq!(Small::from(bytes).decode(), Err(BlockError::NonZeroPadding));
+
+    // Blocks work as values of XOR-based codes
+    let k = 16;
+    let tuples: Vec<Tuple<usize, Block<32>>> = (0..1000)
+        .map(|i| Tuple {
+            t: (i, Block::encode(format!("value {i}").as_bytes()).unwrap()),
+        })
+        .collect();
+    let keys: Vec<usize> = (0..k).map(|i| i * 7).collect();
+
+    do_key_test(&PungCode::new(k), &tuples, &keys);
+    do_key_test(&SubcubeCode::new(2, 2), &tuples, &keys[..4]);
 }
@@ -0,0 +1,168 @@
+use super::{Tuple, Xor};
+use serde::Serialize;
+
+/// How one bucket of an encoded collection is obtained from the collection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BucketView {
+    /// The entries at these positions of the collection, in this order.
+    Entries(Vec<usize>),
+    /// The same rows as another bucket (e.g., a replica of a logical bucket).
+    Copy(usize),
+    /// Row r is the XOR of row r of each of these buckets that has such a row.
+    Parity(Vec<usize>),
+}
+
+/// An encoded collection that refers to the entries of the original collection by position
+/// instead of holding copies of them. This is returned by `BatchCode::encode_view`, and takes a
+/// few bytes per row instead of a full `Tuple`. Buckets can then be materialized one at a time
+/// (e.g., by `MultiPirServer::setup_view`) and dropped once the PIR server has processed them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncodedView {
+    buckets: Vec<BucketView>,
+    lens: Vec<usize>,
+}
+
+impl EncodedView {
+    /// Creates a view from the description of each bucket. Copy and Parity buckets must only
+    /// refer to buckets that come before them.
+    pub fn new(buckets: Vec<BucketView>) -> EncodedView {
+        let mut lens = Vec::with_capacity(buckets.len());
+
+        for (i, bucket) in buckets.iter().enumerate() {
+            let len = match bucket {
+                BucketView::Entries(positions) => positions.len(),
+                BucketView::Copy(source) => {
+                    assert!(*source < i, "Buckets may only copy earlier buckets");
+                    lens[*source]
+                }
+                BucketView::Parity(sources) => {
+                    assert!(
+                        sources.iter().all(|s| *s < i),
+                        "Buckets may only XOR earlier buckets"
+                    );
+                    sources.iter().map(|s| lens[*s]).max().unwrap_or(0)
+                }
+            };
+
+            lens.push(len);
+        }
+
+        EncodedView { buckets, lens }
+    }
+
+    pub fn buckets(&self) -> &[BucketView] {
+        &self.buckets
+    }
+
+    pub fn num_buckets(&self) -> usize {
+        self.buckets.len()
+    }
+
+    /// Number of rows of the given bucket.
+    pub fn bucket_len(&self, bucket: usize) -> usize {
+        self.lens[bucket]
+    }
+
+    /// Whether the view has no Parity buckets, so that every bucket can be materialized with
+    /// `materialize_systematic`.
+    pub fn is_systematic(&self) -> bool {
+        !self
+            .buckets
+            .iter()
+            .any(|bucket| matches!(bucket, BucketView::Parity(_)))
+    }
+
+    /// Computes a single row of a bucket, or returns None if the bucket has no such row.
+    /// `collection` must be the collection that was passed to `encode_view`.
+    pub fn row<K, V>(
+        &self,
+        bucket: usize,
+        row: usize,
+        collection: &[Tuple<K, V>],
+    ) -> Option<Tuple<K, V>>
+    where
+        K: Clone + Serialize + Xor,
+        V: Clone + Serialize + Xor,
+    {
+        self.row_with(bucket, row, collection, &|a, b| Some(a ^ b))?
+    }
+
+    /// Builds a copy of the given bucket (the same as the corresponding bucket returned by
+    /// `encode`). `collection` must be the collection that was passed to `encode_view`.
+    pub fn materialize<K, V>(&self, bucket: usize, collection: &[Tuple<K, V>]) -> Vec<Tuple<K, V>>
+    where
+        K: Clone + Serialize + Xor,
+        V: Clone + Serialize + Xor,
+    {
+        self.materialize_with(bucket, collection, &|a, b| Some(a ^ b))
+            .unwrap()
+    }
+
+    /// Same as `materialize`, but for keys and values that cannot be XORed. Returns None if the
+    /// bucket is (or copies) a Parity bucket (see `is_systematic`).
+    pub fn materialize_systematic<K, V>(
+        &self,
+        bucket: usize,
+        collection: &[Tuple<K, V>],
+    ) -> Option<Vec<Tuple<K, V>>>
+    where
+        K: Clone + Serialize,
+        V: Clone + Serialize,
+    {
+        self.materialize_with(bucket, collection, &|_, _| None)
+    }
+
+    // Computes a row, combining the rows of Parity buckets with xor. The outer None means
+    // there is no such row, and the inner None that xor gave up.
+    #[allow(clippy::type_complexity)]
+    fn row_with<K, V>(
+        &self,
+        bucket: usize,
+        row: usize,
+        collection: &[Tuple<K, V>],
+        xor: &dyn Fn(Tuple<K, V>, Tuple<K, V>) -> Option<Tuple<K, V>>,
+    ) -> Option<Option<Tuple<K, V>>>
+    where
+        K: Clone + Serialize,
+        V: Clone + Serialize,
+    {
+        if row >= self.lens[bucket] {
+            return None;
+        }
+
+        match &self.buckets[bucket] {
+            BucketView::Entries(positions) => Some(Some(collection[positions[row]].clone())),
+            BucketView::Copy(source) => self.row_with(*source, row, collection, xor),
+            BucketView::Parity(sources) => {
+                let mut rows = sources
+                    .iter()
+                    .filter_map(|s| self.row_with(*s, row, collection, xor));
+                let first = rows.next()?;
+
+                Some(rows.try_fold(first?, |a, b| xor(a, b?)))
+            }
+        }
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn materialize_with<K, V>(
+        &self,
+        bucket: usize,
+        collection: &[Tuple<K, V>],
+        xor: &dyn Fn(Tuple<K, V>, Tuple<K, V>) -> Option<Tuple<K, V>>,
+    ) -> Option<Vec<Tuple<K, V>>>
+    where
+        K: Clone + Serialize,
+        V: Clone + Serialize,
+    {
+        match &self.buckets[bucket] {
+            BucketView::Entries(positions) => {
+                Some(positions.iter().map(|p| collection[*p].clone()).collect())
+            }
+            BucketView::Copy(source) => self.materialize_with(*source, collection, xor),
+            BucketView::Parity(_) => (0..self.lens[bucket])
+                .map(|r| self.row_with(bucket, r, collection, xor).unwrap())
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,485 @@
+use crate::client::MultiPirClient;
+use crate::pbc::choices::ChoicesCode;
+use crate::pbc::descriptor::{CodeDescriptor, CodeParams, DescriptorError};
+use crate::pbc::hasher::{CodeId, HasherSpec};
+use crate::pbc::padding::{pad, Padding};
+use crate::pbc::sharding::ShardingCode;
+use crate::pbc::simulation::estimate_failure;
+use crate::pbc::stats::{pir_cost, PirCost, PirParams};
+use crate::pbc::{BatchCode, ParamError, ScheduleError, Strategy, Tuple, Xor};
+use crate::server::MultiPirServer;
+use rand::{ChaChaRng, Rng};
+use serde::Serialize;
+use std::{cmp, hash};
+
+/// SealPIR parameters that the planner considers by default.
+pub const DEFAULT_PIR_PARAMS: [PirParams; 8] = [
+    PirParams {
+        poly_degree: 2048,
+        log_plain_mod: 12,
+        d: 1,
+    },
+    PirParams {
+        poly_degree: 2048,
+        log_plain_mod: 12,
+        d: 2,
+    },
+    PirParams {
+        poly_degree: 2048,
+        log_plain_mod: 20,
+        d: 1,
+    },
+    PirParams {
+        poly_degree: 2048,
+        log_plain_mod: 20,
+        d: 2,
+    },
+    PirParams {
+        poly_degree: 4096,
+        log_plain_mod: 12,
+        d: 1,
+    },
+    PirParams {
+        poly_degree: 4096,
+        log_plain_mod: 12,
+        d: 2,
+    },
+    PirParams {
+        poly_degree: 4096,
+        log_plain_mod: 20,
+        d: 1,
+    },
+    PirParams {
+        poly_degree: 4096,
+        log_plain_mod: 20,
+        d: 2,
+    },
+];
+
+/// Batch code (and its parameters) chosen by the planner. The batch size k is kept in the Plan.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum CodeChoice {
+    Replication,
+    Sharding {
+        replicas: usize,
+    },
+    Choices {
+        d: usize,
+        replicas: usize,
+    },
+    Cuckoo {
+        d: usize,
+        r: f64,
+        strategy: Strategy,
+    },
+    Pung,
+}
+
+impl CodeChoice {
+    pub fn id(&self) -> CodeId {
+        match *self {
+            CodeChoice::Replication => CodeId::Replication,
+            CodeChoice::Sharding { .. } => CodeId::Sharding,
+            CodeChoice::Choices { .. } => CodeId::Choices,
+            CodeChoice::Cuckoo { .. } => CodeId::Cuckoo,
+            CodeChoice::Pung => CodeId::Pung,
+        }
+    }
+
+    /// Parameters of the code for batches of k keys.
+    pub fn params(&self, k: usize) -> CodeParams {
+        match *self {
+            CodeChoice::Replication => CodeParams::Replication { k },
+            CodeChoice::Sharding { replicas } => CodeParams::Sharding {
+                k,
+                replicas,
+                strategy: Strategy::default(),
+            },
+            CodeChoice::Choices { d, replicas } => CodeParams::Choices {
+                k,
+                d,
+                replicas,
+                strategy: Strategy::default(),
+            },
+            CodeChoice::Cuckoo { d, r, strategy } => CodeParams::Cuckoo {
+                k,
+                d,
+                r,
+                stash: 0,
+                strategy,
+            },
+            CodeChoice::Pung => CodeParams::Pung { k },
+        }
+    }
+
+    /// Builds the code for batches of k keys, placing keys with the given hasher.
+    pub fn build<K, V>(&self, k: usize, hasher: HasherSpec) -> Box<dyn BatchCode<K, V>>
+    where
+        K: Clone + Serialize + Xor + cmp::Eq + hash::Hash,
+        V: Clone + Serialize + Xor,
+    {
+        self.params(k).build(hasher)
+    }
+
+    /// Same as `build`, but for keys and values that cannot be XORed. Returns None for PungCode
+    /// (see `CodeParams::build_systematic`).
+    pub fn build_systematic<K, V>(
+        &self,
+        k: usize,
+        hasher: HasherSpec,
+    ) -> Option<Box<dyn BatchCode<K, V>>>
+    where
+        K: Clone + Serialize + cmp::Eq + hash::Hash,
+        V: Clone + Serialize,
+    {
+        self.params(k).build_systematic(hasher)
+    }
+}
+
+/// Weights of the planner's cost function. The cost of a plan is cpu times the
+/// plaintext-ciphertext multiplications needed to answer a batch, plus network times the bytes
+/// of a batch's queries and replies, plus storage times the bytes of the server's preprocessed
+/// database (see `PirCost`).
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CostWeights {
+    pub cpu: f64,
+    pub network: f64,
+    pub storage: f64,
+}
+
+impl Default for CostWeights {
+    fn default() -> Self {
+        CostWeights {
+            cpu: 1.0,
+            network: 1.0,
+            storage: 1.0,
+        }
+    }
+}
+
+/// The code and PIR parameters chosen by `Planner::plan`. Plans can be serialized, so that the
+/// server can publish the one it uses and clients can build matching parameters.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Plan {
+    pub code: CodeChoice,
+    /// Hash function that places keys in buckets.
+    pub hasher: HasherSpec,
+    pub k: usize,
+    /// Number of entries of the collection the plan was made for.
+    pub n: usize,
+    pub num_buckets: usize,
+    /// Every bucket is padded to this length (see `Plan::encode`).
+    pub bucket_len: usize,
+    pub ele_size: usize,
+    pub pir: PirParams,
+    /// Upper bound on the probability that a batch of k keys cannot be scheduled, or the upper
+    /// end of a 95% confidence interval for codes whose failure probability was simulated.
+    pub failure: f64,
+    /// Projected cost of all buckets.
+    pub cost: PirCost,
+    /// Value of the cost function (see `CostWeights`).
+    pub score: f64,
+}
+
+impl Plan {
+    pub fn code<K, V>(&self) -> Box<dyn BatchCode<K, V>>
+    where
+        K: Clone + Serialize + Xor + cmp::Eq + hash::Hash,
+        V: Clone + Serialize + Xor,
+    {
+        self.code.build(self.k, self.hasher)
+    }
+
+    /// Same as `code`, but for keys and values that cannot be XORed. Fails with `RequiresXor`
+    /// if the plan uses PungCode (see `Planner::set_systematic`).
+    pub fn systematic_code<K, V>(&self) -> Result<Box<dyn BatchCode<K, V>>, ScheduleError>
+    where
+        K: Clone + Serialize + cmp::Eq + hash::Hash,
+        V: Clone + Serialize,
+    {
+        self.code
+            .build_systematic(self.k, self.hasher)
+            .ok_or(ScheduleError::RequiresXor)
+    }
+
+    /// Encodes the collection and pads every bucket to bucket_len with copies of dummy (see
+    /// `padding::pad`). Fails with `BucketOverflow` in the unlikely event that a bucket exceeds
+    /// bucket_len (which becomes likely if the collection has more than n entries).
+    pub fn encode<K, V>(
+        &self,
+        collection: &[Tuple<K, V>],
+        dummy: &Tuple<K, V>,
+    ) -> Result<Vec<Vec<Tuple<K, V>>>, ScheduleError>
+    where
+        K: Clone + Serialize + Xor + cmp::Eq + hash::Hash,
+        V: Clone + Serialize + Xor,
+    {
+        let mut buckets = self.code().encode(collection)?;
+        pad(&mut buckets, Padding::Fixed(self.bucket_len), dummy)?;
+        Ok(buckets)
+    }
+
+    /// Same as `encode`, but for keys and values that cannot be XORed (see `systematic_code`).
+    pub fn encode_systematic<K, V>(
+        &self,
+        collection: &[Tuple<K, V>],
+        dummy: &Tuple<K, V>,
+    ) -> Result<Vec<Vec<Tuple<K, V>>>, ScheduleError>
+    where
+        K: Clone + Serialize + cmp::Eq + hash::Hash,
+        V: Clone + Serialize,
+    {
+        let mut buckets = self.systematic_code()?.encode(collection)?;
+        pad(&mut buckets, Padding::Fixed(self.bucket_len), dummy)?;
+        Ok(buckets)
+    }
+
+    /// A client for the buckets returned by `encode` (see `MultiPirClient::new_uniform`).
+    pub fn client<'a>(&self) -> MultiPirClient<'a> {
+        MultiPirClient::new_uniform(
+            self.num_buckets,
+            self.bucket_len as u32,
+            self.ele_size as u32,
+            self.pir.poly_degree,
+            self.pir.log_plain_mod,
+            self.pir.d,
+        )
+    }
+
+    /// Descriptor of the code and the padded buckets returned by `encode`, which the server can
+    /// publish instead of the whole plan.
+    pub fn descriptor(&self) -> CodeDescriptor {
+        CodeDescriptor::new(
+            self.code.params(self.k),
+            self.hasher,
+            vec![self.bucket_len; self.num_buckets],
+        )
+    }
+
+    /// A server for the buckets returned by `encode`, which must still be passed to
+    /// `MultiPirServer::setup`.
+    pub fn server<'a>(&self) -> MultiPirServer<'a> {
+        let sizes = vec![(self.bucket_len as u32, self.ele_size as u32); self.num_buckets];
+        MultiPirServer::new(
+            &sizes,
+            self.pir.poly_degree,
+            self.pir.log_plain_mod,
+            self.pir.d,
+        )
+    }
+}
+
+/// Chooses a batch code and SealPIR parameters for a collection of n entries of ele_size bytes
+/// (the size of a serialized `Tuple`) and batches of k keys. The planner considers
+/// ReplicationCode, ShardingCode, ChoicesCode (d = 2, 3), CuckooCode (d = 2, 3, 4 and several
+/// values of r) and PungCode. ShardingCode and ChoicesCode get the fewest replicas that meet the
+/// target (see `ShardingCode::with_failure`). It keeps the codes whose failure probability is
+/// at most the target, and returns the one with the lowest cost.
+///
+/// Failure probabilities come from `BatchCode::failure_bound`. Codes without a bound (e.g.,
+/// PungCode) are only considered if simulation trials are enabled, in which case the upper end
+/// of a 95% confidence interval from `simulation::estimate_failure` is used instead. Buckets are
+/// padded to `BatchCode::bucket_bound`, with the same target as the probability of exceeding it.
+pub struct Planner {
+    n: usize,
+    ele_size: usize,
+    k: usize,
+    target: f64,
+    weights: CostWeights,
+    pir_params: Vec<PirParams>,
+    trials: usize,
+    hasher: HasherSpec,
+    systematic: bool,
+}
+
+impl Planner {
+    /// Fails if k is 0 or the target is not in (0, 1).
+    pub fn new(n: usize, ele_size: usize, k: usize, target: f64) -> Result<Planner, ParamError> {
+        if k == 0 {
+            return Err(ParamError::InvalidParam {
+                name: "k",
+                reason: "batches must have at least one key".to_string(),
+            });
+        }
+
+        if !(target > 0.0 && target < 1.0) {
+            return Err(ParamError::InvalidParam {
+                name: "target",
+                reason: format!("{target} is not in (0, 1)"),
+            });
+        }
+
+        Ok(Planner {
+            n,
+            ele_size,
+            k,
+            target,
+            weights: CostWeights::default(),
+            pir_params: DEFAULT_PIR_PARAMS.to_vec(),
+            trials: 0,
+            hasher: HasherSpec::default(),
+            systematic: false,
+        })
+    }
+
+    pub fn set_weights(&mut self, weights: CostWeights) {
+        self.weights = weights;
+    }
+
+    /// Replaces the SealPIR parameters that are considered (DEFAULT_PIR_PARAMS by default).
+    pub fn set_pir_params(&mut self, pir_params: Vec<PirParams>) {
+        self.pir_params = pir_params;
+    }
+
+    /// Hash function that plans place keys with (SHA-256 by default). A keyed hasher is much
+    /// faster, and has the same failure bounds. A SaltedHasher is bound to the code of each
+    /// plan (see `HasherSpec::for_code`), so its code identifier here does not matter. Fails
+    /// if this crate does not implement the hasher (see `HasherSpec::is_supported`).
+    pub fn set_hasher(&mut self, hasher: HasherSpec) -> Result<(), DescriptorError> {
+        if !hasher.is_supported() {
+            return Err(DescriptorError::UnsupportedHasher {
+                version: hasher.version(),
+            });
+        }
+
+        self.hasher = hasher;
+        Ok(())
+    }
+
+    /// Whether to only consider systematic codes (i.e., to leave out PungCode), whose plans work
+    /// with keys and values that cannot be XORed (see `Plan::systematic_code`).
+    pub fn set_systematic(&mut self, systematic: bool) {
+        self.systematic = systematic;
+    }
+
+    /// Number of random batches used to estimate the failure probability of codes without a
+    /// failure bound (0, the default, leaves those codes out). Showing a failure probability
+    /// below the target takes about 3.84 / target trials (see `simulation::trials_needed`).
+    pub fn set_simulation_trials(&mut self, trials: usize) {
+        self.trials = trials;
+    }
+
+    /// The cheapest plan that meets the target, or None if there is none.
+    pub fn plan(&self) -> Option<Plan> {
+        self.candidates().into_iter().next()
+    }
+
+    /// Every plan that meets the target, from cheapest to most expensive.
+    pub fn candidates(&self) -> Vec<Plan> {
+        let security = cmp::max(1, (-self.target.log2()).ceil() as u32);
+        let mut plans = Vec::new();
+
+        for choice in self.codes() {
+            let hasher = self.hasher.for_code(choice.id());
+            let code: Box<dyn BatchCode<usize, usize>> = choice.build(self.k, hasher);
+
+            let failure = match self.failure(code.as_ref()) {
+                Some(failure) if failure <= self.target => failure,
+                _ => continue,
+            };
+
+            let num_buckets = match code.encode_view(&[]) {
+                Ok(view) => view.num_buckets(),
+                Err(_) => continue,
+            };
+
+            let bucket_len = code.bucket_bound(self.n, security);
+
+            for pir in &self.pir_params {
+                let cost = match pir_cost(bucket_len, self.ele_size, *pir) {
+                    Some(cost) => scale(cost, num_buckets as u64),
+                    None => continue,
+                };
+
+                plans.push(Plan {
+                    code: choice,
+                    hasher,
+                    k: self.k,
+                    n: self.n,
+                    num_buckets,
+                    bucket_len,
+                    ele_size: self.ele_size,
+                    pir: *pir,
+                    failure,
+                    cost,
+                    score: self.score(&cost),
+                });
+            }
+        }
+
+        plans.sort_by(|a, b| a.score.total_cmp(&b.score));
+        plans
+    }
+
+    // Codes (and parameters) that can be built for batches of k keys
+    fn codes(&self) -> Vec<CodeChoice> {
+        let k = self.k;
+        let mut codes = vec![CodeChoice::Replication];
+
+        if let Ok(code) = ShardingCode::with_failure(k, self.target) {
+            codes.push(CodeChoice::Sharding {
+                replicas: code.replicas(),
+            });
+        }
+
+        for d in 2..=3 {
+            if let Ok(code) = ChoicesCode::with_failure(k, d, self.target) {
+                codes.push(CodeChoice::Choices {
+                    d,
+                    replicas: code.replicas(),
+                });
+            }
+        }
+
+        for d in 2..=4 {
+            for r in [1.25, 1.5, 2.0, 3.0] {
+                codes.push(CodeChoice::Cuckoo {
+                    d,
+                    r,
+                    strategy: Strategy::Matching,
+                });
+            }
+        }
+
+        if !self.systematic {
+            codes.push(CodeChoice::Pung);
+        }
+
+        // Leave out the ones whose constructor would panic (e.g., too many buckets)
+        codes.retain(|choice| choice.params(k).validate().is_ok());
+        codes
+    }
+
+    fn failure(&self, code: &dyn BatchCode<usize, usize>) -> Option<f64> {
+        if let Some(bound) = code.failure_bound() {
+            return Some(bound);
+        }
+
+        if self.trials == 0 {
+            return None;
+        }
+
+        // Seeded so that the same inputs yield the same plan
+        let mut rng = ChaChaRng::new_unseeded();
+        let sample = |rng: &mut dyn Rng| rng.next_u64() as usize;
+        let estimate = estimate_failure(code, self.k, self.trials, sample, &mut rng).ok()?;
+
+        Some(estimate.interval(1.96).1)
+    }
+
+    fn score(&self, cost: &PirCost) -> f64 {
+        self.weights.cpu * cost.server_ops as f64
+            + self.weights.network * (cost.query_size + cost.reply_size) as f64
+            + self.weights.storage * cost.server_memory as f64
+    }
+}
+
+// Cost of serving num_buckets buckets with the same cost
+fn scale(cost: PirCost, num_buckets: u64) -> PirCost {
+    PirCost {
+        server_memory: cost.server_memory * num_buckets,
+        query_size: cost.query_size * num_buckets,
+        reply_size: cost.reply_size * num_buckets,
+        server_ops: cost.server_ops * num_buckets,
+    }
+}
@@ -0,0 +1,149 @@
+use crate::client::{MultiPirClient, QueryError};
+use crate::pbc::layout::Layout;
+use crate::pbc::{BatchCode, ScheduleError, Tuple};
+use rand::Rng;
+use sealpir::{PirQuery, PirReply};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::{cmp, error, fmt, hash};
+
+/// Positions to retrieve for each key of a round (see `BatchCode::get_indexed_schedule`).
+pub type Round<K> = HashMap<K, Vec<(usize, usize)>>;
+
+/// Values retrieved by `RoundPlanner::retrieve`.
+#[derive(Debug, Clone)]
+pub struct Retrieval<K, V> {
+    pub values: HashMap<K, V>,
+    /// Number of PIR rounds (i.e., batches of queries sent to the server).
+    pub rounds: usize,
+}
+
+/// Retrieves key sets of any size, by splitting them into as many rounds as needed. Each round
+/// is a batch that the code can schedule: it takes as many of the remaining keys as
+/// `BatchCode::get_partial_schedule` can place (at most the code's k), and carries the rest
+/// into the next round. For example, 300 keys with a code for k = 256 take at least 2 rounds.
+pub struct RoundPlanner<'a, K, V>
+where
+    K: Clone + Serialize + cmp::Eq + hash::Hash,
+    V: Clone + Serialize,
+{
+    code: &'a dyn BatchCode<K, V>,
+    layout: &'a Layout<K>,
+}
+
+impl<'a, K, V> RoundPlanner<'a, K, V>
+where
+    K: Clone + Serialize + cmp::Eq + hash::Hash,
+    V: Clone + Serialize,
+{
+    /// The layout must be that of the collection the server encoded with code.
+    pub fn new(code: &'a dyn BatchCode<K, V>, layout: &'a Layout<K>) -> Self {
+        RoundPlanner { code, layout }
+    }
+
+    /// Splits the keys into rounds. Keys must be unique and in the layout (otherwise this fails
+    /// with `DuplicateKey` or `UnknownKey`). Fails with `Unschedulable` if the code cannot
+    /// schedule any of the remaining keys, which only happens with a broken code. Codes that
+    /// schedule randomly (e.g., CuckooCode) draw from rng (see `get_partial_schedule_with_rng`).
+    pub fn plan(&self, keys: &[K], rng: &mut dyn Rng) -> Result<Vec<Round<K>>, ScheduleError> {
+        if let Some(index) = keys.iter().position(|key| !self.layout.contains(key)) {
+            return Err(ScheduleError::UnknownKey { index });
+        }
+
+        let mut rounds = Vec::new();
+        let mut remaining = keys.to_vec();
+
+        while !remaining.is_empty() {
+            let partial = self.code.get_partial_schedule_with_rng(&remaining, rng)?;
+
+            if partial.schedule.is_empty() {
+                let index = keys.iter().position(|key| *key == remaining[0]).unwrap();
+                return Err(ScheduleError::Unschedulable { index });
+            }
+
+            rounds.push(self.layout.index_schedule(partial.schedule));
+            remaining = partial.leftover;
+        }
+
+        Ok(rounds)
+    }
+
+    /// Retrieves the keys' values with the given client, one round at a time. Each round sends
+    /// a query for every bucket (see `MultiPirClient::gen_scheduled_query`) to the server
+    /// through `send`, which returns the server's replies (e.g., from
+    /// `MultiPirServer::gen_replies`). The rounds are planned with rng (see `plan`). Fails with
+    /// `WrongKey` if an entry has a different key than the one requested, which happens if the
+    /// layout does not match the server's encoding.
+    pub fn retrieve<F>(
+        &self,
+        client: &MultiPirClient,
+        keys: &[K],
+        rng: &mut dyn Rng,
+        mut send: F,
+    ) -> Result<Retrieval<K, V>, RoundError>
+    where
+        F: FnMut(&[PirQuery]) -> Vec<PirReply>,
+    {
+        let rounds = self.plan(keys, rng)?;
+        let mut values = HashMap::with_capacity(keys.len());
+
+        for round in &rounds {
+            let query = client.gen_scheduled_query(round)?;
+            let replies = send(&query.query);
+
+            for (key, parts) in client.decode_scheduled_replies::<K, Tuple<K, V>>(&query, &replies)
+            {
+                let (found, value) = self.code.decode(&parts)?.t;
+
+                if found != key {
+                    let index = keys.iter().position(|k| *k == key).unwrap();
+                    return Err(RoundError::WrongKey { index });
+                }
+
+                values.insert(key, value);
+            }
+        }
+
+        Ok(Retrieval {
+            values,
+            rounds: rounds.len(),
+        })
+    }
+}
+
+/// Reasons why `RoundPlanner::retrieve` fails.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RoundError {
+    Schedule(ScheduleError),
+    Query(QueryError),
+    /// The entry retrieved for the key at this index has a different key.
+    WrongKey {
+        index: usize,
+    },
+}
+
+impl From<ScheduleError> for RoundError {
+    fn from(e: ScheduleError) -> Self {
+        RoundError::Schedule(e)
+    }
+}
+
+impl From<QueryError> for RoundError {
+    fn from(e: QueryError) -> Self {
+        RoundError::Query(e)
+    }
+}
+
+impl fmt::Display for RoundError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RoundError::Schedule(e) => write!(f, "{e}"),
+            RoundError::Query(e) => write!(f, "{e}"),
+            RoundError::WrongKey { index } => {
+                write!(f, "entry retrieved for key {index} has a different key")
+            }
+        }
+    }
+}
+
+impl error::Error for RoundError {}
@@ -1,5 +1,8 @@
+use crate::pbc::view::EncodedView;
+use crate::pbc::{ScheduleError, Tuple, Xor};
 use sealpir::server::PirServer;
 use sealpir::{PirQuery, PirReply};
+use serde::Serialize;
 
 pub struct MultiPirServer<'a> {
     handles: Vec<PirServer<'a>>,
@@ -40,6 +43,65 @@ impl<'a> MultiPirServer<'a> {
         MultiPirServer { handles }
     }
 
+    /// Same as new_setup, but takes the output of BatchCode::encode_view and the collection
+    /// that was encoded. Each bucket is materialized only while its PIR server is set up, so the
+    /// encoded collection is never held in memory all at once.
+    pub fn new_setup_view<K, V>(
+        view: &EncodedView,
+        collection: &[Tuple<K, V>],
+        ele_size: u32,
+        poly_degree: u32,
+        log_plain: u32,
+        d: u32,
+    ) -> MultiPirServer<'a>
+    where
+        K: Clone + Serialize + Xor,
+        V: Clone + Serialize + Xor,
+    {
+        let mut handles = Vec::with_capacity(view.num_buckets());
+
+        for i in 0..view.num_buckets() {
+            let bucket = view.materialize(i, collection);
+            let mut server =
+                PirServer::new(bucket.len() as u32, ele_size, poly_degree, log_plain, d);
+            server.setup(&bucket);
+            handles.push(server);
+        }
+
+        MultiPirServer { handles }
+    }
+
+    /// Same as new_setup_view, but for keys and values that cannot be XORed. Fails with
+    /// `RequiresXor` if the view has Parity buckets (see `EncodedView::is_systematic`).
+    pub fn new_setup_view_systematic<K, V>(
+        view: &EncodedView,
+        collection: &[Tuple<K, V>],
+        ele_size: u32,
+        poly_degree: u32,
+        log_plain: u32,
+        d: u32,
+    ) -> Result<MultiPirServer<'a>, ScheduleError>
+    where
+        K: Clone + Serialize,
+        V: Clone + Serialize,
+    {
+        if !view.is_systematic() {
+            return Err(ScheduleError::RequiresXor);
+        }
+
+        let mut handles = Vec::with_capacity(view.num_buckets());
+
+        for i in 0..view.num_buckets() {
+            let bucket = view.materialize_systematic(i, collection).unwrap();
+            let mut server =
+                PirServer::new(bucket.len() as u32, ele_size, poly_degree, log_plain, d);
+            server.setup(&bucket);
+            handles.push(server);
+        }
+
+        Ok(MultiPirServer { handles })
+    }
+
     pub fn update_params(&mut self, buckets: &[(u32, u32)], d: u32) {
         assert_eq!(buckets.len(), self.handles.len());
 
@@ -56,6 +118,13 @@ impl<'a> MultiPirServer<'a> {
         }
     }
 
+    /// Sets the same Galois key for every bucket (see MultiPirClient::new_uniform).
+    pub fn set_shared_galois_key(&mut self, key: &[u8], client_id: u32) {
+        for handle in self.handles.iter_mut() {
+            handle.set_galois_key(key, client_id);
+        }
+    }
+
     pub fn setup<T>(&mut self, collection: &[Vec<T>]) {
         assert_eq!(collection.len(), self.handles.len());
 
@@ -64,6 +133,52 @@ impl<'a> MultiPirServer<'a> {
         }
     }
 
+    /// Same as setup, but takes the output of BatchCode::encode_view and the collection that
+    /// was encoded (see new_setup_view).
+    pub fn setup_view<K, V>(&mut self, view: &EncodedView, collection: &[Tuple<K, V>])
+    where
+        K: Clone + Serialize + Xor,
+        V: Clone + Serialize + Xor,
+    {
+        assert_eq!(view.num_buckets(), self.handles.len());
+
+        for (i, handle) in self.handles.iter_mut().enumerate() {
+            handle.setup(&view.materialize(i, collection));
+        }
+    }
+
+    /// Same as setup_view, but for keys and values that cannot be XORed (see
+    /// new_setup_view_systematic).
+    pub fn setup_view_systematic<K, V>(
+        &mut self,
+        view: &EncodedView,
+        collection: &[Tuple<K, V>],
+    ) -> Result<(), ScheduleError>
+    where
+        K: Clone + Serialize,
+        V: Clone + Serialize,
+    {
+        assert_eq!(view.num_buckets(), self.handles.len());
+
+        if !view.is_systematic() {
+            return Err(ScheduleError::RequiresXor);
+        }
+
+        for (i, handle) in self.handles.iter_mut().enumerate() {
+            handle.setup(&view.materialize_systematic(i, collection).unwrap());
+        }
+
+        Ok(())
+    }
+
+    /// Sets up a single bucket again after it changed (e.g., one of the buckets returned by an
+    /// EncodedDatabase update). The bucket may have a different number of elements than before.
+    pub fn setup_bucket<T>(&mut self, index: usize, bucket: &[T], ele_size: u32, d: u32) {
+        let handle = &mut self.handles[index];
+        handle.update_params(bucket.len() as u32, ele_size, d);
+        handle.setup(bucket);
+    }
+
     pub fn gen_replies(&self, queries: &[PirQuery], client_id: u32) -> Vec<PirReply> {
         let len = queries.len();
         assert_eq!(len, self.handles.len());
@@ -1,19 +1,24 @@
+extern crate bincode;
 extern crate mpir;
 extern crate rand;
-extern crate serde;
-
-#[macro_use]
-extern crate serde_derive;
 
+use mpir::chunks::{ChunkError, ChunkKey, Chunker};
 use mpir::client::MultiPirClient;
+use mpir::keyword::{KeywordError, KeywordParams, KeywordPir, Lookup};
+use mpir::pbc::block::Block;
 use mpir::pbc::choices::ChoicesCode;
 use mpir::pbc::cuckoo::CuckooCode;
+use mpir::pbc::descriptor::{CodeDescriptor, DescriptorError};
+use mpir::pbc::hasher::{BucketHasher, CodeId, HasherSpec, KeyedHasher, SaltedHasher};
+use mpir::pbc::padding::{pad, Padding};
 use mpir::pbc::pung::PungCode;
 use mpir::pbc::replication::ReplicationCode;
 use mpir::pbc::sharding::ShardingCode;
-use mpir::pbc::{BatchCode, Tuple};
+use mpir::pbc::{BatchCode, Tuple, MAX_BUCKETS};
+use mpir::planner::{CodeChoice, CostWeights, Plan, Planner};
+use mpir::rounds::{RoundError, RoundPlanner};
 use mpir::server::MultiPirServer;
-use rand::Rng;
+use rand::{ChaChaRng, Rng, SeedableRng};
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::mem;
@@ -25,40 +30,11 @@ const POLY_DEGREE: u32 = 2048;
 const NUM: u32 = 1 << 20;
 const BATCH: [usize; 3] = [16, 64, 256];
 
-#[derive(Serialize, Clone)]
-struct Element {
-    #[serde(serialize_with = "<[_]>::serialize")]
-    e: [u8; SIZE],
-}
-
-impl std::ops::BitXor for Element {
-    type Output = Self;
-
-    fn bitxor(self, rhs: Self) -> Self {
-        let mut el = Element { e: [0u8; SIZE] };
-
-        for i in 0..self.e.len() {
-            el.e[i] = self.e[i] ^ rhs.e[i];
-        }
-
-        el
-    }
-}
-
-impl std::ops::BitXorAssign for Element {
-    fn bitxor_assign(&mut self, rhs: Self) {
-        for i in 0..self.e.len() {
-            self.e[i] ^= rhs.e[i];
-        }
-    }
-}
+type Element = Block<SIZE>;
 
 type OracleTy = (Vec<Vec<Tuple<usize, Element>>>, Vec<(u32, u32)>);
 
-fn get_oracle(
-    code: &dyn BatchCode<usize, Element>,
-    rng: &mut dyn Rng,
-) -> OracleTy {
+fn get_collection(rng: &mut dyn Rng) -> Vec<Tuple<usize, Element>> {
     let mut collection = vec![];
 
     // we do this to construct the Oracle
@@ -66,11 +42,19 @@ fn get_oracle(
         let mut x = [0u8; SIZE];
         rng.fill_bytes(&mut x);
         collection.push(Tuple {
-            t: (i, Element { e: x }),
+            t: (i, Element::from(x)),
         });
     }
 
-    let oracle = code.encode(&collection);
+    collection
+}
+
+fn get_oracle(
+    code: &dyn BatchCode<usize, Element>,
+    rng: &mut dyn Rng,
+) -> OracleTy {
+    let collection = get_collection(rng);
+    let oracle = code.encode(&collection).unwrap();
     let sizes: Vec<(u32, u32)> = oracle
         .iter()
         .map(|vec| (vec.len() as u32, mem::size_of::<(usize, Element)>() as u32))
@@ -95,22 +79,17 @@ fn mpir_sizes() {
 
         let keys: Vec<usize> = key_set.drain().collect();
 
-        // Get schedule. Hash in the head. Simulate entries.
-        let schedule: HashMap<usize, Vec<usize>> = (&code as &dyn BatchCode<usize, Element>)
-            .get_schedule(&keys)
+        // Get schedule. Hash in the head. The layout only depends on the keys the server holds.
+        let collection_keys: Vec<usize> = (0..NUM as usize).collect();
+        let layout = (&code as &dyn BatchCode<usize, Element>)
+            .layout(&collection_keys)
+            .unwrap();
+        let schedule = (&code as &dyn BatchCode<usize, Element>)
+            .get_indexed_schedule(&keys, &layout)
             .unwrap();
 
-        // Consult the oracle for the indices
-        let indexes: HashMap<usize, usize> = schedule
-            .iter()
-            .map(|(key, buckets)| (key, buckets[0]))
-            .map(|(key, bucket)| {
-                (
-                    *key,
-                    oracle[bucket].iter().position(|e| e.t.0 == *key).unwrap(),
-                )
-            })
-            .collect();
+        // Indexes map from bucket -> index to fetch in that bucket
+        let indexes: HashMap<usize, usize> = schedule.values().flatten().cloned().collect();
 
         // Create the client and the server
         let client = MultiPirClient::new(&sizes, POLY_DEGREE, LOGT, DIM);
@@ -163,7 +142,7 @@ fn mpir_sizes() {
     for k in &BATCH {
         // setup
         let mut rng = rand::thread_rng();
-        let mut code = PungCode::new(*k);
+        let code = PungCode::new(*k);
 
         let (oracle, sizes) = get_oracle(&code, &mut rng);
 
@@ -175,19 +154,6 @@ fn mpir_sizes() {
         let galois = client.get_galois_keys();
         server.set_galois_keys(&galois, 0);
 
-        // Get label mapping (since Pung is data-dependent...)
-        let mut labels: HashMap<usize, Vec<usize>> = HashMap::new();
-
-        for (i, vec_tuple) in oracle.iter().enumerate() {
-            for tuple in vec_tuple {
-                let entry = labels.entry(tuple.t.0).or_insert_with(Vec::new);
-                entry.push(i);
-            }
-        }
-
-        // Pung's hybrid is a data-dependent code
-        code.set_labels(labels);
-
         // Generate keys (desired indexes)
         let mut key_set: HashSet<usize> = HashSet::new();
         while key_set.len() < *k {
@@ -196,41 +162,17 @@ fn mpir_sizes() {
 
         let keys: Vec<usize> = key_set.drain().collect();
 
-        // Get schedule. Hash in the head. Simulate entries.
-        let schedule: HashMap<usize, Vec<usize>> = (&code as &dyn BatchCode<usize, usize>)
-            .get_schedule(&keys)
+        // Get schedule. Hash in the head. The layout only depends on the keys the server holds.
+        let collection_keys: Vec<usize> = (0..NUM as usize).collect();
+        let layout = (&code as &dyn BatchCode<usize, usize>)
+            .layout(&collection_keys)
+            .unwrap();
+        let schedule = (&code as &dyn BatchCode<usize, usize>)
+            .get_indexed_schedule(&keys, &layout)
             .unwrap();
 
         // Indexes map from bucket -> index to fetch in that bucket
-        let mut indexes = HashMap::new();
-
-        for (key, buckets) in schedule {
-            // find the index within the sub-buckets for this key.
-            let sub_bucket_i = (buckets[0] / 9) * 9;
-
-            let mut index = 0;
-            let mut found = false;
-
-            for i in 0..4 {
-                // Consult the oracle for the indices
-                // buckets that have keys
-                let pos = oracle[sub_bucket_i + i].iter().position(|e| e.t.0 == key);
-
-                if let Some(ind) = pos {
-                    index = ind;
-                    found = true;
-                    break;
-                }
-            }
-
-            if !found {
-                panic!("Index for key not found");
-            }
-
-            for bucket in buckets {
-                indexes.insert(bucket, index);
-            }
-        }
+        let indexes: HashMap<usize, usize> = schedule.values().flatten().cloned().collect();
 
         let mut ind_vec = Vec::with_capacity(oracle.len());
 
@@ -278,9 +220,18 @@ where
     T: BatchCode<usize, Element>,
 {
     let mut rng = rand::thread_rng();
-    let (oracle, sizes) = get_oracle(code, &mut rng);
+    let collection = get_collection(&mut rng);
 
-    let truth = oracle.clone();
+    // Encode without copying the collection into every bucket
+    let view = code.encode_view(&collection).unwrap();
+    let sizes: Vec<(u32, u32)> = (0..view.num_buckets())
+        .map(|b| {
+            (
+                view.bucket_len(b) as u32,
+                mem::size_of::<(usize, Element)>() as u32,
+            )
+        })
+        .collect();
 
     // Generate keys (desired indexes)
     let mut key_set: HashSet<usize> = HashSet::new();
@@ -290,34 +241,29 @@ where
 
     let keys: Vec<usize> = key_set.drain().collect();
 
-    // Get schedule. Hash in the head. Simulate entries.
-    let schedule: HashMap<usize, Vec<usize>> = (code as &dyn BatchCode<usize, Element>)
-        .get_schedule(&keys)
+    // Get schedule. Hash in the head. The layout only depends on the keys the server holds.
+    let collection_keys: Vec<usize> = (0..NUM as usize).collect();
+    let layout = (code as &dyn BatchCode<usize, Element>)
+        .layout(&collection_keys)
+        .unwrap();
+    let schedule = (code as &dyn BatchCode<usize, Element>)
+        .get_indexed_schedule(&keys, &layout)
         .unwrap();
 
-    // Consult the oracle for the indices
-    let indexes: HashMap<usize, usize> = schedule
-        .iter()
-        .map(|(key, buckets)| (key, buckets[0]))
-        .map(|(key, bucket)| {
-            (
-                *key,
-                oracle[bucket].iter().position(|e| e.t.0 == *key).unwrap(),
-            )
-        })
-        .collect();
+    // Indexes map from bucket -> index to fetch in that bucket
+    let indexes: HashMap<usize, usize> = schedule.values().flatten().cloned().collect();
 
     // Create the client and the server
     let client = MultiPirClient::new(&sizes, POLY_DEGREE, LOGT, DIM);
     let mut server = MultiPirServer::new(&sizes, POLY_DEGREE, LOGT, DIM);
-    server.setup(&oracle);
+    server.setup_view(&view, &collection);
 
     let galois = client.get_galois_keys();
     server.set_galois_keys(&galois, 0);
 
-    let mut ind_vec = Vec::with_capacity(oracle.len());
+    let mut ind_vec = Vec::with_capacity(view.num_buckets());
 
-    for bucket in 0..oracle.len() {
+    for bucket in 0..view.num_buckets() {
         if indexes.contains_key(&bucket) {
             ind_vec.push(indexes[&bucket] as u32);
         } else {
@@ -331,7 +277,8 @@ where
 
     for (bucket, result) in results.iter().enumerate() {
         if indexes.contains_key(&bucket) {
-            assert!(result.t.0 == truth[bucket][indexes[&bucket]].t.0);
+            let truth = view.row(bucket, indexes[&bucket], &collection).unwrap();
+            assert!(result.t.0 == truth.t.0);
         }
     }
 
@@ -375,7 +322,7 @@ fn multipir_test_pung() {
 
     // setup
     let mut rng = rand::thread_rng();
-    let mut code = PungCode::new(k);
+    let code = PungCode::new(k);
 
     let (oracle, sizes) = get_oracle(&code, &mut rng);
     let truth = oracle.clone();
@@ -388,19 +335,6 @@ fn multipir_test_pung() {
     let galois = client.get_galois_keys();
     server.set_galois_keys(&galois, 0);
 
-    // Get label mapping (since Pung is data-dependent...)
-    let mut labels: HashMap<usize, Vec<usize>> = HashMap::new();
-
-    for (i, vec_tuple) in oracle.iter().enumerate() {
-        for tuple in vec_tuple {
-            let entry = labels.entry(tuple.t.0).or_insert_with(Vec::new);
-            entry.push(i);
-        }
-    }
-
-    // Pung's hybrid is a data-dependent code
-    code.set_labels(labels);
-
     // Generate keys (desired indexes)
     let mut key_set: HashSet<usize> = HashSet::new();
     while key_set.len() < k {
@@ -409,39 +343,88 @@ fn multipir_test_pung() {
 
     let keys: Vec<usize> = key_set.drain().collect();
 
-    // Get schedule. Hash in the head. Simulate entries.
-    let schedule: HashMap<usize, Vec<usize>> = (&code as &dyn BatchCode<usize, usize>)
-        .get_schedule(&keys)
+    // Get schedule. Hash in the head. The layout only depends on the keys the server holds.
+    let collection_keys: Vec<usize> = (0..NUM as usize).collect();
+    let layout = (&code as &dyn BatchCode<usize, usize>)
+        .layout(&collection_keys)
+        .unwrap();
+    let schedule = (&code as &dyn BatchCode<usize, usize>)
+        .get_indexed_schedule(&keys, &layout)
         .unwrap();
 
     // Indexes map from bucket -> index to fetch in that bucket
-    let mut indexes = HashMap::new();
+    let indexes: HashMap<usize, usize> = schedule.values().flatten().cloned().collect();
 
-    for (key, buckets) in schedule {
-        // find the index within the sub-buckets for this key.
-        let sub_bucket_i = (buckets[0] / 9) * 9;
+    let mut ind_vec = Vec::with_capacity(oracle.len());
 
-        let mut index = 0;
-        let mut found = false;
+    for bucket in 0..oracle.len() {
+        if indexes.contains_key(&bucket) {
+            ind_vec.push(indexes[&bucket] as u32);
+        } else {
+            ind_vec.push(rng.next_u32() % sizes[bucket].0);
+        }
+    }
 
-        for i in 0..4 {
-            // Consult the oracle for the indices
-            // buckets that have keys
-            let pos = oracle[sub_bucket_i + i].iter().position(|e| e.t.0 == key);
+    let query = client.gen_query(&ind_vec);
+    let reply = server.gen_replies(&query, 0);
+    let results = client.decode_replies::<Tuple<usize, Element>>(&ind_vec[..], &reply);
 
-            if let Some(ind) = pos {
-                index = ind;
-                found = true;
-                break;
-            }
+    for (bucket, result) in results.iter().enumerate() {
+        if indexes.contains_key(&bucket) {
+            assert!(result.t.0 == truth[bucket][indexes[&bucket]].t.0);
         }
+    }
+}
 
-        if !found {
-            panic!("Index for key not found");
-        }
+#[test]
+fn multipir_test_padded() {
+    let k = 16;
+    let code = CuckooCode::new(k, 3, 1.5);
+    let mut rng = rand::thread_rng();
+
+    // Pad every bucket to a length that only depends on NUM and the code
+    let collection = get_collection(&mut rng);
+    let mut oracle = (&code as &dyn BatchCode<usize, Element>)
+        .encode(&collection)
+        .unwrap();
+    let bound = (&code as &dyn BatchCode<usize, Element>).bucket_bound(NUM as usize, 40);
+    let dummy = Tuple {
+        t: (usize::MAX, Element::default()),
+    };
+    let len = pad(&mut oracle, Padding::Fixed(bound), &dummy).unwrap() as u32;
+
+    // One set of parameters and one Galois key for all buckets
+    let ele_size = mem::size_of::<(usize, Element)>() as u32;
+    let sizes = vec![(len, ele_size); oracle.len()];
+    let client = MultiPirClient::new_uniform(oracle.len(), len, ele_size, POLY_DEGREE, LOGT, DIM);
+    let mut server = MultiPirServer::new(&sizes, POLY_DEGREE, LOGT, DIM);
+    server.setup(&oracle);
+
+    let galois = client.get_shared_galois_key().unwrap();
+    server.set_shared_galois_key(&galois, 0);
+
+    // Generate keys (desired indexes)
+    let mut key_set: HashSet<usize> = HashSet::new();
+    while key_set.len() < k {
+        key_set.insert(rng.next_u32() as usize % NUM as usize);
+    }
+
+    let keys: Vec<usize> = key_set.drain().collect();
 
-        for bucket in buckets {
-            indexes.insert(bucket, index);
+    let collection_keys: Vec<usize> = (0..NUM as usize).collect();
+    let layout = (&code as &dyn BatchCode<usize, Element>)
+        .layout(&collection_keys)
+        .unwrap();
+    let schedule = (&code as &dyn BatchCode<usize, Element>)
+        .get_indexed_schedule(&keys, &layout)
+        .unwrap();
+
+    // Indexes map from bucket -> (key, index to fetch in that bucket)
+    let mut indexes = HashMap::new();
+
+    for (key, positions) in schedule {
+        for (bucket, row) in positions {
+            indexes.insert(bucket, (key, row));
         }
     }
 
@@ -449,9 +432,9 @@ fn multipir_test_pung() {
 
     for bucket in 0..oracle.len() {
         if indexes.contains_key(&bucket) {
-            ind_vec.push(indexes[&bucket] as u32);
+            ind_vec.push(indexes[&bucket].1 as u32);
         } else {
-            ind_vec.push(rng.next_u32() % sizes[bucket].0);
+            ind_vec.push(rng.next_u32() % len);
         }
     }
 
@@ -460,8 +443,519 @@ fn multipir_test_pung() {
     let results = client.decode_replies::<Tuple<usize, Element>>(&ind_vec[..], &reply);
 
     for (bucket, result) in results.iter().enumerate() {
-        if indexes.contains_key(&bucket) {
-            assert!(result.t.0 == truth[bucket][indexes[&bucket]].t.0);
+        if let Some((key, _)) = indexes.get(&bucket) {
+            assert_eq!(result.t.0, *key);
         }
     }
 }
+
+#[test]
+fn planner_test() {
+    let ele_size = mem::size_of::<(usize, Element)>();
+    let target = 2f64.powi(-20);
+    let mut planner = Planner::new(NUM as usize, ele_size, 64, target).unwrap();
+
+    let plans = planner.candidates();
+    assert!(plans.iter().all(|p| p.failure <= target));
+    assert!(plans.windows(2).all(|w| w[0].score <= w[1].score));
+    assert!(plans.iter().any(|p| p.code == CodeChoice::Replication));
+    assert!(!plans.iter().any(|p| p.code == CodeChoice::Pung)); // no bound without simulation
+
+    // Sharding and choices get as few replicas as the target allows
+    let sharding = CodeChoice::Sharding {
+        replicas: ShardingCode::with_failure(64, target).unwrap().replicas(),
+    };
+    let choices = CodeChoice::Choices {
+        d: 2,
+        replicas: ChoicesCode::with_failure(64, 2, target).unwrap().replicas(),
+    };
+    assert!(plans.iter().any(|p| p.code == sharding));
+    assert!(plans.iter().any(|p| p.code == choices));
+
+    // Plans can be published and used by clients
+    let plan = planner.plan().unwrap();
+    assert_eq!(plan, plans[0]);
+    let bytes = bincode::serialize(&plan).unwrap();
+    assert_eq!(bincode::deserialize::<Plan>(&bytes).unwrap(), plan);
+
+    // Only network matters
+    planner.set_weights(CostWeights {
+        cpu: 0.0,
+        network: 1.0,
+        storage: 0.0,
+    });
+
+    let plan = planner.plan().unwrap();
+    let network = |p: &Plan| p.cost.query_size + p.cost.reply_size;
+    assert!(plans.iter().all(|p| network(&plan) <= network(p)));
+
+    // Weights that make costs NaN do not break sorting
+    planner.set_weights(CostWeights {
+        cpu: f64::NAN,
+        network: 1.0,
+        storage: 0.0,
+    });
+    assert!(planner.plan().is_some());
+
+    // Codes that would have more than MAX_BUCKETS buckets are left out
+    let planner = Planner::new(NUM as usize, ele_size, MAX_BUCKETS, target).unwrap();
+    let plans = planner.candidates();
+    assert!(plans.iter().all(|p| p.num_buckets <= MAX_BUCKETS));
+    assert!(plans.iter().any(|p| p.code == CodeChoice::Replication));
+
+    // Invalid inputs are rejected
+    let mut planner = Planner::new(NUM as usize, ele_size, 64, target).unwrap();
+    assert!(Planner::new(NUM as usize, ele_size, 0, target).is_err());
+    assert!(Planner::new(NUM as usize, ele_size, 64, 0.0).is_err());
+    assert!(Planner::new(NUM as usize, ele_size, 64, 1.0).is_err());
+    assert!(Planner::new(NUM as usize, ele_size, 64, f64::NAN).is_err());
+
+    let unsupported = HasherSpec::Salted {
+        version: SaltedHasher::VERSION + 1,
+        salt: [0u8; 32],
+        code: CodeId::Cuckoo,
+    };
+    assert_eq!(
+        planner.set_hasher(unsupported),
+        Err(DescriptorError::UnsupportedHasher {
+            version: SaltedHasher::VERSION + 1
+        })
+    );
+}
+
+#[test]
+fn multipir_test_fingerprints() {
+    // Keys that cannot be XORed, such as public key fingerprints
+    type Fingerprint = [u8; 32];
+
+    let k = 16;
+    let n = 1 << 12;
+    let ele_size = mem::size_of::<(Fingerprint, Element)>();
+    let mut rng = rand::thread_rng();
+
+    let collection: Vec<Tuple<Fingerprint, Element>> = (0..n)
+        .map(|_| {
+            let mut key = [0u8; 32];
+            let mut x = [0u8; SIZE];
+            rng.fill_bytes(&mut key);
+            rng.fill_bytes(&mut x);
+            Tuple {
+                t: (key, Element::from(x)),
+            }
+        })
+        .collect();
+
+    // The planner only considers codes that work with such keys
+    let mut planner = Planner::new(n, ele_size, k, 2f64.powi(-20)).unwrap();
+    planner.set_systematic(true);
+    let plan = planner.plan().unwrap();
+    let code = plan.systematic_code::<Fingerprint, Element>().unwrap();
+
+    let dummy = Tuple {
+        t: ([0xff; 32], Element::default()),
+    };
+    let buckets = plan.encode_systematic(&collection, &dummy).unwrap();
+    assert_eq!(buckets.len(), plan.num_buckets);
+    assert!(buckets.iter().all(|b| b.len() == plan.bucket_len));
+
+    // The server sets up its buckets from a view of the collection
+    let view = code.encode_view(&collection).unwrap();
+    let sizes: Vec<(u32, u32)> = (0..view.num_buckets())
+        .map(|b| (view.bucket_len(b) as u32, ele_size as u32))
+        .collect();
+    let client = MultiPirClient::new(&sizes, POLY_DEGREE, LOGT, DIM);
+    let mut server = MultiPirServer::new_setup_view_systematic(
+        &view,
+        &collection,
+        ele_size as u32,
+        POLY_DEGREE,
+        LOGT,
+        DIM,
+    )
+    .unwrap();
+
+    let galois = client.get_galois_keys();
+    server.set_galois_keys(&galois, 0);
+
+    let keys: Vec<Fingerprint> = collection[..k].iter().map(|e| e.t.0).collect();
+    let collection_keys: Vec<Fingerprint> = collection.iter().map(|e| e.t.0).collect();
+    let layout = code.layout(&collection_keys).unwrap();
+    let schedule = code.get_indexed_schedule(&keys, &layout).unwrap();
+
+    let query = client.gen_scheduled_query(&schedule).unwrap();
+    let reply = server.gen_replies(&query.query, 0);
+    let results =
+        client.decode_scheduled_replies::<Fingerprint, Tuple<Fingerprint, Element>>(&query, &reply);
+    assert_eq!(results.len(), k);
+
+    for (i, key) in keys.iter().enumerate() {
+        let entry = code.decode(&results[key]).unwrap();
+        assert_eq!(entry.t.1, collection[i].t.1);
+    }
+
+    // Views with parity buckets cannot be set up without XOR
+    let collection = get_collection(&mut rng);
+    let view = (&PungCode::new(k) as &dyn BatchCode<usize, Element>)
+        .encode_view(&collection)
+        .unwrap();
+    assert!(MultiPirServer::new_setup_view_systematic(
+        &view,
+        &collection,
+        ele_size as u32,
+        POLY_DEGREE,
+        LOGT,
+        DIM
+    )
+    .is_err());
+}
+
+#[test]
+fn multipir_test_planned() {
+    let k = 16;
+    let ele_size = mem::size_of::<(usize, Element)>();
+    let mut rng = rand::thread_rng();
+
+    // Keys are placed with a keyed hasher, whose seed is part of the plan
+    let mut planner = Planner::new(NUM as usize, ele_size, k, 2f64.powi(-20)).unwrap();
+    planner
+        .set_hasher(KeyedHasher::random(&mut rng).spec())
+        .unwrap();
+    let plan = planner.plan().unwrap();
+
+    // The client builds its code from the descriptor that the server publishes
+    let published = bincode::serialize(&plan.descriptor()).unwrap();
+    let descriptor: CodeDescriptor = bincode::deserialize(&published).unwrap();
+    let code = descriptor.code::<usize, Element>().unwrap();
+
+    let collection = get_collection(&mut rng);
+    let dummy = Tuple {
+        t: (usize::MAX, Element::default()),
+    };
+    let buckets = plan.encode(&collection, &dummy).unwrap();
+    assert_eq!(descriptor.bucket_sizes.len(), buckets.len());
+
+    let client = plan.client();
+    let mut server = plan.server();
+    server.setup(&buckets);
+
+    let galois = client.get_shared_galois_key().unwrap();
+    server.set_shared_galois_key(&galois, 0);
+
+    // Generate keys (desired indexes)
+    let mut key_set: HashSet<usize> = HashSet::new();
+    while key_set.len() < k {
+        key_set.insert(rng.next_u32() as usize % NUM as usize);
+    }
+
+    let keys: Vec<usize> = key_set.drain().collect();
+
+    let collection_keys: Vec<usize> = (0..NUM as usize).collect();
+    let layout = code.layout(&collection_keys).unwrap();
+    let schedule = code.get_indexed_schedule(&keys, &layout).unwrap();
+
+    // The client fills in dummy queries for the buckets the schedule does not use
+    let query = client.gen_scheduled_query(&schedule).unwrap();
+    assert_eq!(query.query.len(), plan.num_buckets);
+    assert_eq!(query.real.iter().filter(|r| **r).count(), k);
+
+    let reply = server.gen_replies(&query.query, 0);
+    let results = client.decode_scheduled_replies::<usize, Tuple<usize, Element>>(&query, &reply);
+    assert_eq!(results.len(), k);
+
+    for (key, parts) in results {
+        let entry = code.decode(&parts).unwrap();
+        assert_eq!(entry.t.0, key);
+        assert_eq!(entry.t.1, collection[key].t.1);
+    }
+
+    // Schedules that would read a bucket twice are rejected
+    let (key, positions) = schedule.iter().next().unwrap();
+    let mut twice = schedule.clone();
+    twice.insert(key + 1, positions.clone());
+    assert!(client.gen_scheduled_query(&twice).is_err());
+}
+
+#[test]
+fn multipir_test_rounds() {
+    let k = 16;
+    let code = CuckooCode::new(k, 3, 1.5);
+    let code: &dyn BatchCode<usize, Element> = &code;
+    let mut rng = rand::thread_rng();
+
+    let collection = get_collection(&mut rng);
+    let mut oracle = code.encode(&collection).unwrap();
+    let dummy = Tuple {
+        t: (usize::MAX, Element::default()),
+    };
+    let len = pad(&mut oracle, Padding::Max, &dummy).unwrap() as u32;
+
+    let ele_size = mem::size_of::<(usize, Element)>() as u32;
+    let sizes = vec![(len, ele_size); oracle.len()];
+    let client = MultiPirClient::new_uniform(oracle.len(), len, ele_size, POLY_DEGREE, LOGT, DIM);
+    let mut server = MultiPirServer::new(&sizes, POLY_DEGREE, LOGT, DIM);
+    server.setup(&oracle);
+
+    let galois = client.get_shared_galois_key().unwrap();
+    server.set_shared_galois_key(&galois, 0);
+
+    // More keys than fit in a batch
+    let mut key_set: HashSet<usize> = HashSet::new();
+    while key_set.len() < 2 * k + 8 {
+        key_set.insert(rng.next_u32() as usize % NUM as usize);
+    }
+
+    let keys: Vec<usize> = key_set.drain().collect();
+
+    let collection_keys: Vec<usize> = (0..NUM as usize).collect();
+    let layout = code.layout(&collection_keys).unwrap();
+    let planner = RoundPlanner::new(code, &layout);
+
+    let rounds = planner.plan(&keys, &mut rng).unwrap();
+    assert!(rounds.len() >= 3);
+    assert_eq!(rounds.iter().map(|r| r.len()).sum::<usize>(), keys.len());
+    assert!(rounds.iter().all(|r| r.len() <= k));
+
+    let retrieval = planner
+        .retrieve(&client, &keys, &mut rng, |query| {
+            server.gen_replies(query, 0)
+        })
+        .unwrap();
+
+    assert!(retrieval.rounds >= 3);
+    assert_eq!(retrieval.values.len(), keys.len());
+
+    for key in &keys {
+        assert_eq!(retrieval.values[key], collection[*key].t.1);
+    }
+
+    // The same seed yields the same rounds
+    let seeded = |seed| planner.plan(&keys, &mut ChaChaRng::from_seed(&[seed][..]));
+    assert_eq!(seeded(7).unwrap(), seeded(7).unwrap());
+
+    // Keys the server does not hold are rejected before any query is sent
+    assert!(planner.plan(&[keys[0], NUM as usize], &mut rng).is_err());
+
+    // Entries whose keys do not match the layout (e.g., a stale encoding) are rejected
+    for entry in oracle.iter_mut().flatten() {
+        entry.t.0 ^= 1;
+    }
+
+    let mut server = MultiPirServer::new(&sizes, POLY_DEGREE, LOGT, DIM);
+    server.setup(&oracle);
+    server.set_shared_galois_key(&galois, 0);
+
+    let retrieval = planner.retrieve(&client, &keys, &mut rng, |query| {
+        server.gen_replies(query, 0)
+    });
+    assert!(matches!(retrieval, Err(RoundError::WrongKey { .. })));
+}
+
+#[test]
+fn multipir_test_chunks() {
+    type Chunk = Block<256>;
+
+    let k = 64;
+    let n = 2000;
+    let mut rng = rand::thread_rng();
+
+    // Values of up to 2 KB, each split into 9 chunks of 256 bytes
+    let chunker = Chunker::<256>::for_len(2000);
+    assert_eq!(chunker.chunks(), 9);
+
+    let collection: Vec<Tuple<usize, Vec<u8>>> = (0..n)
+        .map(|i| {
+            let mut value = vec![0u8; rng.gen_range(0, 2001)];
+            rng.fill_bytes(&mut value);
+            Tuple { t: (i, value) }
+        })
+        .collect();
+
+    let chunks = chunker.split_collection(&collection).unwrap();
+    assert_eq!(chunks.len(), n * chunker.chunks());
+
+    let code = CuckooCode::new(k, 3, 1.5);
+    let code: &dyn BatchCode<ChunkKey<usize>, Chunk> = &code;
+    let mut oracle = code.encode(&chunks).unwrap();
+    let dummy = Tuple {
+        t: (
+            ChunkKey {
+                key: usize::MAX,
+                index: 0,
+            },
+            Chunk::default(),
+        ),
+    };
+    let len = pad(&mut oracle, Padding::Max, &dummy).unwrap() as u32;
+
+    let ele_size = mem::size_of::<(ChunkKey<usize>, Chunk)>() as u32;
+    let sizes = vec![(len, ele_size); oracle.len()];
+    let client = MultiPirClient::new_uniform(oracle.len(), len, ele_size, POLY_DEGREE, LOGT, DIM);
+    let mut server = MultiPirServer::new(&sizes, POLY_DEGREE, LOGT, DIM);
+    server.setup(&oracle);
+
+    let galois = client.get_shared_galois_key().unwrap();
+    server.set_shared_galois_key(&galois, 0);
+
+    let chunk_keys: Vec<ChunkKey<usize>> = chunks.iter().map(|c| c.t.0.clone()).collect();
+    let layout = code.layout(&chunk_keys).unwrap();
+    let planner = RoundPlanner::new(code, &layout);
+
+    let keys = [3, 500, 1999, 1024, 42];
+    let retrieval = chunker
+        .retrieve(&planner, &client, &keys, &mut rng, |query| {
+            server.gen_replies(query, 0)
+        })
+        .unwrap();
+
+    for key in &keys {
+        assert_eq!(retrieval.values[key], collection[*key].t.1);
+    }
+
+    // Chunks that are missing, swapped or tampered with are detected
+    let mut parts: HashMap<ChunkKey<usize>, Chunk> = chunks[..2 * chunker.chunks()]
+        .iter()
+        .map(|c| (c.t.0.clone(), c.t.1))
+        .collect();
+    assert_eq!(chunker.join(&0, &parts).unwrap(), collection[0].t.1);
+    assert_eq!(
+        chunker.join(&2, &parts),
+        Err(ChunkError::MissingChunk { index: 0 })
+    );
+
+    let first = ChunkKey { key: 0, index: 0 };
+    let other = ChunkKey { key: 1, index: 0 };
+    let swapped = parts[&other];
+    parts.insert(first.clone(), swapped);
+    assert_eq!(chunker.join(&0, &parts), Err(ChunkError::Corrupted));
+
+    let mut bytes = *chunks[0].t.1.as_bytes();
+    bytes[20] ^= 1;
+    parts.insert(first, Chunk::from(bytes));
+    assert_eq!(chunker.join(&0, &parts), Err(ChunkError::Corrupted));
+
+    assert_eq!(
+        chunker.split(&0, &[0; 2500]).err(),
+        Some(ChunkError::TooLong {
+            len: 2500,
+            max_len: chunker.max_len()
+        })
+    );
+}
+
+#[test]
+fn multipir_test_keyword() {
+    let k = 16;
+    let n = 1 << 14;
+    let mut rng = rand::thread_rng();
+
+    // Sparse keys: the client does not know which keys the collection has
+    let mut key_set: HashSet<usize> = HashSet::new();
+    while key_set.len() < 2 * n {
+        key_set.insert(rng.gen_range(0, usize::MAX)); // usize::MAX is the dummy key
+    }
+
+    let mut all_keys: Vec<usize> = key_set.drain().collect();
+    let absent = all_keys.split_off(n);
+
+    let collection: Vec<Tuple<usize, Element>> = all_keys
+        .iter()
+        .map(|&key| {
+            let mut x = [0u8; SIZE];
+            rng.fill_bytes(&mut x);
+            Tuple {
+                t: (key, Element::from(x)),
+            }
+        })
+        .collect();
+
+    let code = CuckooCode::new(k, 3, 1.5);
+    let code: &dyn BatchCode<usize, Element> = &code;
+    let buckets = code.encode(&collection).unwrap();
+
+    // The server places each bucket in 3 tables with room to spare, and publishes the params
+    let keyword = KeywordPir::for_buckets(&buckets, 3, 1.5).unwrap();
+    let dummy = Tuple {
+        t: (usize::MAX, Element::default()),
+    };
+    let tables = keyword.build_tables(&buckets, &dummy).unwrap();
+    assert_eq!(tables.len(), keyword.num_tables(buckets.len()));
+
+    let params = keyword.params();
+    let width = params.width as u32;
+    let ele_size = mem::size_of::<(usize, Element)>() as u32;
+    let sizes = vec![(width, ele_size); tables.len()];
+    let mut server = MultiPirServer::new(&sizes, POLY_DEGREE, LOGT, DIM);
+    server.setup(&tables);
+
+    // The client only needs the code and the params
+    let keyword = KeywordPir::new(params).unwrap();
+    let client = MultiPirClient::new_uniform(tables.len(), width, ele_size, POLY_DEGREE, LOGT, DIM);
+    let galois = client.get_shared_galois_key().unwrap();
+    server.set_shared_galois_key(&galois, 0);
+
+    let mut keys: Vec<usize> = all_keys[..k / 2].to_vec();
+    keys.extend_from_slice(&absent[..k / 2 - 1]);
+    keys.push(dummy.t.0); // only the filler rows have this key
+
+    let results = keyword
+        .retrieve(code, &client, &keys, &dummy.t.0, &mut rng, |query| {
+            server.gen_replies(query, 0)
+        })
+        .unwrap();
+    assert_eq!(results.len(), keys.len());
+
+    for (i, key) in all_keys[..k / 2].iter().enumerate() {
+        assert_eq!(results[key], Lookup::Found(collection[i].t.1));
+    }
+
+    for key in &absent[..k / 2 - 1] {
+        assert_eq!(results[key], Lookup::NotPresent);
+    }
+
+    assert_eq!(results[&dummy.t.0], Lookup::NotPresent);
+
+    // The dummy's key is reserved
+    let mut taken = buckets.clone();
+    taken[1].push(dummy.clone());
+    assert_eq!(
+        keyword.build_tables(&taken, &dummy).err(),
+        Some(KeywordError::DummyKey { bucket: 1 })
+    );
+
+    // Buckets that do not fit in their tables are rejected
+    let tiny = KeywordPir::new(KeywordParams { width: 1, ..params }).unwrap();
+    assert_eq!(
+        tiny.build_tables(&buckets, &dummy).err(),
+        Some(KeywordError::TableFull { bucket: 0 })
+    );
+
+    // Params from a broken or newer server are rejected rather than trusted
+    assert_eq!(
+        KeywordPir::new(KeywordParams {
+            probes: 0,
+            ..params
+        })
+        .err(),
+        Some(KeywordError::NoProbes)
+    );
+    assert_eq!(
+        KeywordPir::new(KeywordParams { width: 0, ..params }).err(),
+        Some(KeywordError::NoRows)
+    );
+    assert_eq!(
+        KeywordPir::for_buckets(&buckets, 0, 1.5).err(),
+        Some(KeywordError::NoProbes)
+    );
+
+    let hasher = HasherSpec::Salted {
+        version: SaltedHasher::VERSION + 1,
+        salt: [0u8; 32],
+        code: CodeId::Cuckoo,
+    };
+    assert_eq!(
+        KeywordPir::new(KeywordParams { hasher, ..params }).err(),
+        Some(KeywordError::UnsupportedHasher {
+            version: SaltedHasher::VERSION + 1
+        })
+    );
+}